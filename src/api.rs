@@ -1,10 +1,14 @@
 use std::io::{BufReader, Cursor, Read, Result, Write};
 
 use crate::primitives::{
-    encode_bool, encode_compact_array, encode_compact_nullable_string, encode_compact_string,
-    encode_nullable_field, encode_tag_buffer, parse_bool, parse_compact_array,
-    parse_compact_array_with_tag_buffer, parse_compact_string, parse_int16, parse_int32,
-    parse_int64, parse_int8, parse_nullable_field, parse_tag_buffer, CompactString, Uuid,
+    encode_array, encode_bool, encode_compact_array, encode_compact_bytes,
+    encode_compact_nullable_string, encode_compact_string, encode_nullable_field,
+    encode_nullable_string, encode_string, encode_tag_buffer, encode_tagged_fields, encode_varint,
+    parse_array, parse_bool, parse_compact_array, parse_compact_array_with_tag_buffer,
+    parse_compact_nullable_array, parse_compact_nullable_string, parse_compact_string, parse_int16,
+    parse_int32, parse_int64, parse_int8, parse_nullable_field, parse_nullable_string,
+    parse_string, parse_tag_buffer, parse_tagged_fields, parse_unsigned_varlong,
+    CompactNullableString, Uuid,
 };
 
 pub trait Parser<T> {
@@ -13,10 +17,17 @@ pub trait Parser<T> {
 
 pub trait Encoder {
     fn encode(&self) -> Vec<u8>;
+
+    // appends the encoding to buf, which fixed size types override to skip
+    // allocating a vector per value
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.encode());
+    }
 }
 
 #[allow(dead_code)]
 pub struct FetchRequest {
+    pub replica_id: i32,
     pub max_wait_ms: i32,
     pub min_bytes: i32,
     pub max_bytes: i32,
@@ -28,9 +39,36 @@ pub struct FetchRequest {
     pub rack_id: String,
 }
 
+// consumers don't send a replica state, followers send their broker id in it
+pub const CONSUMER_REPLICA_ID: i32 = -1;
+const REPLICA_STATE_TAG: u32 = 1;
+
+pub const READ_COMMITTED: i8 = 1;
+
+impl FetchRequest {
+    pub fn is_from_follower(&self) -> bool {
+        self.replica_id >= 0
+    }
+
+    // partitions only known from the fetch session are read from the start
+    pub fn partition(&self, topic_id: &Uuid, partition: i32) -> Option<&FetchRequestPartition> {
+        self.topics
+            .iter()
+            .filter(|topic| topic.topic_id == *topic_id)
+            .flat_map(|topic| &topic.partitions)
+            .find(|p| p.partition == partition)
+    }
+
+    pub fn fetch_offset(&self, topic_id: &Uuid, partition: i32) -> i64 {
+        self.partition(topic_id, partition)
+            .map_or(0, |p| p.fetch_offset)
+    }
+}
+
 impl Parser<Self> for FetchRequest {
     fn parse(reader: &mut impl Read) -> Result<Self> {
-        let req = Ok(FetchRequest {
+        let mut req = FetchRequest {
+            replica_id: CONSUMER_REPLICA_ID,
             max_wait_ms: parse_int32(reader)?,
             min_bytes: parse_int32(reader)?,
             max_bytes: parse_int32(reader)?,
@@ -40,10 +78,15 @@ impl Parser<Self> for FetchRequest {
             topics: parse_compact_array(reader)?,
             forgotten_topics_data: parse_compact_array(reader)?,
             rack_id: parse_compact_string(reader)?,
-        });
+        };
 
-        parse_tag_buffer(reader)?;
-        req
+        for (tag, data) in parse_tagged_fields(reader)? {
+            if tag == REPLICA_STATE_TAG {
+                req.replica_id = parse_int32(&mut Cursor::new(data))?;
+            }
+        }
+
+        Ok(req)
     }
 }
 
@@ -67,12 +110,12 @@ impl Parser<Self> for FetchRequestTopic {
 
 #[allow(dead_code)]
 pub struct FetchRequestPartition {
-    partition: i32,
-    current_leader_epoch: i32,
-    fetch_offset: i64,
-    last_fetched_epoch: i32,
-    log_start_offset: i64,
-    partition_max_bytes: i32,
+    pub partition: i32,
+    pub current_leader_epoch: i32,
+    pub fetch_offset: i64,
+    pub last_fetched_epoch: i32,
+    pub log_start_offset: i64,
+    pub partition_max_bytes: i32,
 }
 
 impl Parser<Self> for FetchRequestPartition {
@@ -93,8 +136,8 @@ impl Parser<Self> for FetchRequestPartition {
 
 #[allow(dead_code)]
 pub struct ForgottenTopicsData {
-    topic_id: Uuid,
-    partitions: Vec<i32>,
+    pub topic_id: Uuid,
+    pub partitions: Vec<i32>,
 }
 
 impl Parser<Self> for ForgottenTopicsData {
@@ -114,6 +157,7 @@ pub struct FetchResponse {
     pub error_code: ErrorCode,
     pub session_id: i32,
     pub responses: Vec<FetchResponseResponse>,
+    pub node_endpoints: Vec<NodeEndpoint>,
 }
 
 impl Encoder for FetchResponse {
@@ -124,6 +168,36 @@ impl Encoder for FetchResponse {
         buffer.extend(self.error_code.encode());
         buffer.extend(self.session_id.encode());
         buffer.extend(encode_compact_array(&self.responses));
+
+        // the endpoints only travel as a tagged field when a replica is preferred
+        if self.node_endpoints.is_empty() {
+            buffer.extend(encode_tag_buffer());
+        } else {
+            buffer.extend(encode_tagged_fields(&[(
+                0,
+                encode_compact_array(&self.node_endpoints),
+            )]));
+        }
+        buffer
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NodeEndpoint {
+    pub node_id: i32,
+    pub host: String,
+    pub port: i32,
+    pub rack: Option<String>,
+}
+
+impl Encoder for NodeEndpoint {
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer: Vec<u8> = Vec::new();
+
+        buffer.extend(self.node_id.encode());
+        buffer.extend(encode_compact_string(&self.host));
+        buffer.extend(self.port.encode());
+        buffer.extend(encode_compact_nullable_string(&self.rack));
         buffer.extend(encode_tag_buffer());
         buffer
     }
@@ -156,6 +230,22 @@ pub struct FetchResponsePartition {
     pub records: Vec<u8>,
 }
 
+impl FetchResponsePartition {
+    // a successful read with no aborted transactions and no replica redirect
+    pub fn new(partition_index: i32, records: Vec<u8>, high_watermark: i64) -> Self {
+        FetchResponsePartition {
+            partition_index,
+            error_code: ErrorCode::NoError,
+            high_watermark,
+            last_stable_offset: high_watermark,
+            log_start_offset: 0,
+            aborted_transactions: vec![],
+            preferred_read_replica: -1,
+            records,
+        }
+    }
+}
+
 impl Encoder for FetchResponsePartition {
     fn encode(&self) -> Vec<u8> {
         let mut buffer: Vec<u8> = Vec::new();
@@ -167,29 +257,30 @@ impl Encoder for FetchResponsePartition {
         buffer.extend(self.log_start_offset.encode());
         buffer.extend(encode_compact_array(&self.aborted_transactions));
         buffer.extend(self.preferred_read_replica.encode());
-        buffer.extend(encode_compact_array(&self.records));
+        buffer.extend(encode_compact_bytes(&self.records));
         buffer.extend(encode_tag_buffer());
         buffer
     }
 }
 
-pub struct AbortedTransaction {}
-
-impl Encoder for AbortedTransaction {
-    fn encode(&self) -> Vec<u8> {
-        vec![]
-    }
+#[derive(Debug, PartialEq)]
+pub struct AbortedTransaction {
+    pub producer_id: i64,
+    pub first_offset: i64,
 }
 
-pub struct CompactRecord {}
-
-impl Encoder for CompactRecord {
+impl Encoder for AbortedTransaction {
     fn encode(&self) -> Vec<u8> {
-        vec![]
+        let mut buf = Vec::new();
+        buf.extend(self.producer_id.encode());
+        buf.extend(self.first_offset.encode());
+        buf.extend(encode_tag_buffer());
+        buf
     }
 }
 
 #[allow(dead_code)]
+#[derive(Debug, Default, PartialEq)]
 pub struct ApiVersionsRequest {
     pub client_software_name: String,
     pub client_software_version: String,
@@ -205,6 +296,8 @@ impl Parser<Self> for ApiVersionsRequest {
 }
 
 pub struct ApiVersionsResponse {
+    // the version the response is encoded in
+    pub version: i16,
     pub error_code: i16,
     pub api_keys: Vec<ApiKeys>,
     pub throttle_time_ms: i32,
@@ -215,9 +308,22 @@ impl ApiVersionsResponse {
         let mut buffer: Vec<u8> = Vec::new();
 
         buffer.extend(self.error_code.encode());
-        buffer.extend(encode_compact_array(&self.api_keys));
-        buffer.extend(self.throttle_time_ms.encode());
-        buffer.extend(encode_tag_buffer());
+        // v3 is the first flexible version
+        if self.version >= 3 {
+            buffer.extend(encode_compact_array(&self.api_keys));
+        } else {
+            buffer.extend((self.api_keys.len() as i32).encode());
+            for api_key in &self.api_keys {
+                buffer.extend(api_key.encode_fields());
+            }
+        }
+        // throttle time was added in v1
+        if self.version >= 1 {
+            buffer.extend(self.throttle_time_ms.encode());
+        }
+        if self.version >= 3 {
+            buffer.extend(encode_tag_buffer());
+        }
         buffer
     }
 }
@@ -228,192 +334,1932 @@ pub struct ApiKeys {
     pub max_version: i16,
 }
 
-impl Encoder for ApiKeys {
-    fn encode(&self) -> Vec<u8> {
+impl ApiKeys {
+    fn encode_fields(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
         buffer.extend(&self.api_key.to_be_bytes());
         buffer.extend(&self.min_version.to_be_bytes());
         buffer.extend(&self.max_version.to_be_bytes());
+        buffer
+    }
+}
+
+impl Encoder for ApiKeys {
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = self.encode_fields();
         buffer.extend(encode_tag_buffer());
         buffer
     }
 }
 
-#[allow(dead_code)]
-pub struct DescribeTopicPartitionsRequest {
-    pub topics: Vec<String>,
-    pub response_partition_limit: i32,
-    pub cursor: Option<KCursor>,
+pub struct AddPartitionsToTxnRequest {
+    pub transactional_id: String,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub topics: Vec<AddPartitionsToTxnTopic>,
 }
 
-impl Parser<Self> for DescribeTopicPartitionsRequest {
+impl Parser<Self> for AddPartitionsToTxnRequest {
     fn parse(reader: &mut impl Read) -> Result<Self> {
-        Ok(DescribeTopicPartitionsRequest {
-            topics: parse_compact_array_with_tag_buffer(reader)?
-                .into_iter()
-                .map(|s: CompactString| s.0)
-                .collect(),
-            response_partition_limit: parse_int32(reader)?,
-            cursor: parse_nullable_field(reader)?,
-        })
+        let req = Ok(AddPartitionsToTxnRequest {
+            transactional_id: parse_compact_string(reader)?,
+            producer_id: parse_int64(reader)?,
+            producer_epoch: parse_int16(reader)?,
+            topics: parse_compact_array(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
     }
 }
 
-#[allow(dead_code)]
-pub struct KCursor {
-    pub topic_name: String,
-    pub partition_index: i32,
+pub struct AddPartitionsToTxnTopic {
+    pub name: String,
+    pub partitions: Vec<i32>,
 }
 
-impl Parser<Self> for KCursor {
+impl Parser<Self> for AddPartitionsToTxnTopic {
     fn parse(reader: &mut impl Read) -> Result<Self> {
-        Ok(KCursor {
-            topic_name: parse_compact_string(reader)?,
-            partition_index: parse_int32(reader)?,
-        })
+        let req = Ok(AddPartitionsToTxnTopic {
+            name: parse_compact_string(reader)?,
+            partitions: parse_compact_array(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
     }
 }
 
-impl Encoder for KCursor {
+pub struct AddPartitionsToTxnResponse {
+    pub throttle_time_ms: i32,
+    pub results: Vec<AddPartitionsToTxnTopicResult>,
+}
+
+impl Encoder for AddPartitionsToTxnResponse {
     fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        buf.extend(encode_compact_string(&self.topic_name));
-        buf.extend(self.partition_index.encode());
+        buf.extend(self.throttle_time_ms.encode());
+        buf.extend(encode_compact_array(&self.results));
+        buf.extend(encode_tag_buffer());
         buf
     }
 }
 
-#[allow(dead_code)]
-pub struct DescribeTopicPartitionsResponse {
-    pub throttle_time_ms: i32,
-    pub topics: Vec<Topic>,
-    pub next_cursor: Option<KCursor>,
+pub struct AddPartitionsToTxnTopicResult {
+    pub name: String,
+    pub results: Vec<AddPartitionsToTxnPartitionResult>,
 }
 
-impl DescribeTopicPartitionsResponse {
-    pub fn encode(&self) -> Vec<u8> {
+impl Encoder for AddPartitionsToTxnTopicResult {
+    fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        buf.extend(&self.throttle_time_ms.to_be_bytes());
-        buf.extend(&encode_compact_array(&self.topics));
-        buf.extend(encode_nullable_field(&self.next_cursor));
+        buf.extend(encode_compact_string(&self.name));
+        buf.extend(encode_compact_array(&self.results));
         buf.extend(encode_tag_buffer());
         buf
     }
 }
 
-#[allow(dead_code)]
-pub struct Topic {
-    pub error_code: ErrorCode,
-    pub name: Option<String>,
-    pub topic_id: Uuid,
-    pub is_internal: bool,
-    pub partitions: Vec<Partition>,
-    pub topic_authorized_operations: i32,
+pub struct AddPartitionsToTxnPartitionResult {
+    pub partition_index: i32,
+    pub partition_error_code: ErrorCode,
 }
 
-impl Parser<Self> for Topic {
+impl Encoder for AddPartitionsToTxnPartitionResult {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.partition_index.encode());
+        buf.extend(self.partition_error_code.encode());
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+pub struct EndTxnRequest {
+    pub transactional_id: String,
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub committed: bool,
+}
+
+impl Parser<Self> for EndTxnRequest {
     fn parse(reader: &mut impl Read) -> Result<Self> {
-        Ok(Topic {
-            error_code: ErrorCode::parse(reader)?,
-            name: Some(parse_compact_string(reader)?),
-            topic_id: Uuid::parse(reader)?,
-            is_internal: parse_bool(reader)?,
-            partitions: parse_compact_array(reader)?,
-            topic_authorized_operations: parse_int32(reader)?,
-        })
+        let req = Ok(EndTxnRequest {
+            transactional_id: parse_compact_string(reader)?,
+            producer_id: parse_int64(reader)?,
+            producer_epoch: parse_int16(reader)?,
+            committed: parse_bool(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
     }
 }
 
-impl Encoder for Topic {
+pub struct EndTxnResponse {
+    pub throttle_time_ms: i32,
+    pub error_code: ErrorCode,
+}
+
+impl Encoder for EndTxnResponse {
     fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        buf.extend(&self.error_code.encode());
-        buf.extend(encode_compact_nullable_string(&self.name));
-        buf.extend(self.topic_id.encode());
-        buf.extend(encode_bool(self.is_internal));
-        buf.extend(encode_compact_array(&self.partitions));
-        buf.extend(self.topic_authorized_operations.encode());
+        buf.extend(self.throttle_time_ms.encode());
+        buf.extend(self.error_code.encode());
         buf.extend(encode_tag_buffer());
         buf
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
-pub enum ErrorCode {
-    NoError = 0,
-    UnknownTopicOrPartition = 3,
-    UnsupportedVersion = 35,
-    UnknownTopic = 100,
+// BrokerHeartbeat v0 and v1, v1 only adding a tagged field
+#[allow(dead_code)]
+pub struct BrokerHeartbeatRequest {
+    pub broker_id: i32,
+    pub broker_epoch: i64,
+    // the last metadata log offset the broker applied
+    pub current_metadata_offset: i64,
+    pub want_fence: bool,
+    pub want_shut_down: bool,
 }
 
-impl Parser<Self> for ErrorCode {
+impl Parser<Self> for BrokerHeartbeatRequest {
     fn parse(reader: &mut impl Read) -> Result<Self> {
-        let code = parse_int16(reader)?;
-        let result = match code {
-            value if value == ErrorCode::NoError as i16 => ErrorCode::NoError,
-            value if value == ErrorCode::UnknownTopicOrPartition as i16 => {
-                ErrorCode::UnknownTopicOrPartition
-            }
-            value if value == ErrorCode::UnsupportedVersion as i16 => ErrorCode::UnsupportedVersion,
-            value if value == ErrorCode::UnknownTopic as i16 => ErrorCode::UnknownTopic,
-            _ => panic!("Unknown error code: {}", code),
+        let req = BrokerHeartbeatRequest {
+            broker_id: parse_int32(reader)?,
+            broker_epoch: parse_int64(reader)?,
+            current_metadata_offset: parse_int64(reader)?,
+            want_fence: parse_bool(reader)?,
+            want_shut_down: parse_bool(reader)?,
         };
 
-        Ok(result)
+        parse_tag_buffer(reader)?;
+        Ok(req)
     }
 }
 
-impl Encoder for ErrorCode {
+pub struct BrokerHeartbeatResponse {
+    pub throttle_time_ms: i32,
+    pub error_code: ErrorCode,
+    pub is_caught_up: bool,
+    pub is_fenced: bool,
+    pub should_shut_down: bool,
+}
+
+impl Encoder for BrokerHeartbeatResponse {
     fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        let code = self.clone() as i16;
-        buf.extend(i16::to_be_bytes(code));
+        buf.extend(self.throttle_time_ms.encode());
+        buf.extend(self.error_code.encode());
+        buf.extend(encode_bool(self.is_caught_up));
+        buf.extend(encode_bool(self.is_fenced));
+        buf.extend(encode_bool(self.should_shut_down));
+        buf.extend(encode_tag_buffer());
         buf
     }
 }
 
+// special timestamps asking for the log end offset and the log start offset
+pub const LATEST_TIMESTAMP: i64 = -1;
+pub const EARLIEST_TIMESTAMP: i64 = -2;
+
 #[allow(dead_code)]
-pub struct Partition {
-    pub error_code: ErrorCode,
+pub struct ListOffsetsRequest {
+    pub replica_id: i32,
+    pub isolation_level: i8,
+    pub topics: Vec<ListOffsetsRequestTopic>,
+}
+
+impl Parser<Self> for ListOffsetsRequest {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        let req = Ok(ListOffsetsRequest {
+            replica_id: parse_int32(reader)?,
+            isolation_level: parse_int8(reader)?,
+            topics: parse_compact_array(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
+    }
+}
+
+pub struct ListOffsetsRequestTopic {
+    pub name: String,
+    pub partitions: Vec<ListOffsetsRequestPartition>,
+}
+
+impl Parser<Self> for ListOffsetsRequestTopic {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        let req = Ok(ListOffsetsRequestTopic {
+            name: parse_compact_string(reader)?,
+            partitions: parse_compact_array(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
+    }
+}
+
+#[allow(dead_code)]
+pub struct ListOffsetsRequestPartition {
     pub partition_index: i32,
-    pub leader_id: i32,
-    pub leader_epoch: i32,
-    pub replica_nodes: Vec<i32>,
-    pub isr_nodes: Vec<i32>,
-    pub eligible_leader_replicas: Vec<i32>,
-    pub last_known_elr: Vec<i32>,
-    pub offline_replicas: Vec<i32>,
+    pub current_leader_epoch: i32,
+    pub timestamp: i64,
 }
 
-impl Parser<Self> for Partition {
+impl Parser<Self> for ListOffsetsRequestPartition {
     fn parse(reader: &mut impl Read) -> Result<Self> {
-        Ok(Partition {
-            error_code: ErrorCode::parse(reader)?,
+        let req = Ok(ListOffsetsRequestPartition {
             partition_index: parse_int32(reader)?,
-            leader_id: parse_int32(reader)?,
-            leader_epoch: parse_int32(reader)?,
-            replica_nodes: parse_compact_array(reader)?,
-            isr_nodes: parse_compact_array(reader)?,
-            eligible_leader_replicas: parse_compact_array(reader)?,
-            last_known_elr: parse_compact_array(reader)?,
-            offline_replicas: parse_compact_array(reader)?,
-        })
+            current_leader_epoch: parse_int32(reader)?,
+            timestamp: parse_int64(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
     }
 }
 
-impl Encoder for Partition {
+pub struct ListOffsetsResponse {
+    pub throttle_time_ms: i32,
+    pub topics: Vec<ListOffsetsResponseTopic>,
+}
+
+impl Encoder for ListOffsetsResponse {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.throttle_time_ms.encode());
+        buf.extend(encode_compact_array(&self.topics));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+pub struct ListOffsetsResponseTopic {
+    pub name: String,
+    pub partitions: Vec<ListOffsetsResponsePartition>,
+}
+
+impl Encoder for ListOffsetsResponseTopic {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(encode_compact_string(&self.name));
+        buf.extend(encode_compact_array(&self.partitions));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+pub struct ListOffsetsResponsePartition {
+    pub partition_index: i32,
+    pub error_code: ErrorCode,
+    pub timestamp: i64,
+    pub offset: i64,
+    pub leader_epoch: i32,
+}
+
+impl Encoder for ListOffsetsResponsePartition {
     fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
-        buf.extend(self.error_code.encode());
         buf.extend(self.partition_index.encode());
-        buf.extend(self.leader_id.encode());
+        buf.extend(self.error_code.encode());
+        buf.extend(self.timestamp.encode());
+        buf.extend(self.offset.encode());
         buf.extend(self.leader_epoch.encode());
-        buf.extend(encode_compact_array(&self.replica_nodes));
-        buf.extend(encode_compact_array(&self.isr_nodes));
-        buf.extend(encode_compact_array(&self.eligible_leader_replicas));
-        buf.extend(encode_compact_array(&self.last_known_elr));
-        buf.extend(encode_compact_array(&self.offline_replicas));
         buf.extend(encode_tag_buffer());
         buf
     }
 }
+
+// OffsetCommit v1 to v8, the fields of each version are noted where they differ
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct OffsetCommitRequest {
+    pub group_id: String,
+    pub generation_id: i32,
+    pub member_id: String,
+    // v7+
+    pub group_instance_id: Option<String>,
+    // v2 to v4, -1 keeps commits for the broker's default retention
+    pub retention_time_ms: i64,
+    pub topics: Vec<OffsetCommitRequestTopic>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct OffsetCommitRequestTopic {
+    pub name: String,
+    pub partitions: Vec<OffsetCommitRequestPartition>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct OffsetCommitRequestPartition {
+    pub partition_index: i32,
+    pub committed_offset: i64,
+    // v6+, -1 when unknown
+    pub committed_leader_epoch: i32,
+    pub committed_metadata: Option<String>,
+}
+
+impl OffsetCommitRequest {
+    pub fn parse(reader: &mut impl Read, version: i16) -> Result<Self> {
+        // v8 is the first flexible version
+        let flexible = version >= 8;
+
+        let group_id = parse_versioned_string(reader, flexible)?;
+        let generation_id = parse_int32(reader)?;
+        let member_id = parse_versioned_string(reader, flexible)?;
+        let group_instance_id = if version >= 7 {
+            parse_versioned_nullable_string(reader, flexible)?
+        } else {
+            None
+        };
+        let retention_time_ms = if (2..=4).contains(&version) {
+            parse_int64(reader)?
+        } else {
+            -1
+        };
+
+        let mut topics = Vec::new();
+        for _ in 0..parse_versioned_array_length(reader, flexible)? {
+            let name = parse_versioned_string(reader, flexible)?;
+
+            let mut partitions = Vec::new();
+            for _ in 0..parse_versioned_array_length(reader, flexible)? {
+                let partition_index = parse_int32(reader)?;
+                let committed_offset = parse_int64(reader)?;
+                let committed_leader_epoch = if version >= 6 {
+                    parse_int32(reader)?
+                } else {
+                    -1
+                };
+                // v1's commit timestamp was replaced by the retention time
+                if version == 1 {
+                    parse_int64(reader)?;
+                }
+
+                partitions.push(OffsetCommitRequestPartition {
+                    partition_index,
+                    committed_offset,
+                    committed_leader_epoch,
+                    committed_metadata: parse_versioned_nullable_string(reader, flexible)?,
+                });
+                if flexible {
+                    parse_tag_buffer(reader)?;
+                }
+            }
+
+            topics.push(OffsetCommitRequestTopic { name, partitions });
+            if flexible {
+                parse_tag_buffer(reader)?;
+            }
+        }
+
+        if flexible {
+            parse_tag_buffer(reader)?;
+        }
+
+        Ok(OffsetCommitRequest {
+            group_id,
+            generation_id,
+            member_id,
+            group_instance_id,
+            retention_time_ms,
+            topics,
+        })
+    }
+}
+
+// OffsetCommit v1 to v8
+pub struct OffsetCommitResponse {
+    // the version the response is encoded in
+    pub version: i16,
+    // v3+
+    pub throttle_time_ms: i32,
+    pub topics: Vec<OffsetCommitResponseTopic>,
+}
+
+impl Encoder for OffsetCommitResponse {
+    fn encode(&self) -> Vec<u8> {
+        // v8 is the first flexible version
+        let flexible = self.version >= 8;
+
+        let mut buf = Vec::new();
+        if self.version >= 3 {
+            buf.extend(self.throttle_time_ms.encode());
+        }
+        buf.extend(encode_versioned_array_length(self.topics.len(), flexible));
+        for topic in &self.topics {
+            buf.extend(encode_versioned_string(&topic.name, flexible));
+            buf.extend(encode_versioned_array_length(
+                topic.partitions.len(),
+                flexible,
+            ));
+            for partition in &topic.partitions {
+                buf.extend(partition.partition_index.encode());
+                buf.extend(partition.error_code.encode());
+                if flexible {
+                    buf.extend(encode_tag_buffer());
+                }
+            }
+            if flexible {
+                buf.extend(encode_tag_buffer());
+            }
+        }
+        if flexible {
+            buf.extend(encode_tag_buffer());
+        }
+        buf
+    }
+}
+
+pub struct OffsetCommitResponseTopic {
+    pub name: String,
+    pub partitions: Vec<OffsetCommitResponsePartition>,
+}
+
+pub struct OffsetCommitResponsePartition {
+    pub partition_index: i32,
+    pub error_code: ErrorCode,
+}
+
+// OffsetFetch v1 to v7, the versions that fetch for a single group
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct OffsetFetchRequest {
+    pub group_id: String,
+    // None fetches every partition the group committed to, from v2
+    pub topics: Option<Vec<OffsetFetchRequestTopic>>,
+    // v7+
+    pub require_stable: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct OffsetFetchRequestTopic {
+    pub name: String,
+    pub partition_indexes: Vec<i32>,
+}
+
+impl OffsetFetchRequest {
+    pub fn parse(reader: &mut impl Read, version: i16) -> Result<Self> {
+        // v6 is the first flexible version
+        let flexible = version >= 6;
+
+        let group_id = parse_versioned_string(reader, flexible)?;
+        let topics = match parse_versioned_nullable_array_length(reader, flexible)? {
+            Some(length) => {
+                let mut topics = Vec::new();
+                for _ in 0..length {
+                    let name = parse_versioned_string(reader, flexible)?;
+                    let mut partition_indexes = Vec::new();
+                    for _ in 0..parse_versioned_array_length(reader, flexible)? {
+                        partition_indexes.push(parse_int32(reader)?);
+                    }
+                    topics.push(OffsetFetchRequestTopic {
+                        name,
+                        partition_indexes,
+                    });
+                    if flexible {
+                        parse_tag_buffer(reader)?;
+                    }
+                }
+                Some(topics)
+            }
+            None => None,
+        };
+        let require_stable = if version >= 7 {
+            parse_bool(reader)?
+        } else {
+            false
+        };
+
+        if flexible {
+            parse_tag_buffer(reader)?;
+        }
+
+        Ok(OffsetFetchRequest {
+            group_id,
+            topics,
+            require_stable,
+        })
+    }
+}
+
+// OffsetFetch v1 to v7
+pub struct OffsetFetchResponse {
+    // the version the response is encoded in
+    pub version: i16,
+    // v3+
+    pub throttle_time_ms: i32,
+    pub topics: Vec<OffsetFetchResponseTopic>,
+    // v2+
+    pub error_code: ErrorCode,
+}
+
+impl Encoder for OffsetFetchResponse {
+    fn encode(&self) -> Vec<u8> {
+        let flexible = self.version >= 6;
+
+        let mut buf = Vec::new();
+        if self.version >= 3 {
+            buf.extend(self.throttle_time_ms.encode());
+        }
+        buf.extend(encode_versioned_array_length(self.topics.len(), flexible));
+        for topic in &self.topics {
+            buf.extend(encode_versioned_string(&topic.name, flexible));
+            buf.extend(encode_versioned_array_length(
+                topic.partitions.len(),
+                flexible,
+            ));
+            for partition in &topic.partitions {
+                buf.extend(partition.partition_index.encode());
+                buf.extend(partition.committed_offset.encode());
+                if self.version >= 5 {
+                    buf.extend(partition.committed_leader_epoch.encode());
+                }
+                buf.extend(encode_versioned_nullable_string(
+                    &partition.metadata,
+                    flexible,
+                ));
+                buf.extend(partition.error_code.encode());
+                if flexible {
+                    buf.extend(encode_tag_buffer());
+                }
+            }
+            if flexible {
+                buf.extend(encode_tag_buffer());
+            }
+        }
+        if self.version >= 2 {
+            buf.extend(self.error_code.encode());
+        }
+        if flexible {
+            buf.extend(encode_tag_buffer());
+        }
+        buf
+    }
+}
+
+pub struct OffsetFetchResponseTopic {
+    pub name: String,
+    pub partitions: Vec<OffsetFetchResponsePartition>,
+}
+
+pub struct OffsetFetchResponsePartition {
+    pub partition_index: i32,
+    pub committed_offset: i64,
+    // v5+
+    pub committed_leader_epoch: i32,
+    pub metadata: Option<String>,
+    pub error_code: ErrorCode,
+}
+
+fn parse_versioned_string(reader: &mut impl Read, flexible: bool) -> Result<String> {
+    if flexible {
+        parse_compact_string(reader)
+    } else {
+        parse_string(reader)
+    }
+}
+
+fn parse_versioned_nullable_string(
+    reader: &mut impl Read,
+    flexible: bool,
+) -> Result<Option<String>> {
+    if flexible {
+        parse_compact_nullable_string(reader)
+    } else {
+        parse_nullable_string(reader)
+    }
+}
+
+fn parse_versioned_array_length(reader: &mut impl Read, flexible: bool) -> Result<usize> {
+    if flexible {
+        Ok(parse_unsigned_varlong(reader)?.saturating_sub(1) as usize)
+    } else {
+        Ok(parse_int32(reader)?.max(0) as usize)
+    }
+}
+
+fn parse_versioned_nullable_array_length(
+    reader: &mut impl Read,
+    flexible: bool,
+) -> Result<Option<usize>> {
+    if flexible {
+        Ok(parse_unsigned_varlong(reader)?
+            .checked_sub(1)
+            .map(|length| length as usize))
+    } else {
+        let length = parse_int32(reader)?;
+        Ok((length >= 0).then_some(length as usize))
+    }
+}
+
+fn encode_versioned_string(string: &str, flexible: bool) -> Vec<u8> {
+    if flexible {
+        encode_compact_string(string)
+    } else {
+        encode_string(string)
+    }
+}
+
+fn encode_versioned_nullable_string(string: &Option<String>, flexible: bool) -> Vec<u8> {
+    if flexible {
+        encode_compact_nullable_string(string)
+    } else {
+        encode_nullable_string(string)
+    }
+}
+
+fn encode_versioned_array_length(length: usize, flexible: bool) -> Vec<u8> {
+    if flexible {
+        encode_varint(length as u64 + 1)
+    } else {
+        (length as i32).encode()
+    }
+}
+
+pub struct OffsetDeleteRequest {
+    pub group_id: String,
+    pub topics: Vec<OffsetDeleteRequestTopic>,
+}
+
+impl Parser<Self> for OffsetDeleteRequest {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        Ok(OffsetDeleteRequest {
+            group_id: parse_string(reader)?,
+            topics: parse_array(reader)?,
+        })
+    }
+}
+
+pub struct OffsetDeleteRequestTopic {
+    pub name: String,
+    pub partitions: Vec<i32>,
+}
+
+impl Parser<Self> for OffsetDeleteRequestTopic {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        Ok(OffsetDeleteRequestTopic {
+            name: parse_string(reader)?,
+            partitions: parse_array(reader)?,
+        })
+    }
+}
+
+pub struct OffsetDeleteResponse {
+    pub error_code: ErrorCode,
+    pub throttle_time_ms: i32,
+    pub topics: Vec<OffsetDeleteResponseTopic>,
+}
+
+impl Encoder for OffsetDeleteResponse {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.error_code.encode());
+        buf.extend(self.throttle_time_ms.encode());
+        buf.extend(encode_array(&self.topics));
+        buf
+    }
+}
+
+pub struct OffsetDeleteResponseTopic {
+    pub name: String,
+    pub partitions: Vec<OffsetDeleteResponsePartition>,
+}
+
+impl Encoder for OffsetDeleteResponseTopic {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(encode_string(&self.name));
+        buf.extend(encode_array(&self.partitions));
+        buf
+    }
+}
+
+pub struct OffsetDeleteResponsePartition {
+    pub partition_index: i32,
+    pub error_code: ErrorCode,
+}
+
+impl Encoder for OffsetDeleteResponsePartition {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.partition_index.encode());
+        buf.extend(self.error_code.encode());
+        buf
+    }
+}
+
+// the records of a produce are borrowed from the request frame, so that
+// they can be written out without being copied first
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct ProduceRequest<'a> {
+    pub transactional_id: Option<String>,
+    pub acks: i16,
+    pub timeout_ms: i32,
+    pub topic_data: Vec<ProduceTopicData<'a>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ProduceTopicData<'a> {
+    pub name: String,
+    pub partition_data: Vec<ProducePartitionData<'a>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ProducePartitionData<'a> {
+    pub index: i32,
+    pub records: Option<&'a [u8]>,
+}
+
+pub struct ProduceResponse {
+    // the version the response is encoded in
+    pub version: i16,
+    pub responses: Vec<ProduceTopicResponse>,
+    pub throttle_time_ms: i32,
+}
+
+impl Encoder for ProduceResponse {
+    fn encode(&self) -> Vec<u8> {
+        // v9 is the first flexible version
+        let flexible = self.version >= 9;
+
+        let mut buf = Vec::new();
+        buf.extend(encode_versioned_array_length(
+            self.responses.len(),
+            flexible,
+        ));
+        for topic in &self.responses {
+            buf.extend(topic.encode_fields(self.version));
+        }
+        buf.extend(self.throttle_time_ms.encode());
+        if flexible {
+            buf.extend(encode_tag_buffer());
+        }
+        buf
+    }
+}
+
+pub struct ProduceTopicResponse {
+    pub name: String,
+    pub partition_responses: Vec<ProduceResponsePartition>,
+}
+
+impl ProduceTopicResponse {
+    fn encode_fields(&self, version: i16) -> Vec<u8> {
+        let flexible = version >= 9;
+
+        let mut buf = Vec::new();
+        buf.extend(encode_versioned_string(&self.name, flexible));
+        buf.extend(encode_versioned_array_length(
+            self.partition_responses.len(),
+            flexible,
+        ));
+        for partition in &self.partition_responses {
+            buf.extend(partition.encode_fields(version));
+        }
+        if flexible {
+            buf.extend(encode_tag_buffer());
+        }
+        buf
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ProduceResponsePartition {
+    pub index: i32,
+    pub error_code: ErrorCode,
+    pub base_offset: i64,
+    pub log_append_time_ms: i64,
+    pub log_start_offset: i64,
+    // the records that made the batch invalid, from v8
+    pub record_errors: Vec<BatchIndexAndErrorMessage>,
+    pub error_message: Option<String>,
+}
+
+impl ProduceResponsePartition {
+    // nothing was appended, so there are no offsets to report
+    pub fn error(index: i32, error_code: ErrorCode) -> Self {
+        ProduceResponsePartition {
+            index,
+            error_code,
+            base_offset: -1,
+            log_append_time_ms: -1,
+            log_start_offset: -1,
+            record_errors: Vec::new(),
+            error_message: None,
+        }
+    }
+}
+
+impl ProduceResponsePartition {
+    fn encode_fields(&self, version: i16) -> Vec<u8> {
+        let flexible = version >= 9;
+
+        let mut buf = Vec::new();
+        buf.extend(self.index.encode());
+        buf.extend(self.error_code.encode());
+        buf.extend(self.base_offset.encode());
+        buf.extend(self.log_append_time_ms.encode());
+        if version >= 5 {
+            buf.extend(self.log_start_offset.encode());
+        }
+        if version >= 8 {
+            buf.extend(encode_versioned_array_length(
+                self.record_errors.len(),
+                flexible,
+            ));
+            for record_error in &self.record_errors {
+                buf.extend(record_error.batch_index.encode());
+                buf.extend(encode_versioned_nullable_string(
+                    &record_error.batch_index_error_message,
+                    flexible,
+                ));
+                if flexible {
+                    buf.extend(encode_tag_buffer());
+                }
+            }
+            buf.extend(encode_versioned_nullable_string(
+                &self.error_message,
+                flexible,
+            ));
+        }
+        if flexible {
+            buf.extend(encode_tag_buffer());
+        }
+        buf
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BatchIndexAndErrorMessage {
+    // of the record within its batch
+    pub batch_index: i32,
+    pub batch_index_error_message: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct MetadataRequest {
+    // null asks for every topic, while an empty list asks for none
+    pub topics: Option<Vec<MetadataRequestTopic>>,
+    pub allow_auto_topic_creation: bool,
+    pub include_topic_authorized_operations: bool,
+}
+
+impl Parser<Self> for MetadataRequest {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        let req = Ok(MetadataRequest {
+            topics: parse_compact_nullable_array(reader)?,
+            allow_auto_topic_creation: parse_bool(reader)?,
+            include_topic_authorized_operations: parse_bool(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
+    }
+}
+
+// topics are asked for by id or, with a zero id, by name
+#[derive(Debug, PartialEq)]
+pub struct MetadataRequestTopic {
+    pub topic_id: Uuid,
+    pub name: Option<String>,
+}
+
+impl Parser<Self> for MetadataRequestTopic {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        let topic = Ok(MetadataRequestTopic {
+            topic_id: Uuid::parse(reader)?,
+            name: parse_compact_nullable_string(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        topic
+    }
+}
+
+pub struct MetadataResponse {
+    pub throttle_time_ms: i32,
+    pub brokers: Vec<NodeEndpoint>,
+    pub cluster_id: Option<String>,
+    pub controller_id: i32,
+    pub topics: Vec<MetadataResponseTopic>,
+}
+
+impl Encoder for MetadataResponse {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.throttle_time_ms.encode());
+        buf.extend(encode_compact_array(&self.brokers));
+        buf.extend(encode_compact_nullable_string(&self.cluster_id));
+        buf.extend(self.controller_id.encode());
+        buf.extend(encode_compact_array(&self.topics));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+pub struct MetadataResponseTopic {
+    pub error_code: ErrorCode,
+    pub name: Option<String>,
+    pub topic_id: Uuid,
+    pub is_internal: bool,
+    pub partitions: Vec<MetadataResponsePartition>,
+    pub topic_authorized_operations: i32,
+}
+
+impl Encoder for MetadataResponseTopic {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.error_code.encode());
+        buf.extend(encode_compact_nullable_string(&self.name));
+        buf.extend(self.topic_id.encode());
+        buf.extend(encode_bool(self.is_internal));
+        buf.extend(encode_compact_array(&self.partitions));
+        buf.extend(self.topic_authorized_operations.encode());
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+pub struct MetadataResponsePartition {
+    pub error_code: ErrorCode,
+    pub partition_index: i32,
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+    pub replica_nodes: Vec<i32>,
+    pub isr_nodes: Vec<i32>,
+    pub offline_replicas: Vec<i32>,
+}
+
+impl Encoder for MetadataResponsePartition {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.error_code.encode());
+        buf.extend(self.partition_index.encode());
+        buf.extend(self.leader_id.encode());
+        buf.extend(self.leader_epoch.encode());
+        buf.extend(encode_compact_array(&self.replica_nodes));
+        buf.extend(encode_compact_array(&self.isr_nodes));
+        buf.extend(encode_compact_array(&self.offline_replicas));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct ElectLeadersRequest {
+    pub election_type: i8,
+    // null asks for every partition
+    pub topic_partitions: Option<Vec<ElectLeadersTopic>>,
+    pub timeout_ms: i32,
+}
+
+impl Parser<Self> for ElectLeadersRequest {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        let req = Ok(ElectLeadersRequest {
+            election_type: parse_int8(reader)?,
+            topic_partitions: parse_compact_nullable_array(reader)?,
+            timeout_ms: parse_int32(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ElectLeadersTopic {
+    pub topic: String,
+    pub partitions: Vec<i32>,
+}
+
+impl Parser<Self> for ElectLeadersTopic {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        let topic = Ok(ElectLeadersTopic {
+            topic: parse_compact_string(reader)?,
+            partitions: parse_compact_array(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        topic
+    }
+}
+
+pub struct ElectLeadersResponse {
+    pub throttle_time_ms: i32,
+    pub error_code: ErrorCode,
+    pub replica_election_results: Vec<ReplicaElectionResult>,
+}
+
+impl Encoder for ElectLeadersResponse {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.throttle_time_ms.encode());
+        buf.extend(self.error_code.encode());
+        buf.extend(encode_compact_array(&self.replica_election_results));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+pub struct ReplicaElectionResult {
+    pub topic: String,
+    pub partition_result: Vec<ElectionPartitionResult>,
+}
+
+impl Encoder for ReplicaElectionResult {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(encode_compact_string(&self.topic));
+        buf.extend(encode_compact_array(&self.partition_result));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+pub struct ElectionPartitionResult {
+    pub partition_id: i32,
+    pub error_code: ErrorCode,
+    pub error_message: Option<String>,
+}
+
+impl Encoder for ElectionPartitionResult {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.partition_id.encode());
+        buf.extend(self.error_code.encode());
+        buf.extend(encode_compact_nullable_string(&self.error_message));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+// DescribeCluster v0 and v1
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct DescribeClusterRequest {
+    pub include_cluster_authorized_operations: bool,
+    // v1+, 1 for brokers and 2 for controllers
+    pub endpoint_type: i8,
+}
+
+pub const BROKER_ENDPOINT_TYPE: i8 = 1;
+
+impl DescribeClusterRequest {
+    pub fn parse(reader: &mut impl Read, version: i16) -> Result<Self> {
+        let include_cluster_authorized_operations = parse_bool(reader)?;
+        let endpoint_type = if version >= 1 {
+            parse_int8(reader)?
+        } else {
+            BROKER_ENDPOINT_TYPE
+        };
+
+        parse_tag_buffer(reader)?;
+        Ok(DescribeClusterRequest {
+            include_cluster_authorized_operations,
+            endpoint_type,
+        })
+    }
+}
+
+pub struct DescribeClusterResponse {
+    // the version the response is encoded in
+    pub version: i16,
+    pub throttle_time_ms: i32,
+    pub error_code: ErrorCode,
+    pub error_message: Option<String>,
+    // v1+
+    pub endpoint_type: i8,
+    pub cluster_id: String,
+    pub controller_id: i32,
+    // laid out like the brokers of a Metadata response
+    pub brokers: Vec<NodeEndpoint>,
+    pub cluster_authorized_operations: i32,
+}
+
+impl Encoder for DescribeClusterResponse {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.throttle_time_ms.encode());
+        buf.extend(self.error_code.encode());
+        buf.extend(encode_compact_nullable_string(&self.error_message));
+        if self.version >= 1 {
+            buf.extend(self.endpoint_type.encode());
+        }
+        buf.extend(encode_compact_string(&self.cluster_id));
+        buf.extend(self.controller_id.encode());
+        buf.extend(encode_compact_array(&self.brokers));
+        buf.extend(self.cluster_authorized_operations.encode());
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct DescribeTopicPartitionsRequest {
+    pub topics: Vec<Option<String>>,
+    pub response_partition_limit: i32,
+    pub cursor: Option<KCursor>,
+}
+
+impl Parser<Self> for DescribeTopicPartitionsRequest {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        let req = Ok(DescribeTopicPartitionsRequest {
+            topics: parse_compact_array_with_tag_buffer(reader)?
+                .into_iter()
+                .map(|s: CompactNullableString| s.0)
+                .collect(),
+            response_partition_limit: parse_int32(reader)?,
+            cursor: parse_nullable_field(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        req
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, PartialEq)]
+pub struct KCursor {
+    pub topic_name: String,
+    pub partition_index: i32,
+}
+
+impl Parser<Self> for KCursor {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        let cursor = Ok(KCursor {
+            topic_name: parse_compact_string(reader)?,
+            partition_index: parse_int32(reader)?,
+        });
+
+        parse_tag_buffer(reader)?;
+        cursor
+    }
+}
+
+impl Encoder for KCursor {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(encode_compact_string(&self.topic_name));
+        buf.extend(self.partition_index.encode());
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+#[allow(dead_code)]
+pub struct DescribeTopicPartitionsResponse {
+    pub throttle_time_ms: i32,
+    pub topics: Vec<Topic>,
+    pub next_cursor: Option<KCursor>,
+}
+
+impl DescribeTopicPartitionsResponse {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(&self.throttle_time_ms.to_be_bytes());
+        buf.extend(&encode_compact_array(&self.topics));
+        buf.extend(encode_nullable_field(&self.next_cursor));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+#[allow(dead_code)]
+pub struct Topic {
+    pub error_code: ErrorCode,
+    pub name: Option<String>,
+    pub topic_id: Uuid,
+    pub is_internal: bool,
+    pub partitions: Vec<Partition>,
+    pub topic_authorized_operations: i32,
+}
+
+impl Parser<Self> for Topic {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        Ok(Topic {
+            error_code: ErrorCode::parse(reader)?,
+            name: Some(parse_compact_string(reader)?),
+            topic_id: Uuid::parse(reader)?,
+            is_internal: parse_bool(reader)?,
+            partitions: parse_compact_array(reader)?,
+            topic_authorized_operations: parse_int32(reader)?,
+        })
+    }
+}
+
+impl Encoder for Topic {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(&self.error_code.encode());
+        buf.extend(encode_compact_nullable_string(&self.name));
+        buf.extend(self.topic_id.encode());
+        buf.extend(encode_bool(self.is_internal));
+        buf.extend(encode_compact_array(&self.partitions));
+        buf.extend(self.topic_authorized_operations.encode());
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ErrorCode {
+    UnknownServerError,
+    NoError,
+    OffsetOutOfRange,
+    CorruptMessage,
+    UnknownTopicOrPartition,
+    InvalidFetchSize,
+    LeaderNotAvailable,
+    NotLeaderOrFollower,
+    RequestTimedOut,
+    BrokerNotAvailable,
+    ReplicaNotAvailable,
+    MessageTooLarge,
+    StaleControllerEpoch,
+    OffsetMetadataTooLarge,
+    NetworkException,
+    CoordinatorLoadInProgress,
+    CoordinatorNotAvailable,
+    NotCoordinator,
+    InvalidTopicException,
+    RecordListTooLarge,
+    NotEnoughReplicas,
+    NotEnoughReplicasAfterAppend,
+    InvalidRequiredAcks,
+    IllegalGeneration,
+    TopicAuthorizationFailed,
+    GroupAuthorizationFailed,
+    ClusterAuthorizationFailed,
+    InvalidTimestamp,
+    UnsupportedVersion,
+    TopicAlreadyExists,
+    InvalidPartitions,
+    InvalidReplicationFactor,
+    NotController,
+    InvalidRequest,
+    OutOfOrderSequenceNumber,
+    DuplicateSequenceNumber,
+    InvalidProducerEpoch,
+    InvalidTxnState,
+    KafkaStorageError,
+    UnknownProducerId,
+    GroupIdNotFound,
+    FetchSessionIdNotFound,
+    InvalidFetchSessionEpoch,
+    FencedLeaderEpoch,
+    UnknownLeaderEpoch,
+    OffsetNotAvailable,
+    ElectionNotNeeded,
+    InvalidRecord,
+    ThrottlingQuotaExceeded,
+    ProducerFenced,
+    // for topics addressed by id; by name they're UnknownTopicOrPartition
+    UnknownTopicId,
+    BrokerIdNotRegistered,
+    UnsupportedEndpointType,
+    // a code without a variant of its own, kept so that it's written back as is
+    Unknown(i16),
+}
+
+impl ErrorCode {
+    // the variant for code, Unknown only for the codes without one
+    pub fn from_code(code: i16) -> ErrorCode {
+        match code {
+            -1 => ErrorCode::UnknownServerError,
+            0 => ErrorCode::NoError,
+            1 => ErrorCode::OffsetOutOfRange,
+            2 => ErrorCode::CorruptMessage,
+            3 => ErrorCode::UnknownTopicOrPartition,
+            4 => ErrorCode::InvalidFetchSize,
+            5 => ErrorCode::LeaderNotAvailable,
+            6 => ErrorCode::NotLeaderOrFollower,
+            7 => ErrorCode::RequestTimedOut,
+            8 => ErrorCode::BrokerNotAvailable,
+            9 => ErrorCode::ReplicaNotAvailable,
+            10 => ErrorCode::MessageTooLarge,
+            11 => ErrorCode::StaleControllerEpoch,
+            12 => ErrorCode::OffsetMetadataTooLarge,
+            13 => ErrorCode::NetworkException,
+            14 => ErrorCode::CoordinatorLoadInProgress,
+            15 => ErrorCode::CoordinatorNotAvailable,
+            16 => ErrorCode::NotCoordinator,
+            17 => ErrorCode::InvalidTopicException,
+            18 => ErrorCode::RecordListTooLarge,
+            19 => ErrorCode::NotEnoughReplicas,
+            20 => ErrorCode::NotEnoughReplicasAfterAppend,
+            21 => ErrorCode::InvalidRequiredAcks,
+            22 => ErrorCode::IllegalGeneration,
+            29 => ErrorCode::TopicAuthorizationFailed,
+            30 => ErrorCode::GroupAuthorizationFailed,
+            31 => ErrorCode::ClusterAuthorizationFailed,
+            32 => ErrorCode::InvalidTimestamp,
+            35 => ErrorCode::UnsupportedVersion,
+            36 => ErrorCode::TopicAlreadyExists,
+            37 => ErrorCode::InvalidPartitions,
+            38 => ErrorCode::InvalidReplicationFactor,
+            41 => ErrorCode::NotController,
+            42 => ErrorCode::InvalidRequest,
+            45 => ErrorCode::OutOfOrderSequenceNumber,
+            46 => ErrorCode::DuplicateSequenceNumber,
+            47 => ErrorCode::InvalidProducerEpoch,
+            48 => ErrorCode::InvalidTxnState,
+            56 => ErrorCode::KafkaStorageError,
+            59 => ErrorCode::UnknownProducerId,
+            69 => ErrorCode::GroupIdNotFound,
+            70 => ErrorCode::FetchSessionIdNotFound,
+            71 => ErrorCode::InvalidFetchSessionEpoch,
+            74 => ErrorCode::FencedLeaderEpoch,
+            75 => ErrorCode::UnknownLeaderEpoch,
+            78 => ErrorCode::OffsetNotAvailable,
+            84 => ErrorCode::ElectionNotNeeded,
+            87 => ErrorCode::InvalidRecord,
+            89 => ErrorCode::ThrottlingQuotaExceeded,
+            90 => ErrorCode::ProducerFenced,
+            100 => ErrorCode::UnknownTopicId,
+            103 => ErrorCode::BrokerIdNotRegistered,
+            115 => ErrorCode::UnsupportedEndpointType,
+            code => ErrorCode::Unknown(code),
+        }
+    }
+
+    pub fn code(self) -> i16 {
+        match self {
+            ErrorCode::UnknownServerError => -1,
+            ErrorCode::NoError => 0,
+            ErrorCode::OffsetOutOfRange => 1,
+            ErrorCode::CorruptMessage => 2,
+            ErrorCode::UnknownTopicOrPartition => 3,
+            ErrorCode::InvalidFetchSize => 4,
+            ErrorCode::LeaderNotAvailable => 5,
+            ErrorCode::NotLeaderOrFollower => 6,
+            ErrorCode::RequestTimedOut => 7,
+            ErrorCode::BrokerNotAvailable => 8,
+            ErrorCode::ReplicaNotAvailable => 9,
+            ErrorCode::MessageTooLarge => 10,
+            ErrorCode::StaleControllerEpoch => 11,
+            ErrorCode::OffsetMetadataTooLarge => 12,
+            ErrorCode::NetworkException => 13,
+            ErrorCode::CoordinatorLoadInProgress => 14,
+            ErrorCode::CoordinatorNotAvailable => 15,
+            ErrorCode::NotCoordinator => 16,
+            ErrorCode::InvalidTopicException => 17,
+            ErrorCode::RecordListTooLarge => 18,
+            ErrorCode::NotEnoughReplicas => 19,
+            ErrorCode::NotEnoughReplicasAfterAppend => 20,
+            ErrorCode::InvalidRequiredAcks => 21,
+            ErrorCode::IllegalGeneration => 22,
+            ErrorCode::TopicAuthorizationFailed => 29,
+            ErrorCode::GroupAuthorizationFailed => 30,
+            ErrorCode::ClusterAuthorizationFailed => 31,
+            ErrorCode::InvalidTimestamp => 32,
+            ErrorCode::UnsupportedVersion => 35,
+            ErrorCode::TopicAlreadyExists => 36,
+            ErrorCode::InvalidPartitions => 37,
+            ErrorCode::InvalidReplicationFactor => 38,
+            ErrorCode::NotController => 41,
+            ErrorCode::InvalidRequest => 42,
+            ErrorCode::OutOfOrderSequenceNumber => 45,
+            ErrorCode::DuplicateSequenceNumber => 46,
+            ErrorCode::InvalidProducerEpoch => 47,
+            ErrorCode::InvalidTxnState => 48,
+            ErrorCode::KafkaStorageError => 56,
+            ErrorCode::UnknownProducerId => 59,
+            ErrorCode::GroupIdNotFound => 69,
+            ErrorCode::FetchSessionIdNotFound => 70,
+            ErrorCode::InvalidFetchSessionEpoch => 71,
+            ErrorCode::FencedLeaderEpoch => 74,
+            ErrorCode::UnknownLeaderEpoch => 75,
+            ErrorCode::OffsetNotAvailable => 78,
+            ErrorCode::ElectionNotNeeded => 84,
+            ErrorCode::InvalidRecord => 87,
+            ErrorCode::ThrottlingQuotaExceeded => 89,
+            ErrorCode::ProducerFenced => 90,
+            ErrorCode::UnknownTopicId => 100,
+            ErrorCode::BrokerIdNotRegistered => 103,
+            ErrorCode::UnsupportedEndpointType => 115,
+            ErrorCode::Unknown(code) => code,
+        }
+    }
+}
+
+// by code, so that an Unknown holding a code with a variant of its own is
+// the same error as that variant
+impl PartialEq for ErrorCode {
+    fn eq(&self, other: &Self) -> bool {
+        self.code() == other.code()
+    }
+}
+
+impl Parser<Self> for ErrorCode {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        Ok(ErrorCode::from_code(parse_int16(reader)?))
+    }
+}
+
+impl Encoder for ErrorCode {
+    fn encode(&self) -> Vec<u8> {
+        self.code().to_be_bytes().to_vec()
+    }
+}
+
+#[allow(dead_code)]
+pub struct Partition {
+    pub error_code: ErrorCode,
+    pub partition_index: i32,
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+    pub replica_nodes: Vec<i32>,
+    pub isr_nodes: Vec<i32>,
+    pub eligible_leader_replicas: Vec<i32>,
+    pub last_known_elr: Vec<i32>,
+    pub offline_replicas: Vec<i32>,
+}
+
+impl Parser<Self> for Partition {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        Ok(Partition {
+            error_code: ErrorCode::parse(reader)?,
+            partition_index: parse_int32(reader)?,
+            leader_id: parse_int32(reader)?,
+            leader_epoch: parse_int32(reader)?,
+            replica_nodes: parse_compact_array(reader)?,
+            isr_nodes: parse_compact_array(reader)?,
+            eligible_leader_replicas: parse_compact_array(reader)?,
+            last_known_elr: parse_compact_array(reader)?,
+            offline_replicas: parse_compact_array(reader)?,
+        })
+    }
+}
+
+impl Encoder for Partition {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(self.error_code.encode());
+        buf.extend(self.partition_index.encode());
+        buf.extend(self.leader_id.encode());
+        buf.extend(self.leader_epoch.encode());
+        buf.extend(encode_compact_array(&self.replica_nodes));
+        buf.extend(encode_compact_array(&self.isr_nodes));
+        buf.extend(encode_compact_array(&self.eligible_leader_replicas));
+        buf.extend(encode_compact_array(&self.last_known_elr));
+        buf.extend(encode_compact_array(&self.offline_replicas));
+        buf.extend(encode_tag_buffer());
+        buf
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, time::Instant};
+
+    use crate::api::{
+        AbortedTransaction, ApiKeys, ApiVersionsResponse, DescribeClusterRequest,
+        DescribeTopicPartitionsRequest, DescribeTopicPartitionsResponse, ElectLeadersRequest,
+        Encoder, ErrorCode, FetchRequestPartition, FetchResponse, FetchResponsePartition,
+        ListOffsetsResponsePartition, MetadataRequest, NodeEndpoint, OffsetFetchRequest,
+        OffsetFetchRequestTopic, OffsetFetchResponse, OffsetFetchResponsePartition,
+        OffsetFetchResponseTopic, Parser, Partition, ProduceResponse, ProduceResponsePartition,
+        ProduceTopicResponse, Topic, BROKER_ENDPOINT_TYPE,
+    };
+    use crate::primitives::Uuid;
+
+    #[test]
+    fn test_parse_fetch_request_partition() {
+        let mut buf = Vec::new();
+        buf.extend(1i32.to_be_bytes());
+        buf.extend(5i32.to_be_bytes());
+        buf.extend(42i64.to_be_bytes());
+        buf.extend((-1i32).to_be_bytes());
+        buf.extend(0i64.to_be_bytes());
+        buf.extend(1048576i32.to_be_bytes());
+        buf.push(0);
+
+        let partition = FetchRequestPartition::parse(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(1, partition.partition);
+        assert_eq!(5, partition.current_leader_epoch);
+        assert_eq!(42, partition.fetch_offset);
+        assert_eq!(-1, partition.last_fetched_epoch);
+        assert_eq!(0, partition.log_start_offset);
+        assert_eq!(1048576, partition.partition_max_bytes);
+    }
+
+    #[test]
+    fn test_parse_describe_topic_partitions_null_name() {
+        let mut buf = Vec::new();
+        buf.push(3);
+        buf.extend([4, b'f', b'o', b'o', 0]);
+        buf.extend([0, 0]);
+        buf.extend(100i32.to_be_bytes());
+        buf.extend([0xff, 0]);
+
+        let request = DescribeTopicPartitionsRequest::parse(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(vec![Some("foo".to_string()), None], request.topics);
+        assert!(request.cursor.is_none());
+    }
+
+    #[test]
+    fn test_parse_metadata_request_v12() {
+        let mut buf = Vec::new();
+        buf.push(3);
+        buf.extend([0; 16]);
+        buf.extend([4, b'f', b'o', b'o', 0]);
+        buf.extend([1; 16]);
+        buf.extend([0, 0]);
+        buf.extend([1, 0, 0]);
+
+        let request = MetadataRequest::parse(&mut Cursor::new(buf)).unwrap();
+        let topics = request.topics.unwrap();
+        assert_eq!(2, topics.len());
+        assert_eq!(Some("foo".to_string()), topics[0].name);
+        assert_eq!(Uuid { uuid: [1; 16] }, topics[1].topic_id);
+        assert!(topics[1].name.is_none());
+        assert!(request.allow_auto_topic_creation);
+
+        // a null topic list rather than an empty one
+        let request = MetadataRequest::parse(&mut Cursor::new(vec![0, 0, 0, 0])).unwrap();
+        assert!(request.topics.is_none());
+    }
+
+    #[test]
+    fn test_parse_elect_leaders_request() {
+        let mut buf = vec![0, 2, 4, b'f', b'o', b'o', 3];
+        buf.extend(0i32.to_be_bytes());
+        buf.extend(1i32.to_be_bytes());
+        buf.push(0);
+        buf.extend(60000i32.to_be_bytes());
+        buf.push(0);
+
+        let request = ElectLeadersRequest::parse(&mut Cursor::new(buf)).unwrap();
+        let topics = request.topic_partitions.unwrap();
+        assert_eq!("foo", topics[0].topic);
+        assert_eq!(vec![0, 1], topics[0].partitions);
+        assert_eq!(60000, request.timeout_ms);
+    }
+
+    #[test]
+    fn test_error_code_values() {
+        for (error_code, value) in [
+            (ErrorCode::UnknownTopicOrPartition, 3i16),
+            (ErrorCode::UnknownTopicId, 100),
+            (ErrorCode::BrokerIdNotRegistered, 103),
+            (ErrorCode::UnsupportedEndpointType, 115),
+        ] {
+            assert_eq!(value.to_be_bytes().to_vec(), error_code.encode());
+            let parsed = ErrorCode::parse(&mut Cursor::new(value.to_be_bytes())).unwrap();
+            assert_eq!(error_code, parsed);
+        }
+    }
+
+    #[test]
+    fn test_parse_error_codes_without_variant() {
+        let parse = |code: i16| ErrorCode::parse(&mut Cursor::new(code.to_be_bytes())).unwrap();
+
+        assert_eq!(ErrorCode::NotLeaderOrFollower, parse(6));
+        assert_eq!(ErrorCode::Unknown(12345), parse(12345));
+        // written back the way they were read
+        assert_eq!(12345i16.to_be_bytes().to_vec(), parse(12345).encode());
+        assert_eq!(6i16.to_be_bytes().to_vec(), parse(6).encode());
+
+        // an Unknown holding a known code is that code's error
+        assert_eq!(ErrorCode::NotLeaderOrFollower, ErrorCode::Unknown(6));
+        assert_ne!(ErrorCode::NotLeaderOrFollower, ErrorCode::Unknown(7));
+        assert!(matches!(
+            ErrorCode::from_code(ErrorCode::Unknown(6).code()),
+            ErrorCode::NotLeaderOrFollower
+        ));
+    }
+
+    #[test]
+    fn test_parse_describe_cluster_request() {
+        let request = DescribeClusterRequest::parse(&mut Cursor::new([1, 0]), 0).unwrap();
+        assert!(request.include_cluster_authorized_operations);
+        assert_eq!(BROKER_ENDPOINT_TYPE, request.endpoint_type);
+
+        let request = DescribeClusterRequest::parse(&mut Cursor::new([0, 2, 0]), 1).unwrap();
+        assert!(!request.include_cluster_authorized_operations);
+        assert_eq!(2, request.endpoint_type);
+    }
+
+    #[test]
+    fn test_encode_fetch_response_node_endpoints() {
+        let mut response = FetchResponse {
+            throttle_time_ms: 0,
+            error_code: ErrorCode::NoError,
+            session_id: 0,
+            responses: Vec::new(),
+            node_endpoints: Vec::new(),
+        };
+        assert_eq!(vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0], response.encode());
+
+        response.node_endpoints.push(NodeEndpoint {
+            node_id: 1,
+            host: "localhost".to_string(),
+            port: 9092,
+            rack: None,
+        });
+        let encoded = response.encode();
+
+        // one tagged field with tag 0 follows the empty responses array
+        assert_eq!([1, 0], encoded[11..13]);
+        let endpoints = &encoded[14..];
+        assert_eq!(2, endpoints[0]);
+        assert_eq!(1i32.to_be_bytes(), endpoints[1..5]);
+
+        let host = endpoints
+            .windows(9)
+            .position(|w| w == b"localhost")
+            .unwrap();
+        assert_eq!(9092i32.to_be_bytes(), endpoints[host + 9..host + 13]);
+        assert_eq!(encoded.len() - 14, encoded[13] as usize);
+    }
+
+    #[test]
+    fn test_encode_fetch_response_partition() {
+        let partition = FetchResponsePartition::new(2, vec![0xab], 5);
+        let encoded = partition.encode();
+
+        assert_eq!(2i32.to_be_bytes(), encoded[0..4]);
+        assert_eq!(0i16.to_be_bytes(), encoded[4..6]);
+        assert_eq!(5i64.to_be_bytes(), encoded[6..14]);
+        assert_eq!(5i64.to_be_bytes(), encoded[14..22]);
+        assert_eq!(0i64.to_be_bytes(), encoded[22..30]);
+        // no aborted transactions, no preferred replica, then the records
+        assert_eq!(1, encoded[30]);
+        assert_eq!((-1i32).to_be_bytes(), encoded[31..35]);
+        assert_eq!([2, 0xab, 0], encoded[35..]);
+    }
+
+    #[test]
+    fn test_encode_offsets_past_i32() {
+        // 12345678901 doesn't fit in an i32, so truncation would show here
+        let mut partition = FetchResponsePartition::new(0, vec![], 12345678901);
+        partition.log_start_offset = 1 << 40;
+        partition.aborted_transactions = vec![AbortedTransaction {
+            producer_id: 1 << 40,
+            first_offset: 12345678900,
+        }];
+
+        #[rustfmt::skip]
+        let expected = [
+            0, 0, 0, 0,
+            0, 0,
+            0x00, 0x00, 0x00, 0x02, 0xdf, 0xdc, 0x1c, 0x35,
+            0x00, 0x00, 0x00, 0x02, 0xdf, 0xdc, 0x1c, 0x35,
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+            2,
+            0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x02, 0xdf, 0xdc, 0x1c, 0x34,
+            0,
+            0xff, 0xff, 0xff, 0xff,
+            1,
+            0,
+        ];
+        assert_eq!(expected[..], partition.encode());
+
+        let list_offsets = ListOffsetsResponsePartition {
+            partition_index: 0,
+            error_code: ErrorCode::NoError,
+            timestamp: -1,
+            offset: 12345678901,
+            leader_epoch: 3,
+        };
+        #[rustfmt::skip]
+        let expected = [
+            0, 0, 0, 0,
+            0, 0,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0x00, 0x00, 0x00, 0x02, 0xdf, 0xdc, 0x1c, 0x35,
+            0, 0, 0, 3,
+            0,
+        ];
+        assert_eq!(expected[..], list_offsets.encode());
+    }
+
+    #[test]
+    fn test_encode_api_versions_response_per_version() {
+        let response = |version| ApiVersionsResponse {
+            version,
+            error_code: 0,
+            api_keys: vec![ApiKeys {
+                api_key: 18,
+                min_version: 0,
+                max_version: 4,
+            }],
+            throttle_time_ms: 0,
+        };
+
+        #[rustfmt::skip]
+        let v0 = [
+            0, 0,
+            0, 0, 0, 1,
+            0, 18, 0, 0, 0, 4,
+        ];
+        assert_eq!(v0[..], response(0).encode());
+
+        #[rustfmt::skip]
+        let v3 = [
+            0, 0,
+            2,
+            0, 18, 0, 0, 0, 4, 0,
+            0, 0, 0, 0,
+            0,
+        ];
+        assert_eq!(v3[..], response(3).encode());
+    }
+
+    #[test]
+    fn test_offset_fetch_per_version() {
+        // a null topic array fetches every committed partition
+        let v1 = [0, 1, b'g', 0, 0, 0, 1, 0, 1, b't', 0, 0, 0, 1, 0, 0, 0, 2];
+        let v7 = [2, b'g', 0, 1, 0];
+        assert_eq!(
+            OffsetFetchRequest {
+                group_id: "g".to_string(),
+                topics: Some(vec![OffsetFetchRequestTopic {
+                    name: "t".to_string(),
+                    partition_indexes: vec![2],
+                }]),
+                require_stable: false,
+            },
+            OffsetFetchRequest::parse(&mut Cursor::new(v1), 1).unwrap()
+        );
+        let v7 = OffsetFetchRequest::parse(&mut Cursor::new(v7), 7).unwrap();
+        assert_eq!(None, v7.topics);
+        assert!(v7.require_stable);
+
+        let response = |version| OffsetFetchResponse {
+            version,
+            throttle_time_ms: 0,
+            topics: vec![OffsetFetchResponseTopic {
+                name: "t".to_string(),
+                partitions: vec![OffsetFetchResponsePartition {
+                    partition_index: 2,
+                    committed_offset: 42,
+                    committed_leader_epoch: 3,
+                    metadata: None,
+                    error_code: ErrorCode::NoError,
+                }],
+            }],
+            error_code: ErrorCode::NoError,
+        };
+
+        #[rustfmt::skip]
+        let v1 = [
+            0, 0, 0, 1,
+            0, 1, b't',
+            0, 0, 0, 1,
+            0, 0, 0, 2,
+            0, 0, 0, 0, 0, 0, 0, 42,
+            255, 255,
+            0, 0,
+        ];
+        assert_eq!(v1[..], response(1).encode());
+
+        // the throttle time leads, the leader epoch follows the offset and
+        // the group's error code comes last
+        #[rustfmt::skip]
+        let v7 = [
+            0, 0, 0, 0,
+            2,
+            2, b't',
+            2,
+            0, 0, 0, 2,
+            0, 0, 0, 0, 0, 0, 0, 42,
+            0, 0, 0, 3,
+            0,
+            0, 0,
+            0,
+            0,
+            0, 0,
+            0,
+        ];
+        assert_eq!(v7[..], response(7).encode());
+    }
+
+    #[test]
+    fn test_encode_produce_response_per_version() {
+        let response = |version| ProduceResponse {
+            version,
+            responses: vec![ProduceTopicResponse {
+                name: "t".to_string(),
+                partition_responses: vec![ProduceResponsePartition {
+                    index: 1,
+                    error_code: ErrorCode::NoError,
+                    base_offset: 2,
+                    log_append_time_ms: -1,
+                    log_start_offset: 0,
+                    record_errors: Vec::new(),
+                    error_message: None,
+                }],
+            }],
+            throttle_time_ms: 0,
+        };
+
+        #[rustfmt::skip]
+        let v3 = [
+            0, 0, 0, 1,
+            0, 1, b't',
+            0, 0, 0, 1,
+            0, 0, 0, 1,
+            0, 0,
+            0, 0, 0, 0, 0, 0, 0, 2,
+            255, 255, 255, 255, 255, 255, 255, 255,
+            0, 0, 0, 0,
+        ];
+        assert_eq!(v3[..], response(3).encode());
+
+        // the log start offset, record errors and error message follow the
+        // log append time
+        #[rustfmt::skip]
+        let v8 = [
+            0, 0, 0, 1,
+            0, 1, b't',
+            0, 0, 0, 1,
+            0, 0, 0, 1,
+            0, 0,
+            0, 0, 0, 0, 0, 0, 0, 2,
+            255, 255, 255, 255, 255, 255, 255, 255,
+            0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0,
+            255, 255,
+            0, 0, 0, 0,
+        ];
+        assert_eq!(v8[..], response(8).encode());
+
+        #[rustfmt::skip]
+        let v9 = [
+            2,
+            2, b't',
+            2,
+            0, 0, 0, 1,
+            0, 0,
+            0, 0, 0, 0, 0, 0, 0, 2,
+            255, 255, 255, 255, 255, 255, 255, 255,
+            0, 0, 0, 0, 0, 0, 0, 0,
+            1,
+            0,
+            0,
+            0,
+            0, 0, 0, 0,
+            0,
+        ];
+        assert_eq!(v9[..], response(9).encode());
+    }
+
+    fn describe_response(partitions: i32) -> DescribeTopicPartitionsResponse {
+        DescribeTopicPartitionsResponse {
+            throttle_time_ms: 0,
+            topics: vec![Topic {
+                error_code: ErrorCode::NoError,
+                name: None,
+                topic_id: Uuid { uuid: [1; 16] },
+                is_internal: false,
+                partitions: (0..partitions)
+                    .map(|partition_index| Partition {
+                        error_code: ErrorCode::NoError,
+                        partition_index,
+                        leader_id: 1,
+                        leader_epoch: 0,
+                        replica_nodes: vec![1, 2, 3],
+                        isr_nodes: vec![1, 2, 3],
+                        eligible_leader_replicas: vec![],
+                        last_known_elr: vec![],
+                        offline_replicas: vec![],
+                    })
+                    .collect(),
+                topic_authorized_operations: 0,
+            }],
+            next_cursor: None,
+        }
+    }
+
+    // cargo test bench_encode_compact_array -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_encode_compact_array() {
+        let response = describe_response(10_000);
+        let records = FetchResponsePartition::new(0, vec![0; 1 << 20], 0);
+        let iterations = 100;
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            response.encode();
+        }
+        let describe = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            records.encode();
+        }
+        let fetch = start.elapsed();
+
+        println!("describe: {:?}, fetch: {:?}", describe, fetch);
+    }
+}
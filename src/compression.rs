@@ -0,0 +1,185 @@
+use std::io::{self, Read, Result};
+
+use flate2::read::GzDecoder;
+
+// the low three bits of a batch's attributes
+pub const COMPRESSION_CODEC_MASK: i16 = 0x07;
+
+// the java client frames snappy the way the xerial library does, a header
+// and then length prefixed blocks
+const XERIAL_MAGIC: [u8; 8] = [0x82, b'S', b'N', b'A', b'P', b'P', b'Y', 0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_attributes(attributes: i16) -> Result<Compression> {
+        match attributes & COMPRESSION_CODEC_MASK {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Gzip),
+            2 => Ok(Compression::Snappy),
+            3 => Ok(Compression::Lz4),
+            4 => Ok(Compression::Zstd),
+            codec => Err(invalid(format!("unsupported compression codec {}", codec))),
+        }
+    }
+
+    // the records section of a batch compressed with this codec, which may
+    // not decompress to more than max_size bytes. any failure is InvalidData,
+    // whatever the codec's own error said
+    pub fn decompress(self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        let records = match self {
+            Compression::None => data.to_vec(),
+            Compression::Gzip => read_bounded(GzDecoder::new(data), max_size)?,
+            Compression::Snappy => snappy(data, max_size)?,
+            Compression::Lz4 => read_bounded(lz4_flex::frame::FrameDecoder::new(data), max_size)?,
+            Compression::Zstd => read_bounded(
+                ruzstd::StreamingDecoder::new(data).map_err(invalid)?,
+                max_size,
+            )?,
+        };
+        if records.len() > max_size {
+            return Err(too_large(max_size));
+        }
+        Ok(records)
+    }
+}
+
+// reads one byte past max_size, to tell a stream that fits from one that doesn't
+fn read_bounded(decoder: impl Read, max_size: usize) -> Result<Vec<u8>> {
+    let mut records = Vec::new();
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut records)
+        .map_err(invalid)?;
+    Ok(records)
+}
+
+// xerial framed, or a single raw block as other clients write it. the size
+// of a block is in its header, so it's checked before anything is allocated
+fn snappy(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    let mut decoder = snap::raw::Decoder::new();
+    let Some(framed) = data.strip_prefix(&XERIAL_MAGIC) else {
+        if snap::raw::decompress_len(data).map_err(invalid)? > max_size {
+            return Err(too_large(max_size));
+        }
+        return decoder.decompress_vec(data).map_err(invalid);
+    };
+
+    // past the version and the oldest compatible version
+    let mut blocks = framed
+        .get(8..)
+        .ok_or_else(|| invalid("truncated snappy header"))?;
+    let mut records = Vec::new();
+    while !blocks.is_empty() {
+        let (length, rest) = blocks
+            .split_at_checked(4)
+            .ok_or_else(|| invalid("truncated snappy block length"))?;
+        let length = i32::from_be_bytes(length.try_into().unwrap()).max(0) as usize;
+        let (block, rest) = rest
+            .split_at_checked(length)
+            .ok_or_else(|| invalid("truncated snappy block"))?;
+        if records.len() + snap::raw::decompress_len(block).map_err(invalid)? > max_size {
+            return Err(too_large(max_size));
+        }
+        records.extend(decoder.decompress_vec(block).map_err(invalid)?);
+        blocks = rest;
+    }
+    Ok(records)
+}
+
+fn too_large(max_size: usize) -> io::Error {
+    invalid(format!(
+        "records decompress to more than {} bytes",
+        max_size
+    ))
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{ErrorKind, Write};
+
+    use flate2::{write::GzEncoder, Compression as GzLevel};
+
+    use crate::compression::{Compression, XERIAL_MAGIC};
+
+    const RECORDS: &[u8] = b"records records records records";
+
+    #[test]
+    fn test_decompress() {
+        let mut gzip = GzEncoder::new(Vec::new(), GzLevel::default());
+        gzip.write_all(RECORDS).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let snappy = snap::raw::Encoder::new().compress_vec(RECORDS).unwrap();
+        let mut xerial = XERIAL_MAGIC.to_vec();
+        xerial.extend(1i32.to_be_bytes());
+        xerial.extend(1i32.to_be_bytes());
+        for half in RECORDS.chunks(RECORDS.len() / 2 + 1) {
+            let block = snap::raw::Encoder::new().compress_vec(half).unwrap();
+            xerial.extend((block.len() as i32).to_be_bytes());
+            xerial.extend(block);
+        }
+
+        let mut lz4 = lz4_flex::frame::FrameEncoder::new(Vec::new());
+        lz4.write_all(RECORDS).unwrap();
+        let lz4 = lz4.finish().unwrap();
+
+        // a single segment frame of one raw block, its size in a single byte
+        let mut zstd = vec![0x28, 0xb5, 0x2f, 0xfd, 0x20, RECORDS.len() as u8];
+        zstd.extend((1 | (RECORDS.len() as u32) << 3).to_le_bytes()[..3].iter());
+        zstd.extend(RECORDS);
+
+        for (attributes, data) in [
+            (0, RECORDS.to_vec()),
+            (1, gzip),
+            (2, snappy),
+            (2, xerial),
+            (3, lz4),
+            (4, zstd),
+        ] {
+            let compression = Compression::from_attributes(attributes | 0x10).unwrap();
+            assert_eq!(RECORDS, compression.decompress(&data, 1024).unwrap());
+
+            // one byte short of the records is too small a limit
+            let err = compression
+                .decompress(&data, RECORDS.len() - 1)
+                .unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_unsupported_codec() {
+        let err = Compression::from_attributes(5).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_corrupt_data_is_invalid() {
+        let snappy = snap::raw::Encoder::new().compress_vec(RECORDS).unwrap();
+        let mut xerial = XERIAL_MAGIC.to_vec();
+        xerial.extend([0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0x10, 0]);
+
+        for (compression, data) in [
+            (Compression::Gzip, RECORDS),
+            (Compression::Snappy, &snappy[..snappy.len() / 2]),
+            (Compression::Snappy, &xerial[..]),
+            (Compression::Lz4, RECORDS),
+            (Compression::Zstd, RECORDS),
+        ] {
+            let err = compression.decompress(data, 1024).unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, err.kind(), "{:?}", compression);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::{api::ErrorCode, primitives::Uuid};
+
+// a full fetch that opens a new session
+const INITIAL_EPOCH: i32 = 0;
+// a full fetch without a session, closing the given one if any
+const FINAL_EPOCH: i32 = -1;
+// max.incremental.fetch.session.cache.slots default
+const MAX_SESSIONS: usize = 1000;
+// min.incremental.fetch.session.eviction.ms default, how long a session has
+// to go unused before a new one can take its place
+const EVICTION_IDLE: Duration = Duration::from_secs(120);
+
+#[derive(Debug)]
+pub struct FetchSession {
+    pub epoch: i32,
+    pub partitions: BTreeSet<(Uuid, i32)>,
+    last_used: Instant,
+}
+
+// incremental fetch sessions, keyed by session id
+#[derive(Debug)]
+pub struct FetchSessionCache {
+    next_id: i32,
+    sessions: HashMap<i32, FetchSession>,
+    max_sessions: usize,
+    eviction_idle: Duration,
+}
+
+impl Default for FetchSessionCache {
+    fn default() -> Self {
+        FetchSessionCache::with_limits(MAX_SESSIONS, EVICTION_IDLE)
+    }
+}
+
+impl FetchSessionCache {
+    pub fn new() -> Self {
+        FetchSessionCache::default()
+    }
+
+    pub fn with_limits(max_sessions: usize, eviction_idle: Duration) -> Self {
+        FetchSessionCache {
+            next_id: 1,
+            sessions: HashMap::new(),
+            max_sessions,
+            eviction_idle,
+        }
+    }
+
+    // applies a fetch request to its session, returning the session id to
+    // answer with and the partitions the fetch covers
+    pub fn update(
+        &mut self,
+        session_id: i32,
+        epoch: i32,
+        requested: impl IntoIterator<Item = (Uuid, i32)>,
+        forgotten: impl IntoIterator<Item = (Uuid, i32)>,
+    ) -> Result<(i32, Vec<(Uuid, i32)>), ErrorCode> {
+        match epoch {
+            FINAL_EPOCH => {
+                self.sessions.remove(&session_id);
+                Ok((0, requested.into_iter().collect()))
+            }
+            INITIAL_EPOCH => {
+                self.sessions.remove(&session_id);
+
+                // without room for a session the fetch is answered without
+                // one, and the client carries on with full fetches
+                if self.sessions.len() >= self.max_sessions && !self.evict_idlest() {
+                    return Ok((0, requested.into_iter().collect()));
+                }
+
+                let session_id = self.next_id;
+                self.next_id += 1;
+
+                let session = FetchSession {
+                    epoch: INITIAL_EPOCH + 1,
+                    partitions: requested.into_iter().collect(),
+                    last_used: Instant::now(),
+                };
+                let partitions = session.partitions.iter().cloned().collect();
+                self.sessions.insert(session_id, session);
+                Ok((session_id, partitions))
+            }
+            _ => {
+                let session = self
+                    .sessions
+                    .get_mut(&session_id)
+                    .ok_or(ErrorCode::FetchSessionIdNotFound)?;
+                if session.epoch != epoch {
+                    return Err(ErrorCode::InvalidFetchSessionEpoch);
+                }
+
+                session.epoch += 1;
+                session.last_used = Instant::now();
+                session.partitions.extend(requested);
+                for partition in forgotten {
+                    session.partitions.remove(&partition);
+                }
+
+                Ok((session_id, session.partitions.iter().cloned().collect()))
+            }
+        }
+    }
+
+    // drops the least recently used session, if it has been idle long enough
+    fn evict_idlest(&mut self) -> bool {
+        let idlest = self
+            .sessions
+            .iter()
+            .min_by_key(|(_, session)| session.last_used)
+            .map(|(id, session)| (*id, session.last_used));
+
+        match idlest {
+            Some((id, last_used)) if last_used.elapsed() >= self.eviction_idle => {
+                self.sessions.remove(&id);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{api::ErrorCode, fetch_session::FetchSessionCache, primitives::Uuid};
+
+    #[test]
+    fn test_session_epochs() {
+        let mut cache = FetchSessionCache::new();
+        let foo = Uuid { uuid: [1; 16] };
+
+        let (session_id, partitions) = cache.update(0, 0, [(foo.clone(), 0)], []).unwrap();
+        assert_eq!(1, session_id);
+        assert_eq!(vec![(foo.clone(), 0)], partitions);
+
+        assert_eq!(
+            Err(ErrorCode::InvalidFetchSessionEpoch),
+            cache.update(session_id, 2, [], [])
+        );
+        assert_eq!(
+            Err(ErrorCode::FetchSessionIdNotFound),
+            cache.update(session_id + 1, 1, [], [])
+        );
+
+        let (_, partitions) = cache.update(session_id, 1, [], []).unwrap();
+        assert_eq!(vec![(foo, 0)], partitions);
+
+        // the final epoch closes the session
+        assert_eq!(Ok((0, vec![])), cache.update(session_id, -1, [], []));
+        assert_eq!(
+            Err(ErrorCode::FetchSessionIdNotFound),
+            cache.update(session_id, 2, [], [])
+        );
+    }
+
+    #[test]
+    fn test_full_cache() {
+        let foo = Uuid { uuid: [1; 16] };
+
+        // the only session is still in use, so a new fetch gets none
+        let mut cache = FetchSessionCache::with_limits(1, Duration::from_secs(3600));
+        let (first, _) = cache.update(0, 0, [(foo.clone(), 0)], []).unwrap();
+        assert_eq!(
+            Ok((0, vec![(foo.clone(), 1)])),
+            cache.update(0, 0, [(foo.clone(), 1)], [])
+        );
+        assert!(cache.update(first, 1, [], []).is_ok());
+
+        // an idle session makes room for the new one
+        let mut cache = FetchSessionCache::with_limits(1, Duration::ZERO);
+        let (first, _) = cache.update(0, 0, [(foo.clone(), 0)], []).unwrap();
+        let (second, _) = cache.update(0, 0, [(foo.clone(), 1)], []).unwrap();
+        assert_ne!(0, second);
+        assert_eq!(
+            Err(ErrorCode::FetchSessionIdNotFound),
+            cache.update(first, 1, [], [])
+        );
+        assert!(cache.update(second, 1, [], []).is_ok());
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    fs,
+    io::{ErrorKind, Result},
+    path::Path,
+};
+
+use crate::{
+    log_dir::segment_paths,
+    metadata_log::ClusterMetadataLog,
+    segment::{BatchPosition, Segment},
+};
+
+pub const RETENTION_MS: &str = "retention.ms";
+pub const SEGMENT_BYTES: &str = "segment.bytes";
+pub const CLEANUP_POLICY: &str = "cleanup.policy";
+pub const MAX_MESSAGE_BYTES: &str = "max.message.bytes";
+
+// log.retention.hours and log.segment.bytes defaults
+const DEFAULT_RETENTION_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+const DEFAULT_SEGMENT_BYTES: i64 = 1024 * 1024 * 1024;
+// message.max.bytes default, a megabyte plus the batch overhead
+const DEFAULT_MAX_MESSAGE_BYTES: i64 = 1024 * 1024 + 12;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CleanupPolicy {
+    Delete,
+    Compact,
+}
+
+// how a topic's log is retained and rolled, the broker defaults unless the
+// topic overrides them
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    // -1 keeps data forever
+    pub retention_ms: i64,
+    pub segment_bytes: i64,
+    pub cleanup_policy: CleanupPolicy,
+    // the largest batch a produce may append
+    pub max_message_bytes: i64,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            retention_ms: DEFAULT_RETENTION_MS,
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            cleanup_policy: CleanupPolicy::Delete,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+        }
+    }
+}
+
+impl LogConfig {
+    // overrides that don't parse are ignored, leaving the default in place
+    pub fn for_topic(metadata_log: &ClusterMetadataLog, topic: &str) -> LogConfig {
+        let mut config = LogConfig::default();
+
+        if let Some(value) = metadata_log.topic_config(topic, RETENTION_MS) {
+            config.retention_ms = value.parse().unwrap_or(config.retention_ms);
+        }
+        if let Some(value) = metadata_log.topic_config(topic, SEGMENT_BYTES) {
+            config.segment_bytes = value.parse().unwrap_or(config.segment_bytes);
+        }
+        if let Some(value) = metadata_log.topic_config(topic, MAX_MESSAGE_BYTES) {
+            config.max_message_bytes = value.parse().unwrap_or(config.max_message_bytes);
+        }
+        if let Some(value) = metadata_log.topic_config(topic, CLEANUP_POLICY) {
+            // compact,delete keeps both, which compaction already implies here
+            if value.split(',').any(|policy| policy.trim() == "compact") {
+                config.cleanup_policy = CleanupPolicy::Compact;
+            }
+        }
+
+        config
+    }
+
+    // the batches old enough for retention to delete; compacted topics are
+    // cleaned by key instead, so nothing expires on age
+    pub fn expired<'a>(&self, segment: &'a Segment, now_ms: i64) -> Vec<&'a BatchPosition> {
+        if self.cleanup_policy == CleanupPolicy::Compact || self.retention_ms < 0 {
+            return Vec::new();
+        }
+
+        segment
+            .batches()
+            .iter()
+            .filter(|b| b.max_timestamp < now_ms - self.retention_ms)
+            .collect()
+    }
+
+    pub fn should_roll(&self, segment_size: usize) -> bool {
+        segment_size as i64 >= self.segment_bytes
+    }
+
+    // deletes the oldest segments of the partition in dir whose every batch
+    // has expired, returning how many. The last segment is never deleted, as
+    // appends go to it
+    pub fn delete_expired(&self, dir: &Path, now_ms: i64) -> Result<usize> {
+        let paths = match segment_paths(dir) {
+            Ok(paths) => paths,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+
+        let mut deleted = 0;
+        for path in &paths[..paths.len().saturating_sub(1)] {
+            let segment = Segment::open(path)?;
+            if self.expired(&segment, now_ms).len() < segment.batches().len() {
+                break;
+            }
+            fs::remove_file(path)?;
+            match fs::remove_file(path.with_extension("index")) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        log_config::{CleanupPolicy, LogConfig, RETENTION_MS},
+        log_dir::LogDir,
+        metadata_log::{ClusterMetadataLog, ConfigRecord, RecordBatch, RecordBody, TOPIC_RESOURCE},
+        segment::{test::batch, Segment},
+    };
+
+    fn config_record(topic: &str, name: &str, value: &str) -> RecordBody {
+        RecordBody::Config(ConfigRecord {
+            resource_type: TOPIC_RESOURCE,
+            resource_name: topic.to_string(),
+            name: name.to_string(),
+            value: Some(value.to_string()),
+        })
+    }
+
+    #[test]
+    fn test_topic_retention_override() {
+        let mut metadata_log = ClusterMetadataLog::new(LogDir::new("/nonexistent"));
+        metadata_log.set_batches(vec![RecordBatch::new(
+            0,
+            vec![
+                config_record("foo", RETENTION_MS, "5000"),
+                config_record("foo", RETENTION_MS, "1000"),
+                config_record("bar", "cleanup.policy", "compact"),
+            ],
+        )]);
+
+        let foo = LogConfig::for_topic(&metadata_log, "foo");
+        let bar = LogConfig::for_topic(&metadata_log, "bar");
+        assert_eq!(1000, foo.retention_ms);
+        assert_eq!(LogConfig::default().retention_ms, bar.retention_ms);
+        assert_eq!(CleanupPolicy::Compact, bar.cleanup_policy);
+
+        // batches written at times 0 and 5000
+        let mut data = batch(0, 0, b"a");
+        let mut second = batch(1, 0, b"b");
+        second[35..43].copy_from_slice(&5000i64.to_be_bytes());
+        data.extend(second);
+        let segment = Segment::from_bytes(data);
+
+        let expired = foo.expired(&segment, 5500);
+        assert_eq!(1, expired.len());
+        assert_eq!(0, expired[0].base_offset);
+        assert!(LogConfig::default().expired(&segment, 5500).is_empty());
+        assert!(bar.expired(&segment, i64::MAX).is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::{primitives::Uuid, properties::read_properties};
+
+pub const METADATA_TOPIC: &str = "__cluster_metadata";
+// written into each log dir by kafka-storage format
+const META_PROPERTIES: &str = "meta.properties";
+const DIRECTORY_ID: &str = "directory.id";
+
+// the on-disk layout under log.dirs: one directory per topic partition,
+// holding segment and index files named after their zero-padded base offset.
+// with several log dirs (JBOD) each partition lives in one of them
+#[derive(Debug, Clone)]
+pub struct LogDir {
+    roots: Vec<PathBuf>,
+    // the directory.id of each root, read once when the log dirs are built
+    directory_ids: Vec<Option<Uuid>>,
+    // metadata.log.dir, when the metadata log is kept apart from the data
+    metadata_root: Option<PathBuf>,
+}
+
+#[allow(dead_code)]
+impl LogDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LogDir::with_dirs([root])
+    }
+
+    pub fn with_dirs(roots: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        let roots: Vec<PathBuf> = roots.into_iter().map(Into::into).collect();
+        LogDir {
+            directory_ids: roots.iter().map(|root| read_directory_id(root)).collect(),
+            roots,
+            metadata_root: None,
+        }
+    }
+
+    pub fn with_metadata_dir(self, root: impl Into<PathBuf>) -> Self {
+        LogDir {
+            metadata_root: Some(root.into()),
+            ..self
+        }
+    }
+
+    fn metadata_roots(&self) -> &[PathBuf] {
+        match &self.metadata_root {
+            Some(root) => std::slice::from_ref(root),
+            None => &self.roots,
+        }
+    }
+
+    // where the metadata log is, or would be created
+    pub fn metadata_log_path(&self) -> PathBuf {
+        match &self.metadata_root {
+            Some(root) => root
+                .join(partition_dir_name(METADATA_TOPIC, 0))
+                .join(file_name(0, "log")),
+            None => self.segment_path(METADATA_TOPIC, 0, 0),
+        }
+    }
+
+    // the segments of the metadata log, which has to be in exactly one of
+    // the log dirs, in offset order
+    pub fn find_metadata_log(&self) -> Result<Vec<PathBuf>> {
+        let dir_name = partition_dir_name(METADATA_TOPIC, 0);
+        let found: Vec<PathBuf> = self
+            .metadata_roots()
+            .iter()
+            .map(|root| root.join(&dir_name))
+            .filter(|dir| dir.is_dir())
+            .collect();
+
+        match found.as_slice() {
+            [dir] => match segment_paths(dir)? {
+                paths if paths.is_empty() => Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("no segments in {:?}", dir),
+                )),
+                paths => Ok(paths),
+            },
+            [] => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no {} in log dirs {:?}", dir_name, self.metadata_roots()),
+            )),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} found in more than one log dir: {:?}", dir_name, found),
+            )),
+        }
+    }
+
+    // the one log dir with the given directory.id, which partition records
+    // assign replicas to in JBOD setups
+    pub fn for_directory(&self, directory_id: &Uuid) -> Option<LogDir> {
+        let i = self
+            .directory_ids
+            .iter()
+            .position(|id| id.as_ref() == Some(directory_id))?;
+        Some(LogDir {
+            roots: vec![self.roots[i].clone()],
+            directory_ids: vec![self.directory_ids[i].clone()],
+            metadata_root: self.metadata_root.clone(),
+        })
+    }
+
+    // the log dir already holding the partition, the first one for new partitions
+    pub fn partition_dir(&self, topic: &str, partition: i32) -> PathBuf {
+        let dir_name = partition_dir_name(topic, partition);
+        self.roots
+            .iter()
+            .map(|root| root.join(&dir_name))
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| self.roots[0].join(&dir_name))
+    }
+
+    pub fn segment_path(&self, topic: &str, partition: i32, base_offset: i64) -> PathBuf {
+        self.partition_dir(topic, partition)
+            .join(file_name(base_offset, "log"))
+    }
+
+    pub fn index_path(&self, topic: &str, partition: i32, base_offset: i64) -> PathBuf {
+        self.partition_dir(topic, partition)
+            .join(file_name(base_offset, "index"))
+    }
+}
+
+// the .log files of a partition dir, sorted by the base offset they're named after
+pub fn segment_paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            if let Some(base_offset) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<i64>().ok())
+            {
+                paths.push((base_offset, path));
+            }
+        }
+    }
+    paths.sort();
+
+    Ok(paths.into_iter().map(|(_, path)| path).collect())
+}
+
+// the segment of a partition dir starting at base_offset
+pub fn segment_file(dir: &Path, base_offset: i64) -> PathBuf {
+    dir.join(file_name(base_offset, "log"))
+}
+
+// the names kafka allows for topics, which are also the only ones safe to
+// make partition dir names of
+pub fn is_legal_topic_name(name: &str) -> bool {
+    (1..=249).contains(&name.len())
+        && name != "."
+        && name != ".."
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'.' | b'_' | b'-'))
+}
+
+fn read_directory_id(root: &Path) -> Option<Uuid> {
+    let properties = read_properties(root.join(META_PROPERTIES)).ok()?;
+    Uuid::from_base64(properties.get(DIRECTORY_ID)?)
+}
+
+fn partition_dir_name(topic: &str, partition: i32) -> String {
+    format!("{}-{}", topic, partition)
+}
+
+fn file_name(base_offset: i64, extension: &str) -> String {
+    format!("{:020}.{}", base_offset, extension)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::PathBuf, process};
+
+    use crate::{
+        log_dir::{is_legal_topic_name, LogDir},
+        primitives::Uuid,
+    };
+
+    #[test]
+    fn test_metadata_log_path() {
+        let log_dir = LogDir::new("/tmp/kraft-combined-logs");
+        assert_eq!(
+            PathBuf::from("/tmp/kraft-combined-logs/__cluster_metadata-0/00000000000000000000.log"),
+            log_dir.metadata_log_path()
+        );
+    }
+
+    #[test]
+    fn test_partition_paths() {
+        let log_dir = LogDir::new("/logs");
+        assert_eq!(
+            PathBuf::from("/logs/foo-3"),
+            log_dir.partition_dir("foo", 3)
+        );
+        assert_eq!(
+            PathBuf::from("/logs/foo-3/00000000000000001234.log"),
+            log_dir.segment_path("foo", 3, 1234)
+        );
+        assert_eq!(
+            PathBuf::from("/logs/foo-3/00000000000000001234.index"),
+            log_dir.index_path("foo", 3, 1234)
+        );
+        assert_eq!(
+            "09223372036854775807.log",
+            log_dir
+                .segment_path("foo", 0, i64::MAX)
+                .file_name()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_multiple_log_dirs() {
+        let base = env::temp_dir().join(format!("kafka-test-{}-jbod", process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let (first, second) = (base.join("first"), base.join("second"));
+        fs::create_dir_all(first.join("foo-0")).unwrap();
+        fs::create_dir_all(second.join("__cluster_metadata-0")).unwrap();
+        let segment = second.join("__cluster_metadata-0/00000000000000000000.log");
+        fs::write(&segment, []).unwrap();
+
+        let log_dir = LogDir::with_dirs([&first, &second]);
+        assert_eq!(vec![segment], log_dir.find_metadata_log().unwrap());
+        assert_eq!(first.join("foo-0"), log_dir.partition_dir("foo", 0));
+        assert_eq!(first.join("bar-0"), log_dir.partition_dir("bar", 0));
+
+        let missing = LogDir::new(&first).find_metadata_log().unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, missing.kind());
+
+        fs::create_dir_all(first.join("__cluster_metadata-0")).unwrap();
+        let ambiguous = log_dir.find_metadata_log().unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, ambiguous.kind());
+    }
+
+    #[test]
+    fn test_log_dir_for_directory_id() {
+        let base = env::temp_dir().join(format!("kafka-test-{}-directory-id", process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let (first, second) = (base.join("first"), base.join("second"));
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        fs::write(
+            second.join("meta.properties"),
+            "version=1\ndirectory.id=AgICAgICAgICAgICAgICAg\n",
+        )
+        .unwrap();
+
+        let log_dir = LogDir::with_dirs([&first, &second]);
+        let assigned = log_dir.for_directory(&Uuid { uuid: [2; 16] }).unwrap();
+        assert_eq!(second.join("foo-0"), assigned.partition_dir("foo", 0));
+        assert!(log_dir.for_directory(&Uuid { uuid: [3; 16] }).is_none());
+
+        // the ids were read when the log dirs were built, not per lookup
+        fs::remove_file(second.join("meta.properties")).unwrap();
+        assert!(log_dir.for_directory(&Uuid { uuid: [2; 16] }).is_some());
+    }
+
+    #[test]
+    fn test_legal_topic_names() {
+        for name in [
+            "foo",
+            "foo.bar_baz-1",
+            "__consumer_offsets",
+            &"a".repeat(249),
+        ] {
+            assert!(is_legal_topic_name(name), "{}", name);
+        }
+        for name in [
+            "",
+            ".",
+            "..",
+            "../foo",
+            "foo/bar",
+            "foo bar",
+            &"a".repeat(250),
+        ] {
+            assert!(!is_legal_topic_name(name), "{}", name);
+        }
+    }
+}
@@ -1,32 +1,214 @@
 #![allow(unused_imports)]
 mod api;
+mod compression;
+mod crc;
+mod fetch_session;
+mod log_config;
+mod log_dir;
 mod metadata_log;
+mod offset_index;
+mod offsets;
 mod primitives;
+mod properties;
+mod quota;
+mod segment;
+mod slice;
+mod txn;
 
 use core::panic;
 use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap},
     env,
     fs::File,
-    io::{BufReader, Cursor, ErrorKind, Read, Write},
-    net::{TcpListener, TcpStream},
+    future::Future,
+    io::{self, BufReader, Cursor, ErrorKind, Read, Write},
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::Path,
+    process,
     sync::{Arc, Mutex},
     thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpListener, TcpSocket, TcpStream},
+    runtime, signal,
+    sync::{watch, RwLock},
+    task::{self, JoinSet},
+    time,
+};
+
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
+
 use api::{
-    Encoder, FetchRequest, FetchResponse, FetchResponsePartition, FetchResponseResponse, Partition,
+    Encoder, FetchRequest, FetchResponse, FetchResponsePartition, FetchResponseResponse,
+    ListOffsetsRequest, ListOffsetsRequestPartition, ListOffsetsResponse,
+    ListOffsetsResponsePartition, ListOffsetsResponseTopic, Partition, EARLIEST_TIMESTAMP,
+    LATEST_TIMESTAMP, READ_COMMITTED,
+};
+use fetch_session::FetchSessionCache;
+use log_config::LogConfig;
+use log_dir::{is_legal_topic_name, LogDir};
+use metadata_log::{
+    ClusterMetadataLog, MetadataVersion, PartitionRecord, RecordBody, RecordType, TopicRecord,
 };
-use metadata_log::{ClusterMetadataLog, RecordBody, RecordType, TopicRecord};
+use offsets::{expire_timestamp, CommittedOffset, OffsetStore};
 use primitives::{encode_tag_buffer, parse_nullable_string, parse_tag_buffer, Uuid};
+use quota::{quota_key, ClientQuotas};
+use segment::{read_log_start_offset, validate_records, Segment, SegmentSet, SegmentWriter};
+use slice::{SliceParser, SliceReader};
+use txn::{control_batch, mark_transactional, TransactionCoordinator};
 
 use crate::api::{
-    ApiKeys, ApiVersionsRequest, ApiVersionsResponse, DescribeTopicPartitionsRequest,
-    DescribeTopicPartitionsResponse, ErrorCode, KCursor, Parser, Topic,
+    AbortedTransaction, AddPartitionsToTxnPartitionResult, AddPartitionsToTxnRequest,
+    AddPartitionsToTxnResponse, AddPartitionsToTxnTopicResult, ApiKeys, ApiVersionsRequest,
+    ApiVersionsResponse, BrokerHeartbeatRequest, BrokerHeartbeatResponse, DescribeClusterRequest,
+    DescribeClusterResponse, DescribeTopicPartitionsRequest, DescribeTopicPartitionsResponse,
+    ElectLeadersRequest, ElectLeadersResponse, ElectionPartitionResult, EndTxnRequest,
+    EndTxnResponse, ErrorCode, KCursor, MetadataRequest, MetadataRequestTopic, MetadataResponse,
+    MetadataResponsePartition, MetadataResponseTopic, NodeEndpoint, OffsetCommitRequest,
+    OffsetCommitResponse, OffsetCommitResponsePartition, OffsetCommitResponseTopic,
+    OffsetDeleteRequest, OffsetDeleteResponse, OffsetDeleteResponsePartition,
+    OffsetDeleteResponseTopic, OffsetFetchRequest, OffsetFetchResponse,
+    OffsetFetchResponsePartition, OffsetFetchResponseTopic, Parser, ProducePartitionData,
+    ProduceRequest, ProduceResponse, ProduceResponsePartition, ProduceTopicResponse,
+    ReplicaElectionResult, Topic, BROKER_ENDPOINT_TYPE,
 };
 
-struct Request {
+const LOG_DIR: &str = "/tmp/kraft-combined-logs";
+const NODE_ID: i32 = 1;
+const HOST: &str = "127.0.0.1";
+const PORT: i32 = 9092;
+const UNIMPLEMENTED_APIS: UnimplementedApis = UnimplementedApis::Reject;
+// max.request.partition.size.limit default
+const MAX_REQUEST_PARTITION_SIZE_LIMIT: i32 = 2000;
+// socket.request.max.bytes default
+const SOCKET_REQUEST_MAX_BYTES: usize = 104857600;
+// socket.listen.backlog.size default
+const SOCKET_LISTEN_BACKLOG_SIZE: u32 = 50;
+
+// connections.max.idle.ms default
+const CONNECTIONS_MAX_IDLE: Duration = Duration::from_secs(600);
+// how often the metadata log is checked for what the controller appended
+const METADATA_RELOAD_INTERVAL: Duration = Duration::from_millis(100);
+// log.retention.check.interval.ms default
+const LOG_RETENTION_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// connection tasks are named after it and the peer address in panic reports
+const HANDLER_TASK_PREFIX: &str = "connection";
+// of the runtime's worker threads, tokio's own default
+const HANDLER_THREAD_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+const CLIENT_LISTENER: &str = "PLAINTEXT";
+const CONTROLLER_LISTENER: &str = "CONTROLLER";
+
+// per connection state
+struct Connection {
+    listener_name: String,
+}
+
+impl Connection {
+    fn new(listener_name: &str) -> Self {
+        Connection {
+            listener_name: listener_name.to_string(),
+        }
+    }
+
+    fn is_controller(&self) -> bool {
+        self.listener_name == CONTROLLER_LISTENER
+    }
+
+    // the control plane APIs are only served on the controller listener
+    fn serves(&self, api_key: i16) -> bool {
+        self.is_controller() || !controller_api_keys().iter().any(|k| k.api_key == api_key)
+    }
+}
+
+// shared by the listener and all connection tasks, which stop once it's triggered
+#[derive(Clone)]
+struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+}
+
+#[allow(dead_code)]
+impl Shutdown {
+    fn new() -> Self {
+        Shutdown {
+            triggered: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    // completes once the shutdown is triggered, right away if it already was
+    async fn triggered(&self) {
+        let mut receiver = self.triggered.subscribe();
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+// what to do with requests for APIs we don't implement, known or not
+#[derive(Debug, PartialEq)]
+enum UnimplementedApis {
+    // answer with an UnsupportedVersion error
+    Reject,
+    // close the connection
+    Close,
+}
+
+// how the properties file says to handle unimplemented APIs, default when it
+// doesn't say or says something else
+fn unimplemented_apis(
+    properties: &HashMap<String, String>,
+    default: UnimplementedApis,
+) -> UnimplementedApis {
+    match properties
+        .get(properties::UNIMPLEMENTED_APIS)
+        .map(String::as_str)
+    {
+        Some("reject") => UnimplementedApis::Reject,
+        Some("close") => UnimplementedApis::Close,
+        _ => default,
+    }
+}
+
+// state shared by all connections
+struct Broker {
+    endpoint: NodeEndpoint,
+    log_dir: LogDir,
+    // read by every describe and fetch, written only when the log is reloaded
+    metadata_log: RwLock<ClusterMetadataLog>,
+    offsets: Mutex<OffsetStore>,
+    transactions: Mutex<TransactionCoordinator>,
+    segments: SegmentWriter,
+    // partitions without an entry have all of their log committed
+    high_watermarks: Mutex<HashMap<(String, i32), i64>>,
+    // the offsets each follower last fetched from, by partition and replica
+    follower_offsets: Mutex<HashMap<(String, i32), HashMap<i32, i64>>>,
+    unimplemented_apis: UnimplementedApis,
+    fetch_sessions: Mutex<FetchSessionCache>,
+    quotas: Mutex<ClientQuotas>,
+    // the most partitions a DescribeTopicPartitions response holds
+    partition_size_limit: i32,
+    // socket.request.max.bytes, the largest request frame read and Metadata
+    // response body sent in full
+    socket_request_max_bytes: usize,
+}
+
+// borrows from the request frame it was parsed from
+struct Request<'a> {
     header: RequestHeader,
-    body: RequestBody,
+    body: RequestBody<'a>,
 }
 
 #[allow(dead_code)]
@@ -35,7 +217,7 @@ struct RequestHeader {
     request_api_key: i16,
     request_api_version: i16,
     correlation_id: i32,
-    client_id: String,
+    client_id: Option<String>,
 }
 
 struct Response {
@@ -49,319 +231,5734 @@ struct ResponseHeader {
 }
 
 enum ApiKey {
+    Produce = 0,
     Fetch = 1,
+    ListOffsets = 2,
+    Metadata = 3,
+    ControlledShutdown = 7,
+    OffsetCommit = 8,
+    OffsetFetch = 9,
     ApiVersions = 18,
+    AddPartitionsToTxn = 24,
+    EndTxn = 26,
+    ElectLeaders = 43,
+    OffsetDelete = 47,
+    DescribeCluster = 60,
+    Vote = 52,
+    BeginQuorumEpoch = 53,
+    EndQuorumEpoch = 54,
+    DescribeQuorum = 55,
+    BrokerRegistration = 62,
+    BrokerHeartbeat = 63,
     DescribeTopicPartitions = 75,
 }
 
-enum RequestBody {
+enum RequestBody<'a> {
+    Produce(ProduceRequest<'a>),
     Fetch(FetchRequest),
+    ListOffsets(ListOffsetsRequest),
+    Metadata(MetadataRequest),
+    OffsetCommit(OffsetCommitRequest),
+    OffsetFetch(OffsetFetchRequest),
     ApiVersions(ApiVersionsRequest),
+    AddPartitionsToTxn(AddPartitionsToTxnRequest),
+    EndTxn(EndTxnRequest),
+    ElectLeaders(ElectLeadersRequest),
+    OffsetDelete(OffsetDeleteRequest),
+    DescribeCluster(DescribeClusterRequest),
+    BrokerHeartbeat(BrokerHeartbeatRequest),
     DescribeTopicPartitions(DescribeTopicPartitionsRequest),
+    // a known API without a handler, or not served on the connection's
+    // listener; its body is left unparsed
+    Unimplemented,
+    // an API key we don't know at all, so neither its body nor its response
+    // layout
+    Unknown,
 }
 
 enum ResponseBody {
+    Produce(ProduceResponse),
     Fetch(FetchResponse),
+    ListOffsets(ListOffsetsResponse),
+    Metadata(MetadataResponse),
+    OffsetCommit(OffsetCommitResponse),
+    OffsetFetch(OffsetFetchResponse),
     ApiVersions(ApiVersionsResponse),
+    AddPartitionsToTxn(AddPartitionsToTxnResponse),
+    EndTxn(EndTxnResponse),
+    ElectLeaders(ElectLeadersResponse),
+    OffsetDelete(OffsetDeleteResponse),
+    DescribeCluster(DescribeClusterResponse),
+    BrokerHeartbeat(BrokerHeartbeatResponse),
     DescribeTopicPartitions(DescribeTopicPartitionsResponse),
 }
 
-fn parse_request(message: &[u8]) -> Request {
-    let mut cursor = Cursor::new(message);
+impl ResponseBody {
+    // tells the client how long it is throttled for, if longer than the
+    // handler already did
+    fn throttle(&mut self, throttle: Duration) {
+        let throttle_time_ms = match self {
+            ResponseBody::Produce(r) => &mut r.throttle_time_ms,
+            ResponseBody::Fetch(r) => &mut r.throttle_time_ms,
+            ResponseBody::ListOffsets(r) => &mut r.throttle_time_ms,
+            ResponseBody::Metadata(r) => &mut r.throttle_time_ms,
+            ResponseBody::OffsetCommit(r) => &mut r.throttle_time_ms,
+            ResponseBody::OffsetFetch(r) => &mut r.throttle_time_ms,
+            ResponseBody::ApiVersions(r) => &mut r.throttle_time_ms,
+            ResponseBody::AddPartitionsToTxn(r) => &mut r.throttle_time_ms,
+            ResponseBody::EndTxn(r) => &mut r.throttle_time_ms,
+            ResponseBody::ElectLeaders(r) => &mut r.throttle_time_ms,
+            ResponseBody::OffsetDelete(r) => &mut r.throttle_time_ms,
+            ResponseBody::DescribeCluster(r) => &mut r.throttle_time_ms,
+            ResponseBody::BrokerHeartbeat(r) => &mut r.throttle_time_ms,
+            ResponseBody::DescribeTopicPartitions(r) => &mut r.throttle_time_ms,
+        };
+        let throttle = throttle.as_millis().min(i32::MAX as u128) as i32;
+        *throttle_time_ms = (*throttle_time_ms).max(throttle);
+    }
+}
+
+enum ParseError {
+    // without a header there is no correlation id to answer to
+    Header(std::io::Error),
+    Body(RequestHeader, std::io::Error),
+}
+
+fn parse_request<'a>(
+    message: &'a [u8],
+    connection: &Connection,
+) -> Result<Request<'a>, ParseError> {
+    let mut reader = SliceReader::new(message);
+
+    let header = parse_request_header(&mut reader).map_err(ParseError::Header)?;
+    if !connection.serves(header.request_api_key) {
+        return Ok(Request {
+            header,
+            body: RequestBody::Unimplemented,
+        });
+    }
+    match parse_request_body(&header, &mut reader) {
+        Ok(body) => Ok(Request { header, body }),
+        Err(err) => Err(ParseError::Body(header, err)),
+    }
+}
+
+fn parse_request_body<'a>(
+    header: &RequestHeader,
+    reader: &mut SliceReader<'a>,
+) -> std::io::Result<RequestBody<'a>> {
+    // the less common APIs only have Read based parsers
+    let cursor = &mut Cursor::new(reader.remaining());
 
-    let header = parse_request_header(&mut cursor);
     let body = match header.request_api_key {
-        value if value == ApiKey::Fetch as i16 => {
-            let req = FetchRequest::parse(&mut cursor).expect("failed to parse Fetch request");
-            RequestBody::Fetch(req)
+        value if value == ApiKey::Produce as i16 => RequestBody::Produce(
+            ProduceRequest::parse_slice(reader, header.request_api_version)?,
+        ),
+        value if value == ApiKey::Fetch as i16 => RequestBody::Fetch(FetchRequest::parse(cursor)?),
+        value if value == ApiKey::ListOffsets as i16 => {
+            RequestBody::ListOffsets(ListOffsetsRequest::parse(cursor)?)
+        }
+        value if value == ApiKey::Metadata as i16 => {
+            RequestBody::Metadata(MetadataRequest::parse(cursor)?)
+        }
+        value if value == ApiKey::OffsetCommit as i16 => RequestBody::OffsetCommit(
+            OffsetCommitRequest::parse(cursor, header.request_api_version)?,
+        ),
+        value if value == ApiKey::OffsetFetch as i16 => RequestBody::OffsetFetch(
+            OffsetFetchRequest::parse(cursor, header.request_api_version)?,
+        ),
+        // the client software name and version only came with v3
+        value if value == ApiKey::ApiVersions as i16 && header.request_api_version < 3 => {
+            RequestBody::ApiVersions(ApiVersionsRequest::default())
         }
         value if value == ApiKey::ApiVersions as i16 => {
-            let req = ApiVersionsRequest::parse(&mut cursor)
-                .expect("failed to parse ApiVersions request");
-            RequestBody::ApiVersions(req)
+            RequestBody::ApiVersions(ApiVersionsRequest::parse_slice(reader)?)
+        }
+        value if value == ApiKey::AddPartitionsToTxn as i16 => {
+            RequestBody::AddPartitionsToTxn(AddPartitionsToTxnRequest::parse(cursor)?)
+        }
+        value if value == ApiKey::EndTxn as i16 => {
+            RequestBody::EndTxn(EndTxnRequest::parse(cursor)?)
+        }
+        value if value == ApiKey::ElectLeaders as i16 => {
+            RequestBody::ElectLeaders(ElectLeadersRequest::parse(cursor)?)
+        }
+        value if value == ApiKey::OffsetDelete as i16 => {
+            RequestBody::OffsetDelete(OffsetDeleteRequest::parse(cursor)?)
+        }
+        value if value == ApiKey::DescribeCluster as i16 => RequestBody::DescribeCluster(
+            DescribeClusterRequest::parse(cursor, header.request_api_version)?,
+        ),
+        value if value == ApiKey::BrokerHeartbeat as i16 => {
+            RequestBody::BrokerHeartbeat(BrokerHeartbeatRequest::parse(cursor)?)
         }
         value if value == ApiKey::DescribeTopicPartitions as i16 => {
-            let req = DescribeTopicPartitionsRequest::parse(&mut cursor)
-                .expect("failed to parse DescribeTopicPartitions request");
-            RequestBody::DescribeTopicPartitions(req)
+            RequestBody::DescribeTopicPartitions(DescribeTopicPartitionsRequest::parse_slice(
+                reader,
+            )?)
         }
-        _ => panic!("Unknown API key: {}", header.request_api_key),
+        value if is_unimplemented_api(value) => RequestBody::Unimplemented,
+        _ => RequestBody::Unknown,
     };
 
-    Request { header, body }
+    Ok(body)
 }
 
-fn parse_request_header(message: &mut impl Read) -> RequestHeader {
-    let mut buf = [0; 2];
-    message.read_exact(&mut buf).unwrap();
-    let request_api_key = i16::from_be_bytes(buf);
+// the APIs we know but have no handler for
+fn is_unimplemented_api(api_key: i16) -> bool {
+    [
+        ApiKey::ControlledShutdown,
+        ApiKey::Vote,
+        ApiKey::BeginQuorumEpoch,
+        ApiKey::EndQuorumEpoch,
+        ApiKey::DescribeQuorum,
+        ApiKey::BrokerRegistration,
+    ]
+    .into_iter()
+    .any(|key| key as i16 == api_key)
+}
+
+// the first version of an API with a flexible request header, None for the
+// ones that have none. APIs we don't know at all are assumed to be flexible
+// from the start, as every API added since flexible versions were is
+fn first_flexible_version(api_key: i16) -> Option<i16> {
+    match api_key {
+        value if value == ApiKey::Produce as i16 => Some(9),
+        value if value == ApiKey::Fetch as i16 => Some(12),
+        value if value == ApiKey::ListOffsets as i16 => Some(6),
+        value if value == ApiKey::Metadata as i16 => Some(9),
+        value if value == ApiKey::ControlledShutdown as i16 => Some(3),
+        value if value == ApiKey::OffsetCommit as i16 => Some(8),
+        value if value == ApiKey::OffsetFetch as i16 => Some(6),
+        value if value == ApiKey::ApiVersions as i16 => Some(3),
+        value if value == ApiKey::AddPartitionsToTxn as i16 => Some(3),
+        value if value == ApiKey::EndTxn as i16 => Some(3),
+        value if value == ApiKey::ElectLeaders as i16 => Some(2),
+        value if value == ApiKey::OffsetDelete as i16 => None,
+        value if value == ApiKey::BeginQuorumEpoch as i16 => Some(1),
+        value if value == ApiKey::EndQuorumEpoch as i16 => Some(1),
+        _ => Some(0),
+    }
+}
 
-    message.read_exact(&mut buf).unwrap();
-    let request_api_version = i16::from_be_bytes(buf);
+// v2 headers end in a tag buffer, v1 ones don't and v0 ones, only left for
+// ControlledShutdown v0, don't have a client id either
+fn request_header_version(api_key: i16, api_version: i16) -> i16 {
+    if api_key == ApiKey::ControlledShutdown as i16 && api_version == 0 {
+        return 0;
+    }
+    match first_flexible_version(api_key) {
+        Some(flexible) if api_version >= flexible => 2,
+        _ => 1,
+    }
+}
 
-    let mut buf = [0; 4];
-    message.read_exact(&mut buf).unwrap();
-    let correlation_id = i32::from_be_bytes(buf);
+fn parse_request_header(reader: &mut SliceReader) -> std::io::Result<RequestHeader> {
+    let request_api_key = reader.int16()?;
+    let request_api_version = reader.int16()?;
+    let correlation_id = reader.int32()?;
 
-    let client_id = parse_nullable_string(message).expect("failed to parse request header");
-    parse_tag_buffer(message).expect("failed to parse request header");
+    let header_version = request_header_version(request_api_key, request_api_version);
+    let client_id = match header_version {
+        0 => None,
+        _ => reader.nullable_string_lossy()?,
+    };
+    if header_version >= 2 {
+        reader.tag_buffer()?;
+    }
 
-    return RequestHeader {
+    Ok(RequestHeader {
         request_api_key,
         request_api_version,
         correlation_id,
         client_id,
+    })
+}
+
+fn response_header(header: &RequestHeader) -> ResponseHeader {
+    // a v1 response header with its tag buffer answers a v2 request header,
+    // except for ApiVersions, which always answers with a v0 one
+    let include_tag_buffer = header.request_api_key != ApiKey::ApiVersions as i16
+        && request_header_version(header.request_api_key, header.request_api_version) >= 2;
+
+    ResponseHeader {
+        correlation_id: header.correlation_id,
+        include_tag_buffer,
+    }
+}
+
+// the smallest response carrying error_code, for APIs with a top level error
+fn error_response(header: &RequestHeader, error_code: ErrorCode) -> Option<Response> {
+    let body = match header.request_api_key {
+        value if value == ApiKey::Fetch as i16 => ResponseBody::Fetch(FetchResponse {
+            throttle_time_ms: 0,
+            error_code,
+            session_id: 0,
+            responses: Vec::new(),
+            node_endpoints: Vec::new(),
+        }),
+        value if value == ApiKey::ApiVersions as i16 => {
+            ResponseBody::ApiVersions(ApiVersionsResponse {
+                version: api_versions_response_version(header.request_api_version),
+                error_code: error_code.code(),
+                api_keys: Vec::new(),
+                throttle_time_ms: 0,
+            })
+        }
+        value if value == ApiKey::EndTxn as i16 => ResponseBody::EndTxn(EndTxnResponse {
+            throttle_time_ms: 0,
+            error_code,
+        }),
+        value if value == ApiKey::BrokerHeartbeat as i16 => {
+            ResponseBody::BrokerHeartbeat(BrokerHeartbeatResponse {
+                throttle_time_ms: 0,
+                error_code,
+                is_caught_up: false,
+                is_fenced: true,
+                should_shut_down: false,
+            })
+        }
+        value if value == ApiKey::OffsetDelete as i16 => {
+            ResponseBody::OffsetDelete(OffsetDeleteResponse {
+                error_code,
+                throttle_time_ms: 0,
+                topics: Vec::new(),
+            })
+        }
+        _ => return None,
     };
+
+    Some(Response {
+        header: response_header(header),
+        body,
+    })
 }
 
-fn handle_request(request: &Request, metadata_log: &Arc<Mutex<ClusterMetadataLog>>) -> Response {
-    let mut include_tag_buffer = true;
+async fn handle_request(
+    request: &Request<'_>,
+    broker: &Broker,
+    connection: &Connection,
+) -> Response {
     let resp_body = match &request.body {
+        RequestBody::Produce(body) => {
+            let resp = handle_produce(&request.header, body, broker).await;
+            ResponseBody::Produce(resp)
+        }
         RequestBody::Fetch(body) => {
-            let resp = handle_fetch(&request.header, &body, metadata_log);
+            let resp = handle_fetch(&request.header, body, broker).await;
             ResponseBody::Fetch(resp)
         }
+        RequestBody::ListOffsets(body) => {
+            let resp = handle_list_offsets(&request.header, body, broker).await;
+            ResponseBody::ListOffsets(resp)
+        }
+        RequestBody::Metadata(body) => {
+            let resp = handle_metadata(&request.header, body, broker).await;
+            ResponseBody::Metadata(resp)
+        }
+        RequestBody::OffsetCommit(body) => {
+            let resp = handle_offset_commit(&request.header, body, broker).await;
+            ResponseBody::OffsetCommit(resp)
+        }
+        RequestBody::OffsetFetch(body) => {
+            let resp = handle_offset_fetch(&request.header, body, &broker.offsets);
+            ResponseBody::OffsetFetch(resp)
+        }
         RequestBody::ApiVersions(body) => {
-            include_tag_buffer = false;
-            let resp = handle_apiversions(&request.header, &body);
+            let resp = handle_apiversions(&request.header, body, connection);
             ResponseBody::ApiVersions(resp)
         }
+        RequestBody::Unimplemented | RequestBody::Unknown => {
+            // the response layout of an API we don't serve can't be encoded,
+            // so it is answered the way an unsupported ApiVersions version
+            // is, with a v0 response listing what the client can use instead
+            let mut api_keys = client_api_keys();
+            if connection.is_controller() {
+                api_keys.extend(controller_api_keys());
+            }
+            return Response {
+                header: ResponseHeader {
+                    correlation_id: request.header.correlation_id,
+                    include_tag_buffer: false,
+                },
+                body: ResponseBody::ApiVersions(ApiVersionsResponse {
+                    version: 0,
+                    error_code: ErrorCode::UnsupportedVersion.code(),
+                    api_keys,
+                    throttle_time_ms: 0,
+                }),
+            };
+        }
+        RequestBody::AddPartitionsToTxn(body) => {
+            let resp = handle_add_partitions_to_txn(&request.header, body, &broker.transactions);
+            ResponseBody::AddPartitionsToTxn(resp)
+        }
+        RequestBody::EndTxn(body) => {
+            let resp = handle_end_txn(&request.header, body, broker).await;
+            ResponseBody::EndTxn(resp)
+        }
+        RequestBody::ElectLeaders(body) => {
+            let resp = handle_elect_leaders(&request.header, body, broker).await;
+            ResponseBody::ElectLeaders(resp)
+        }
+        RequestBody::OffsetDelete(body) => {
+            let resp = handle_offset_delete(&request.header, body, &broker.offsets);
+            ResponseBody::OffsetDelete(resp)
+        }
+        RequestBody::DescribeCluster(body) => {
+            let resp = handle_describe_cluster(&request.header, body, broker);
+            ResponseBody::DescribeCluster(resp)
+        }
+        RequestBody::BrokerHeartbeat(body) => {
+            let resp = handle_broker_heartbeat(&request.header, body, broker).await;
+            ResponseBody::BrokerHeartbeat(resp)
+        }
         RequestBody::DescribeTopicPartitions(body) => {
-            let resp = handle_describe_topic_partitions(&request.header, &body, metadata_log);
+            let resp = handle_describe_topic_partitions(
+                &request.header,
+                body,
+                &broker.metadata_log,
+                broker.partition_size_limit,
+            )
+            .await;
             ResponseBody::DescribeTopicPartitions(resp)
         }
     };
 
     Response {
-        header: ResponseHeader {
-            correlation_id: request.header.correlation_id,
-            include_tag_buffer,
-        },
+        header: response_header(&request.header),
         body: resp_body,
     }
 }
 
-fn handle_fetch(
+// for the std file I/O of reading and appending to logs, so that a slow disk
+// doesn't stall the other tasks on this worker. Only multi threaded runtimes
+// have workers to hand those tasks to, others run the I/O in place
+fn blocking_io<T>(io: impl FnOnce() -> T) -> T {
+    match runtime::Handle::try_current().map(|handle| handle.runtime_flavor()) {
+        Ok(runtime::RuntimeFlavor::MultiThread) => task::block_in_place(io),
+        _ => io(),
+    }
+}
+
+async fn handle_fetch(
     _header: &RequestHeader,
     request: &FetchRequest,
-    metadata_log: &Arc<Mutex<ClusterMetadataLog>>,
+    broker: &Broker,
 ) -> FetchResponse {
-    match request.topics.first() {
-        Some(topic) => {
-            let message_data = metadata_log
-                .lock()
-                .unwrap()
-                .message(&topic.topic_id)
-                .expect("unable to read record batch");
-
-            let mut error_code = ErrorCode::UnknownTopic;
-            let mut records = Vec::new();
-
-            if let Some(r) = message_data {
-                records = r;
-                error_code = ErrorCode::NoError;
-            }
+    let requested = request.topics.iter().flat_map(|topic| {
+        topic
+            .partitions
+            .iter()
+            .map(|p| (topic.topic_id.clone(), p.partition))
+    });
+    let forgotten = request.forgotten_topics_data.iter().flat_map(|topic| {
+        topic
+            .partitions
+            .iter()
+            .map(|partition| (topic.topic_id.clone(), *partition))
+    });
 
-            FetchResponse {
+    let session = broker.fetch_sessions.lock().unwrap().update(
+        request.session_id,
+        request.session_epoch,
+        requested,
+        forgotten,
+    );
+    let (session_id, mut partitions) = match session {
+        Ok(session) => session,
+        Err(error_code) => {
+            return FetchResponse {
                 throttle_time_ms: 0,
-                error_code: ErrorCode::NoError,
+                error_code,
                 session_id: 0,
-                responses: vec![FetchResponseResponse {
-                    topic_id: topic.topic_id.clone(),
-                    partitions: vec![FetchResponsePartition {
-                        partition_index: 0,
-                        error_code,
-                        high_watermark: 0,
-                        last_stable_offset: 0,
-                        log_start_offset: 0,
-                        aborted_transactions: vec![],
-                        preferred_read_replica: 0,
-                        records,
-                    }],
-                }],
+                responses: vec![],
+                node_endpoints: vec![],
             }
         }
-        None => FetchResponse {
-            throttle_time_ms: 0,
-            error_code: ErrorCode::NoError,
-            session_id: 0,
-            responses: vec![],
-        },
-    }
-}
+    };
 
-fn handle_apiversions(header: &RequestHeader, _body: &ApiVersionsRequest) -> ApiVersionsResponse {
-    let error_code = if header.request_api_version >= 0 && header.request_api_version <= 4 {
-        ErrorCode::NoError
-    } else {
-        ErrorCode::UnsupportedVersion
+    // sessions keep partitions sorted by topic id, but topics are answered in
+    // the order they were requested, followed by the rest of the session
+    let requested_position = |topic_id: &Uuid| {
+        request
+            .topics
+            .iter()
+            .position(|topic| &topic.topic_id == topic_id)
+            .unwrap_or(request.topics.len())
     };
+    partitions.sort_by_key(|(topic_id, _)| requested_position(topic_id));
 
-    ApiVersionsResponse {
-        error_code: error_code as i16,
-        api_keys: vec![
-            ApiKeys {
-                api_key: ApiKey::Fetch as i16,
-                min_version: 0,
-                max_version: 16,
-            },
-            ApiKeys {
-                api_key: ApiKey::ApiVersions as i16,
-                min_version: 0,
-                max_version: 4,
-            },
-            ApiKeys {
-                api_key: ApiKey::DescribeTopicPartitions as i16,
-                min_version: 0,
-                max_version: 0,
-            },
-        ],
+    // with less than min_bytes to return, wait up to max_wait_ms for more,
+    // reading the logs again only once something was appended to them
+    let deadline = Instant::now() + Duration::from_millis(request.max_wait_ms.max(0) as u64);
+    let deadline = time::Instant::from_std(deadline);
+    let mut appended = broker.segments.subscribe();
+    let responses = loop {
+        // seen before reading, so an append racing the read still wakes the wait
+        appended.borrow_and_update();
+        // not held while waiting, so that the log can be reloaded meanwhile
+        let (responses, bytes) = {
+            let metadata = broker.metadata_log.read().await;
+            blocking_io(|| read_partitions(request, broker, &metadata, &partitions))
+        };
+        if bytes >= request.min_bytes.max(0) as usize || time::Instant::now() >= deadline {
+            break responses;
+        }
+        tokio::select! {
+            _ = appended.changed() => {}
+            _ = time::sleep_until(deadline) => {}
+        }
+    };
+
+    // the top level error_code fails the whole request, as for a session that
+    // can't be used. Partition errors, unknown topic ids among them, stay with
+    // their partitions, or clients would drop the ones that were read too
+    FetchResponse {
         throttle_time_ms: 0,
+        error_code: ErrorCode::NoError,
+        session_id,
+        node_endpoints: node_endpoints(&responses, &broker.endpoint),
+        responses,
     }
 }
 
-fn handle_describe_topic_partitions(
-    _: &RequestHeader,
-    request: &DescribeTopicPartitionsRequest,
-    metadata_log: &Arc<Mutex<ClusterMetadataLog>>,
-) -> DescribeTopicPartitionsResponse {
-    let metadata = metadata_log.lock().unwrap();
+// the partitions grouped by topic, and how many bytes of records they hold.
+// max_bytes caps the records of the whole response, only the first batch
+// read may go over it so that consumers always make progress
+fn read_partitions(
+    request: &FetchRequest,
+    broker: &Broker,
+    metadata: &ClusterMetadataLog,
+    partitions: &[(Uuid, i32)],
+) -> (Vec<FetchResponseResponse>, usize) {
+    let max_bytes = request.max_bytes.max(0) as usize;
+    let mut bytes = 0;
 
-    let mut topics = Vec::new();
-    let mut topic_id = Uuid::new();
-
-    for record in metadata.records() {
-        if let RecordBody::Topic(topic) = &record {
-            if request.topics.contains(&topic.topic_name) {
-                topic_id = topic.topic_uuid.clone();
-
-                topics.push(Topic {
-                    error_code: ErrorCode::NoError,
-                    name: Some(topic.topic_name.clone()),
-                    topic_id: topic_id.clone(),
-                    is_internal: false,
-                    partitions: Vec::new(),
-                    topic_authorized_operations: 0,
-                });
-            }
-        } else if let RecordBody::Partition(partition) = record {
-            if partition.topic_id == topic_id {
-                let resp_partition = Partition {
-                    error_code: ErrorCode::NoError,
-                    partition_index: partition.partition_id,
-                    leader_id: partition.leader,
-                    leader_epoch: partition.leader_epoch,
-                    replica_nodes: partition.replicas.clone(),
-                    isr_nodes: partition.isr.clone(),
-                    eligible_leader_replicas: Vec::new(),
-                    last_known_elr: Vec::new(),
-                    offline_replicas: Vec::new(),
-                };
+    let mut responses: Vec<FetchResponseResponse> = Vec::new();
+    for (topic_id, partition_index) in partitions {
+        let remaining = max_bytes.saturating_sub(bytes);
+        let mut partition = read_partition(
+            request,
+            broker,
+            metadata,
+            topic_id,
+            *partition_index,
+            remaining,
+        );
+        if bytes > 0 && partition.records.len() > remaining {
+            partition.records.clear();
+        }
+        bytes += partition.records.len();
 
-                topics.last_mut().unwrap().partitions.push(resp_partition);
-            }
+        match responses.last_mut() {
+            Some(response) if &response.topic_id == topic_id => response.partitions.push(partition),
+            _ => responses.push(FetchResponseResponse {
+                topic_id: topic_id.clone(),
+                partitions: vec![partition],
+            }),
         }
     }
 
-    if topics.len() == 0 {
-        topics.push(Topic {
-            error_code: ErrorCode::UnknownTopicOrPartition,
-            name: Some(request.topics[0].clone()),
-            topic_id: Uuid::new(),
-            is_internal: false,
-            partitions: Vec::new(),
-            topic_authorized_operations: 0,
+    (responses, bytes)
+}
+
+fn read_partition(
+    request: &FetchRequest,
+    broker: &Broker,
+    metadata: &ClusterMetadataLog,
+    topic_id: &Uuid,
+    partition_index: i32,
+    response_max_bytes: usize,
+) -> FetchResponsePartition {
+    let unknown = |error_code| FetchResponsePartition {
+        error_code,
+        ..FetchResponsePartition::new(partition_index, vec![], 0)
+    };
+    let (name, log_dir) = {
+        let Some(name) = metadata.topic_name(topic_id) else {
+            return unknown(ErrorCode::UnknownTopicId);
+        };
+        // the topic is known by its id, so only the partition can be missing
+        let Some(partition) = metadata
+            .partitions(topic_id)
+            .iter()
+            .find(|p| p.partition_id == partition_index)
+        else {
+            return unknown(ErrorCode::UnknownTopicOrPartition);
+        };
+        if request.is_from_follower() {
+            follower_fetched(
+                broker,
+                &name,
+                partition,
+                request.replica_id,
+                request.fetch_offset(topic_id, partition_index),
+            );
+        }
+        (name, replica_log_dir(broker, partition))
+    };
+
+    let dir = log_dir.partition_dir(&name, partition_index);
+    let read = read_partition_log(
+        request,
+        broker,
+        &dir,
+        &name,
+        topic_id,
+        partition_index,
+        response_max_bytes,
+    );
+    match read {
+        Ok(partition) => partition,
+        Err(err) => {
+            println!(
+                "error: failed to read {}-{}: {}",
+                name, partition_index, err
+            );
+            unknown(ErrorCode::KafkaStorageError)
+        }
+    }
+}
+
+// the part of read_partition that reads the partition's segments in dir
+fn read_partition_log(
+    request: &FetchRequest,
+    broker: &Broker,
+    dir: &Path,
+    name: &str,
+    topic_id: &Uuid,
+    partition_index: i32,
+    response_max_bytes: usize,
+) -> io::Result<FetchResponsePartition> {
+    let segments = SegmentSet::open(dir)?;
+    let log_end_offset = segments.log_end_offset()?;
+    let high_watermark = high_watermark(broker, name, partition_index, log_end_offset);
+    let last_stable_offset = last_stable_offset(&segments, high_watermark)?;
+
+    // followers replicate what isn't committed yet
+    let max_offset = if request.is_from_follower() {
+        log_end_offset
+    } else if request.isolation_level == READ_COMMITTED {
+        last_stable_offset
+    } else {
+        high_watermark
+    };
+
+    // consumers reset their position on an out of range error, rather than
+    // waiting for offsets that aren't there anymore, or not yet
+    let fetch_offset = request.fetch_offset(topic_id, partition_index);
+    let log_start_offset = segments.log_start_offset()?;
+    if fetch_offset < log_start_offset || fetch_offset > log_end_offset {
+        return Ok(FetchResponsePartition {
+            error_code: ErrorCode::OffsetOutOfRange,
+            last_stable_offset,
+            log_start_offset,
+            ..FetchResponsePartition::new(partition_index, vec![], high_watermark)
         });
     }
 
-    DescribeTopicPartitionsResponse {
-        throttle_time_ms: 0,
-        topics,
-        next_cursor: None,
+    let max_bytes = request
+        .partition(topic_id, partition_index)
+        .map_or(usize::MAX, |p| p.partition_max_bytes.max(0) as usize)
+        .min(response_max_bytes);
+    let records = segments.read_range(fetch_offset, max_offset, max_bytes)?;
+    let mut partition = FetchResponsePartition::new(partition_index, records, high_watermark);
+    partition.last_stable_offset = last_stable_offset;
+    partition.log_start_offset = log_start_offset;
+
+    // so that read_committed consumers can drop the records of aborted transactions
+    if !request.is_from_follower() && request.isolation_level == READ_COMMITTED {
+        partition.aborted_transactions = segments
+            .aborted_transactions(fetch_offset, max_offset)?
+            .into_iter()
+            .map(|(producer_id, first_offset)| AbortedTransaction {
+                producer_id,
+                first_offset,
+            })
+            .collect();
     }
+
+    Ok(partition)
 }
 
-fn send(stream: &mut TcpStream, response: &Response) {
-    let body = match &response.body {
-        ResponseBody::Fetch(r) => r.encode(),
-        ResponseBody::ApiVersions(r) => r.encode(),
-        ResponseBody::DescribeTopicPartitions(r) => r.encode(),
-    };
+// the endpoints of the replicas clients are being redirected to
+fn node_endpoints(
+    responses: &[FetchResponseResponse],
+    endpoint: &NodeEndpoint,
+) -> Vec<NodeEndpoint> {
+    let preferred = responses
+        .iter()
+        .flat_map(|r| r.partitions.iter())
+        .any(|p| p.preferred_read_replica == endpoint.node_id);
 
-    let mut msg = Vec::new();
-    msg.extend(response.header.correlation_id.to_be_bytes());
+    if preferred {
+        vec![endpoint.clone()]
+    } else {
+        vec![]
+    }
+}
 
-    if response.header.include_tag_buffer {
-        msg.extend(encode_tag_buffer());
+// the log dir the partition record assigns this broker's replica to, for
+// JBOD brokers; without an assignment to one of its own dirs the partition
+// is looked for in all of them
+fn replica_log_dir(broker: &Broker, partition: &PartitionRecord) -> LogDir {
+    partition
+        .replicas
+        .iter()
+        .position(|replica| *replica == broker.endpoint.node_id)
+        .and_then(|i| partition.directories.get(i))
+        .and_then(|directory| broker.log_dir.for_directory(directory))
+        .unwrap_or_else(|| broker.log_dir.clone())
+}
+
+// partitions nothing has replicated yet are committed up to their log end
+fn high_watermark(broker: &Broker, topic: &str, partition: i32, log_end_offset: i64) -> i64 {
+    broker
+        .high_watermarks
+        .lock()
+        .unwrap()
+        .get(&(topic.to_string(), partition))
+        .map_or(log_end_offset, |hw| (*hw).min(log_end_offset))
+}
+
+// records are committed once every follower in the ISR has them. Without
+// any followers they're committed as soon as they're appended
+fn records_appended(
+    broker: &Broker,
+    topic: &str,
+    partition: &PartitionRecord,
+    previous_end_offset: i64,
+    log_end_offset: i64,
+) {
+    let key = (topic.to_string(), partition.partition_id);
+    let mut high_watermarks = broker.high_watermarks.lock().unwrap();
+    if has_followers(broker, partition) {
+        high_watermarks.entry(key).or_insert(previous_end_offset);
+    } else {
+        let high_watermark = high_watermarks.entry(key).or_insert(log_end_offset);
+        *high_watermark = (*high_watermark).max(log_end_offset);
     }
+}
 
-    msg.extend(body);
+// a follower fetching from an offset has all the records before it
+fn follower_fetched(
+    broker: &Broker,
+    topic: &str,
+    partition: &PartitionRecord,
+    replica_id: i32,
+    fetch_offset: i64,
+) {
+    let key = (topic.to_string(), partition.partition_id);
+    let mut follower_offsets = broker.follower_offsets.lock().unwrap();
+    let offsets = follower_offsets.entry(key.clone()).or_default();
+    offsets.insert(replica_id, fetch_offset);
+    if !has_followers(broker, partition) {
+        return;
+    }
+
+    // not committed until every follower in the ISR has fetched
+    let replicated = partition
+        .isr
+        .iter()
+        .filter(|replica| **replica != broker.endpoint.node_id)
+        .map(|replica| offsets.get(replica).copied())
+        .collect::<Option<Vec<_>>>()
+        .and_then(|offsets| offsets.into_iter().min());
+    if let Some(replicated) = replicated {
+        let mut high_watermarks = broker.high_watermarks.lock().unwrap();
+        let high_watermark = high_watermarks.entry(key).or_insert(replicated);
+        *high_watermark = (*high_watermark).max(replicated);
+    }
+}
 
-    stream.write_all(&(msg.len() as i32).encode()).unwrap();
-    stream.write_all(&msg).unwrap();
+fn has_followers(broker: &Broker, partition: &PartitionRecord) -> bool {
+    partition
+        .isr
+        .iter()
+        .any(|replica| *replica != broker.endpoint.node_id)
 }
 
-fn handle_stream(mut stream: TcpStream, metadata_log: Arc<Mutex<ClusterMetadataLog>>) {
-    loop {
-        let mut message_size = [0; 4];
-        if let Err(err) = stream.read_exact(&mut message_size) {
-            if err.kind() == ErrorKind::UnexpectedEof {
-                break;
-            } else {
-                panic!("Error reading message: {:?}", err);
+// read_committed consumers can't read past the first open transaction
+fn last_stable_offset(segments: &SegmentSet, high_watermark: i64) -> io::Result<i64> {
+    Ok(segments
+        .first_unstable_offset()?
+        .map_or(high_watermark, |offset| offset.min(high_watermark)))
+}
+
+async fn handle_list_offsets(
+    _header: &RequestHeader,
+    request: &ListOffsetsRequest,
+    broker: &Broker,
+) -> ListOffsetsResponse {
+    let metadata = broker.metadata_log.read().await;
+
+    let topics = blocking_io(|| {
+        request
+            .topics
+            .iter()
+            .map(|topic| ListOffsetsResponseTopic {
+                name: topic.name.clone(),
+                partitions: topic
+                    .partitions
+                    .iter()
+                    .map(|partition| {
+                        // a partition the topic doesn't have is as unknown as
+                        // a topic that doesn't exist
+                        match partition_record(&metadata, &topic.name, partition.partition_index) {
+                            Some(record) => {
+                                let log_dir = replica_log_dir(broker, record);
+                                list_offset(request, broker, &log_dir, &topic.name, partition)
+                            }
+                            None => ListOffsetsResponsePartition {
+                                partition_index: partition.partition_index,
+                                error_code: ErrorCode::UnknownTopicOrPartition,
+                                timestamp: -1,
+                                offset: -1,
+                                leader_epoch: -1,
+                            },
+                        }
+                    })
+                    .collect(),
+            })
+            .collect()
+    });
+
+    ListOffsetsResponse {
+        throttle_time_ms: 0,
+        topics,
+    }
+}
+
+fn list_offset(
+    request: &ListOffsetsRequest,
+    broker: &Broker,
+    log_dir: &LogDir,
+    topic: &str,
+    partition: &ListOffsetsRequestPartition,
+) -> ListOffsetsResponsePartition {
+    let dir = log_dir.partition_dir(topic, partition.partition_index);
+    match find_offset(request, broker, &dir, topic, partition) {
+        Ok(partition) => partition,
+        Err(err) => {
+            println!(
+                "error: failed to read {}-{}: {}",
+                topic, partition.partition_index, err
+            );
+            ListOffsetsResponsePartition {
+                partition_index: partition.partition_index,
+                error_code: ErrorCode::KafkaStorageError,
+                timestamp: -1,
+                offset: -1,
+                leader_epoch: -1,
             }
         }
+    }
+}
+
+// the part of list_offset that reads the partition's segments in dir
+fn find_offset(
+    request: &ListOffsetsRequest,
+    broker: &Broker,
+    dir: &Path,
+    topic: &str,
+    partition: &ListOffsetsRequestPartition,
+) -> io::Result<ListOffsetsResponsePartition> {
+    let segments = SegmentSet::open(dir)?;
+
+    // consumers are only told about offsets they could fetch
+    let high_watermark = high_watermark(
+        broker,
+        topic,
+        partition.partition_index,
+        segments.log_end_offset()?,
+    );
+    let latest = if request.isolation_level == READ_COMMITTED {
+        last_stable_offset(&segments, high_watermark)?
+    } else {
+        high_watermark
+    };
+
+    let found = match partition.timestamp {
+        LATEST_TIMESTAMP => Some((-1, latest)),
+        EARLIEST_TIMESTAMP => Some((-1, segments.log_start_offset()?)),
+        timestamp => segments
+            .batch_for_timestamp(timestamp)?
+            .map(|b| (b.max_timestamp, b.base_offset)),
+    };
+
+    let (timestamp, offset, leader_epoch) = match found {
+        Some((timestamp, offset)) => (timestamp, offset, segments.leader_epoch(offset)?),
+        None => (-1, -1, -1),
+    };
 
-        let size: usize = i32::from_be_bytes(message_size).try_into().unwrap();
-        let mut message = vec![0; size];
-        stream.read_exact(&mut message).unwrap();
+    Ok(ListOffsetsResponsePartition {
+        partition_index: partition.partition_index,
+        error_code: ErrorCode::NoError,
+        timestamp,
+        offset,
+        leader_epoch,
+    })
+}
+
+fn handle_apiversions(
+    header: &RequestHeader,
+    _body: &ApiVersionsRequest,
+    connection: &Connection,
+) -> ApiVersionsResponse {
+    let error_code = if SUPPORTED_API_VERSIONS.contains(&header.request_api_version) {
+        ErrorCode::NoError
+    } else {
+        ErrorCode::UnsupportedVersion
+    };
+
+    let mut api_keys = client_api_keys();
+    if connection.is_controller() {
+        api_keys.extend(controller_api_keys());
+    }
 
-        let request = parse_request(&message);
-        let response = handle_request(&request, &metadata_log);
-        send(&mut stream, &response);
+    ApiVersionsResponse {
+        version: api_versions_response_version(header.request_api_version),
+        error_code: error_code.code(),
+        api_keys,
+        throttle_time_ms: 0,
     }
 }
 
-fn parse_args() -> Option<String> {
-    let args: Vec<String> = env::args().collect();
+const SUPPORTED_API_VERSIONS: RangeInclusive<i16> = 0..=4;
 
-    if args.len() > 0 {
-        args.get(1).cloned()
+// unsupported versions are answered in v0, which every client can read
+fn api_versions_response_version(request_api_version: i16) -> i16 {
+    if SUPPORTED_API_VERSIONS.contains(&request_api_version) {
+        request_api_version
     } else {
-        None
+        0
     }
 }
 
-fn metadata_log() -> ClusterMetadataLog {
-    let logfile = "/tmp/kraft-combined-logs/__cluster_metadata-0/00000000000000000000.log";
-    let props_file = parse_args();
+fn client_api_keys() -> Vec<ApiKeys> {
+    vec![
+        // v3 is the first with a transactional id
+        ApiKeys {
+            api_key: ApiKey::Produce as i16,
+            min_version: 3,
+            max_version: 11,
+        },
+        ApiKeys {
+            api_key: ApiKey::Fetch as i16,
+            min_version: 0,
+            max_version: 16,
+        },
+        ApiKeys {
+            api_key: ApiKey::ListOffsets as i16,
+            min_version: 6,
+            max_version: 7,
+        },
+        // the versions with topic ids and nullable topic names
+        ApiKeys {
+            api_key: ApiKey::Metadata as i16,
+            min_version: 12,
+            max_version: 12,
+        },
+        // v0 commits went to zookeeper
+        ApiKeys {
+            api_key: ApiKey::OffsetCommit as i16,
+            min_version: 1,
+            max_version: 8,
+        },
+        // v8 fetches for several groups at once
+        ApiKeys {
+            api_key: ApiKey::OffsetFetch as i16,
+            min_version: 1,
+            max_version: 7,
+        },
+        ApiKeys {
+            api_key: ApiKey::ApiVersions as i16,
+            min_version: 0,
+            max_version: 4,
+        },
+        ApiKeys {
+            api_key: ApiKey::AddPartitionsToTxn as i16,
+            min_version: 3,
+            max_version: 3,
+        },
+        ApiKeys {
+            api_key: ApiKey::EndTxn as i16,
+            min_version: 3,
+            max_version: 3,
+        },
+        ApiKeys {
+            api_key: ApiKey::ElectLeaders as i16,
+            min_version: 2,
+            max_version: 2,
+        },
+        ApiKeys {
+            api_key: ApiKey::OffsetDelete as i16,
+            min_version: 0,
+            max_version: 0,
+        },
+        ApiKeys {
+            api_key: ApiKey::DescribeCluster as i16,
+            min_version: 0,
+            max_version: 1,
+        },
+        ApiKeys {
+            api_key: ApiKey::DescribeTopicPartitions as i16,
+            min_version: 0,
+            max_version: 0,
+        },
+    ]
+}
+
+// the APIs only other nodes use, on the controller listener. Vote,
+// BeginQuorumEpoch, EndQuorumEpoch, DescribeQuorum and BrokerRegistration
+// have no handlers yet, so they aren't advertised
+fn controller_api_keys() -> Vec<ApiKeys> {
+    vec![ApiKeys {
+        api_key: ApiKey::BrokerHeartbeat as i16,
+        min_version: 0,
+        max_version: 1,
+    }]
+}
+
+// the brokers registered in the metadata log are kept unfenced for as long
+// as they heartbeat, and are caught up once they've applied all of it
+async fn handle_broker_heartbeat(
+    _header: &RequestHeader,
+    request: &BrokerHeartbeatRequest,
+    broker: &Broker,
+) -> BrokerHeartbeatResponse {
+    let metadata = broker.metadata_log.read().await;
+    if !metadata.registered_brokers().contains(&request.broker_id) {
+        return BrokerHeartbeatResponse {
+            throttle_time_ms: 0,
+            error_code: ErrorCode::BrokerIdNotRegistered,
+            is_caught_up: false,
+            is_fenced: true,
+            should_shut_down: false,
+        };
+    }
 
-    match props_file {
-        Some(_) => ClusterMetadataLog::new(logfile),
-        None => panic!("no properties file argument"),
+    BrokerHeartbeatResponse {
+        throttle_time_ms: 0,
+        error_code: ErrorCode::NoError,
+        is_caught_up: request.current_metadata_offset + 1 >= metadata.end_offset(),
+        is_fenced: request.want_fence,
+        should_shut_down: request.want_shut_down,
     }
 }
 
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:9092").unwrap();
-    let metadata_log = Arc::new(Mutex::new(metadata_log()));
+fn handle_add_partitions_to_txn(
+    _header: &RequestHeader,
+    request: &AddPartitionsToTxnRequest,
+    transactions: &Mutex<TransactionCoordinator>,
+) -> AddPartitionsToTxnResponse {
+    let partitions = request.topics.iter().flat_map(|topic| {
+        topic
+            .partitions
+            .iter()
+            .map(|partition| (topic.name.clone(), *partition))
+    });
 
-    for stream in listener.incoming() {
-        metadata_log
-            .as_ref()
-            .lock()
-            .unwrap()
-            .load()
-            .expect("failed to read cluster metadata");
+    let error_code = match transactions.lock().unwrap().add_partitions(
+        &request.transactional_id,
+        request.producer_id,
+        request.producer_epoch,
+        partitions,
+    ) {
+        Ok(()) => ErrorCode::NoError,
+        Err(error_code) => error_code,
+    };
 
-        match stream {
-            Ok(stream) => {
-                let log = Arc::clone(&metadata_log);
-                thread::spawn(|| handle_stream(stream, log));
-            }
-            Err(e) => {
-                println!("error: {}", e);
-            }
+    let results = request
+        .topics
+        .iter()
+        .map(|topic| AddPartitionsToTxnTopicResult {
+            name: topic.name.clone(),
+            results: topic
+                .partitions
+                .iter()
+                .map(|partition| AddPartitionsToTxnPartitionResult {
+                    partition_index: *partition,
+                    partition_error_code: error_code,
+                })
+                .collect(),
+        })
+        .collect();
+
+    AddPartitionsToTxnResponse {
+        throttle_time_ms: 0,
+        results,
+    }
+}
+
+async fn handle_produce(
+    header: &RequestHeader,
+    request: &ProduceRequest<'_>,
+    broker: &Broker,
+) -> ProduceResponse {
+    let metadata = broker.metadata_log.read().await;
+
+    let responses = blocking_io(|| {
+        request
+            .topic_data
+            .iter()
+            .map(|topic| ProduceTopicResponse {
+                name: topic.name.clone(),
+                partition_responses: topic
+                    .partition_data
+                    .iter()
+                    .map(|partition| {
+                        produce_partition(request, &topic.name, partition, broker, &metadata)
+                            .unwrap_or_else(|response| response)
+                    })
+                    .collect(),
+            })
+            .collect()
+    });
+
+    ProduceResponse {
+        version: header.request_api_version,
+        responses,
+        throttle_time_ms: 0,
+    }
+}
+
+// appends the partition's batches one at a time, so that each is given the
+// next offsets; any error fails the partition before anything is written
+fn produce_partition(
+    request: &ProduceRequest,
+    topic: &str,
+    partition: &ProducePartitionData,
+    broker: &Broker,
+    metadata: &ClusterMetadataLog,
+) -> Result<ProduceResponsePartition, ProduceResponsePartition> {
+    let error = |error_code| ProduceResponsePartition::error(partition.index, error_code);
+
+    let Some(record) = partition_record(metadata, topic, partition.index) else {
+        return Err(error(ErrorCode::UnknownTopicOrPartition));
+    };
+    let config = LogConfig::for_topic(metadata, topic);
+    let log_dir = replica_log_dir(broker, record);
+
+    let records = partition.records.unwrap_or_default();
+    if let Err(invalid) = validate_records(records, config.max_message_bytes as usize) {
+        return Err(ProduceResponsePartition {
+            record_errors: invalid.record_errors,
+            error_message: Some(invalid.message),
+            ..error(invalid.error_code)
+        });
+    }
+
+    // a transactional producer may only write to the partitions of its transaction
+    let batches = batch_ranges(records);
+    if let (Some(transactional_id), Some(first)) = (&request.transactional_id, batches.first()) {
+        let header = &records[first.clone()];
+        let producer_id = i64::from_be_bytes(header[43..51].try_into().unwrap());
+        let producer_epoch = i16::from_be_bytes(header[51..53].try_into().unwrap());
+        broker
+            .transactions
+            .lock()
+            .unwrap()
+            .validate_produce(
+                transactional_id,
+                producer_id,
+                producer_epoch,
+                topic,
+                partition.index,
+            )
+            .map_err(error)?;
+    }
+
+    // a failed write loses the partition, but not the connection
+    let storage_error = |err: std::io::Error| {
+        println!(
+            "error: failed to append to {}-{}: {}",
+            topic, partition.index, err
+        );
+        error(ErrorCode::KafkaStorageError)
+    };
+    let dir = log_dir.partition_dir(topic, partition.index);
+    let mut base_offset = -1;
+    let mut log_end_offset = -1;
+    for range in batches {
+        let mut batch = records[range].to_vec();
+        // stored as part of the transaction, whatever the producer set, so
+        // that read_committed consumers can tell which records to skip
+        if request.transactional_id.is_some() {
+            mark_transactional(&mut batch);
+        }
+        let offset = broker
+            .segments
+            .append(&dir, &config, record.leader_epoch, &mut batch)
+            .map_err(storage_error)?;
+        if base_offset < 0 {
+            base_offset = offset;
+        }
+        let last_offset_delta = i32::from_be_bytes(batch[23..27].try_into().unwrap());
+        log_end_offset = offset + last_offset_delta as i64 + 1;
+    }
+    if base_offset >= 0 {
+        records_appended(broker, topic, record, base_offset, log_end_offset);
+    }
+    let log_start_offset = read_log_start_offset(&dir).map_err(storage_error)?;
+
+    Ok(ProduceResponsePartition {
+        base_offset,
+        log_start_offset,
+        ..error(ErrorCode::NoError)
+    })
+}
+
+// the partition of a topic known by name. Names that kafka wouldn't allow
+// are never found, as the partition's log dir is named after them
+fn partition_record<'a>(
+    metadata: &'a ClusterMetadataLog,
+    topic: &str,
+    partition: i32,
+) -> Option<&'a PartitionRecord> {
+    if !is_legal_topic_name(topic) {
+        return None;
+    }
+    let topic_id = &metadata.topic_by_name(topic)?.topic_uuid;
+    metadata
+        .partitions(topic_id)
+        .iter()
+        .find(|p| p.partition_id == partition)
+}
+
+// where each batch of a validated record set starts and ends
+fn batch_ranges(records: &[u8]) -> Vec<std::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut position = 0;
+    while position + 12 <= records.len() {
+        let length = i32::from_be_bytes(records[position + 8..position + 12].try_into().unwrap());
+        let end = position + 12 + length as usize;
+        ranges.push(position..end);
+        position = end;
+    }
+    ranges
+}
+
+async fn handle_end_txn(
+    _header: &RequestHeader,
+    request: &EndTxnRequest,
+    broker: &Broker,
+) -> EndTxnResponse {
+    let partitions = match broker.transactions.lock().unwrap().end(
+        &request.transactional_id,
+        request.producer_id,
+        request.producer_epoch,
+    ) {
+        Ok(partitions) => partitions,
+        Err(error_code) => {
+            return EndTxnResponse {
+                throttle_time_ms: 0,
+                error_code,
+            }
+        }
+    };
+
+    let metadata = broker.metadata_log.read().await;
+    let error_code = blocking_io(|| write_markers(request, broker, &metadata, partitions));
+
+    EndTxnResponse {
+        throttle_time_ms: 0,
+        error_code,
+    }
+}
+
+// every partition that can be is marked, the first that can't fails the request
+fn write_markers(
+    request: &EndTxnRequest,
+    broker: &Broker,
+    metadata: &ClusterMetadataLog,
+    partitions: BTreeSet<(String, i32)>,
+) -> ErrorCode {
+    let mut error_code = ErrorCode::NoError;
+    for (topic, partition) in partitions {
+        let Some(record) = partition_record(metadata, &topic, partition) else {
+            if error_code == ErrorCode::NoError {
+                error_code = ErrorCode::UnknownTopicOrPartition;
+            }
+            continue;
+        };
+
+        let mut marker = control_batch(
+            request.producer_id,
+            request.producer_epoch,
+            request.committed,
+        );
+        let config = LogConfig::for_topic(metadata, &topic);
+        let dir = replica_log_dir(broker, record).partition_dir(&topic, partition);
+        match broker
+            .segments
+            .append(&dir, &config, record.leader_epoch, &mut marker)
+        {
+            Ok(offset) => records_appended(broker, &topic, record, offset, offset + 1),
+            Err(err) => {
+                println!(
+                    "error: failed to write transaction marker to {}-{}: {}",
+                    topic, partition, err
+                );
+                if error_code == ErrorCode::NoError {
+                    error_code = ErrorCode::KafkaStorageError;
+                }
+            }
+        }
+    }
+    error_code
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+// the broker's offset.metadata.max.bytes default
+const OFFSET_METADATA_MAX_BYTES: usize = 4096;
+
+// there is no group coordinator to check the generation and member against,
+// so any member's commit is taken
+async fn handle_offset_commit(
+    header: &RequestHeader,
+    request: &OffsetCommitRequest,
+    broker: &Broker,
+) -> OffsetCommitResponse {
+    let now_ms = now_ms();
+    let expire_timestamp = expire_timestamp(now_ms, request.retention_time_ms);
+
+    let metadata = broker.metadata_log.read().await;
+    let mut offsets = broker.offsets.lock().unwrap();
+    let topics = request
+        .topics
+        .iter()
+        .map(|topic| OffsetCommitResponseTopic {
+            name: topic.name.clone(),
+            partitions: topic
+                .partitions
+                .iter()
+                .map(|partition| {
+                    let index = partition.partition_index;
+                    let committed_metadata = &partition.committed_metadata;
+                    let error_code = if partition_record(&metadata, &topic.name, index).is_none() {
+                        ErrorCode::UnknownTopicOrPartition
+                    } else if committed_metadata
+                        .as_ref()
+                        .is_some_and(|m| m.len() > OFFSET_METADATA_MAX_BYTES)
+                    {
+                        ErrorCode::OffsetMetadataTooLarge
+                    } else {
+                        offsets.commit(
+                            &request.group_id,
+                            &topic.name,
+                            index,
+                            CommittedOffset {
+                                offset: partition.committed_offset,
+                                leader_epoch: partition.committed_leader_epoch,
+                                metadata: committed_metadata.clone(),
+                                expire_timestamp: Some(expire_timestamp),
+                            },
+                        );
+                        ErrorCode::NoError
+                    };
+                    OffsetCommitResponsePartition {
+                        partition_index: index,
+                        error_code,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    OffsetCommitResponse {
+        version: header.request_api_version,
+        throttle_time_ms: 0,
+        topics,
+    }
+}
+
+// partitions the group never committed to, or whose commit expired, have
+// offset -1
+fn handle_offset_fetch(
+    header: &RequestHeader,
+    request: &OffsetFetchRequest,
+    offsets: &Mutex<OffsetStore>,
+) -> OffsetFetchResponse {
+    let now_ms = now_ms();
+    let mut offsets = offsets.lock().unwrap();
+    offsets.expire(now_ms);
+
+    let partition = |partition_index, committed: CommittedOffset| OffsetFetchResponsePartition {
+        partition_index,
+        committed_offset: committed.offset,
+        committed_leader_epoch: committed.leader_epoch,
+        metadata: committed.metadata,
+        error_code: ErrorCode::NoError,
+    };
+    let topics = match &request.topics {
+        Some(topics) => topics
+            .iter()
+            .map(|topic| OffsetFetchResponseTopic {
+                name: topic.name.clone(),
+                partitions: topic
+                    .partition_indexes
+                    .iter()
+                    .map(|index| {
+                        partition(
+                            *index,
+                            offsets.fetch(&request.group_id, &topic.name, *index),
+                        )
+                    })
+                    .collect(),
+            })
+            .collect(),
+        None => {
+            let mut topics: Vec<OffsetFetchResponseTopic> = Vec::new();
+            for (name, index, committed) in offsets.fetch_all(&request.group_id) {
+                match topics.last_mut() {
+                    Some(topic) if topic.name == name => {
+                        topic.partitions.push(partition(index, committed))
+                    }
+                    _ => topics.push(OffsetFetchResponseTopic {
+                        name,
+                        partitions: vec![partition(index, committed)],
+                    }),
+                }
+            }
+            topics
+        }
+    };
+
+    OffsetFetchResponse {
+        version: header.request_api_version,
+        throttle_time_ms: 0,
+        topics,
+        error_code: ErrorCode::NoError,
+    }
+}
+
+fn handle_offset_delete(
+    _header: &RequestHeader,
+    request: &OffsetDeleteRequest,
+    offsets: &Mutex<OffsetStore>,
+) -> OffsetDeleteResponse {
+    let mut offsets = offsets.lock().unwrap();
+
+    if !offsets.has_group(&request.group_id) {
+        return OffsetDeleteResponse {
+            error_code: ErrorCode::GroupIdNotFound,
+            throttle_time_ms: 0,
+            topics: Vec::new(),
+        };
+    }
+
+    let topics = request
+        .topics
+        .iter()
+        .map(|topic| OffsetDeleteResponseTopic {
+            name: topic.name.clone(),
+            partitions: topic
+                .partitions
+                .iter()
+                .map(|partition| {
+                    offsets.delete(&request.group_id, &topic.name, *partition);
+                    OffsetDeleteResponsePartition {
+                        partition_index: *partition,
+                        error_code: ErrorCode::NoError,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    OffsetDeleteResponse {
+        error_code: ErrorCode::NoError,
+        throttle_time_ms: 0,
+        topics,
+    }
+}
+
+async fn handle_metadata(
+    _header: &RequestHeader,
+    request: &MetadataRequest,
+    broker: &Broker,
+) -> MetadataResponse {
+    let metadata = broker.metadata_log.read().await;
+
+    let registered = metadata.registered_brokers();
+    let is_offline = |replica: &i32| !registered.is_empty() && !registered.contains(replica);
+    let describe = |topic: &TopicRecord| {
+        let mut partitions: Vec<MetadataResponsePartition> = metadata
+            .partitions(&topic.topic_uuid)
+            .iter()
+            .map(|partition| MetadataResponsePartition {
+                error_code: ErrorCode::NoError,
+                partition_index: partition.partition_id,
+                leader_id: partition.leader,
+                leader_epoch: partition.leader_epoch,
+                replica_nodes: partition.replicas.clone(),
+                isr_nodes: partition.isr.clone(),
+                offline_replicas: partition
+                    .replicas
+                    .iter()
+                    .copied()
+                    .filter(is_offline)
+                    .collect(),
+            })
+            .collect();
+        // in the order they were created in the log, clients expect them by index
+        partitions.sort_by_key(|p| p.partition_index);
+
+        MetadataResponseTopic {
+            error_code: ErrorCode::NoError,
+            name: Some(topic.topic_name.clone()),
+            topic_id: topic.topic_uuid.clone(),
+            is_internal: false,
+            partitions,
+            topic_authorized_operations: i32::MIN,
+        }
+    };
+    let unknown = |requested: &MetadataRequestTopic, error_code| MetadataResponseTopic {
+        error_code,
+        name: requested.name.clone(),
+        topic_id: requested.topic_id.clone(),
+        is_internal: false,
+        partitions: Vec::new(),
+        topic_authorized_operations: i32::MIN,
+    };
+
+    let topics = match &request.topics {
+        None => metadata.topics().iter().map(describe).collect(),
+        Some(requested) => requested
+            .iter()
+            .map(|topic| match &topic.name {
+                _ if topic.topic_id != Uuid::new() => match metadata.topic_by_id(&topic.topic_id) {
+                    Some(record) => describe(record),
+                    None => unknown(topic, ErrorCode::UnknownTopicId),
+                },
+                Some(name) => match metadata.topic_by_name(name) {
+                    Some(record) => describe(record),
+                    None => unknown(topic, ErrorCode::UnknownTopicOrPartition),
+                },
+                None => unknown(topic, ErrorCode::InvalidTopicException),
+            })
+            .collect(),
+    };
+
+    // this node is the only broker, and the controller as well
+    let mut response = MetadataResponse {
+        throttle_time_ms: 0,
+        brokers: vec![broker.endpoint.clone()],
+        cluster_id: None,
+        controller_id: broker.endpoint.node_id,
+        topics,
+    };
+
+    // there's no cursor to page through, so rather than an oversized frame the
+    // topics are answered with an error and without their partitions
+    if response.encode().len() > broker.socket_request_max_bytes {
+        for topic in &mut response.topics {
+            topic.error_code = ErrorCode::InvalidRequest;
+            topic.partitions.clear();
+        }
+    }
+    response
+}
+
+// like Metadata, this node is the whole cluster. Clients can only learn of
+// the broker listener, there's no controller one to describe
+fn handle_describe_cluster(
+    header: &RequestHeader,
+    request: &DescribeClusterRequest,
+    broker: &Broker,
+) -> DescribeClusterResponse {
+    let (error_code, error_message, brokers) = match request.endpoint_type {
+        BROKER_ENDPOINT_TYPE => (ErrorCode::NoError, None, vec![broker.endpoint.clone()]),
+        endpoint_type => (
+            ErrorCode::UnsupportedEndpointType,
+            Some(format!("unsupported endpoint type {}", endpoint_type)),
+            Vec::new(),
+        ),
+    };
+
+    DescribeClusterResponse {
+        version: header.request_api_version,
+        throttle_time_ms: 0,
+        error_code,
+        error_message,
+        endpoint_type: request.endpoint_type,
+        cluster_id: String::new(),
+        controller_id: broker.endpoint.node_id,
+        brokers,
+        cluster_authorized_operations: i32::MIN,
+    }
+}
+
+// this broker leads every partition it has, so there is never an election
+// to run; partitions it doesn't lead are left alone
+async fn handle_elect_leaders(
+    _header: &RequestHeader,
+    request: &ElectLeadersRequest,
+    broker: &Broker,
+) -> ElectLeadersResponse {
+    let metadata = broker.metadata_log.read().await;
+
+    let requested: Vec<(String, Vec<i32>)> = match &request.topic_partitions {
+        Some(topics) => topics
+            .iter()
+            .map(|topic| (topic.topic.clone(), topic.partitions.clone()))
+            .collect(),
+        None => metadata
+            .topics()
+            .iter()
+            .map(|topic| {
+                let partitions = metadata.partitions(&topic.topic_uuid);
+                let ids = partitions.iter().map(|p| p.partition_id).collect();
+                (topic.topic_name.clone(), ids)
+            })
+            .collect(),
+    };
+
+    let replica_election_results = requested
+        .into_iter()
+        .map(|(topic, partitions)| {
+            let records = metadata
+                .topic_by_name(&topic)
+                .map_or(&[][..], |t| metadata.partitions(&t.topic_uuid));
+            let partition_result = partitions
+                .into_iter()
+                .map(|partition_id| {
+                    let error_code = match records.iter().find(|p| p.partition_id == partition_id) {
+                        None => ErrorCode::UnknownTopicOrPartition,
+                        Some(p) if p.leader == broker.endpoint.node_id => {
+                            ErrorCode::ElectionNotNeeded
+                        }
+                        Some(_) => ErrorCode::NoError,
+                    };
+                    ElectionPartitionResult {
+                        partition_id,
+                        error_code,
+                        error_message: None,
+                    }
+                })
+                .collect();
+            ReplicaElectionResult {
+                topic,
+                partition_result,
+            }
+        })
+        .collect();
+
+    ElectLeadersResponse {
+        throttle_time_ms: 0,
+        error_code: ErrorCode::NoError,
+        replica_election_results,
+    }
+}
+
+async fn handle_describe_topic_partitions(
+    _: &RequestHeader,
+    request: &DescribeTopicPartitionsRequest,
+    metadata_log: &RwLock<ClusterMetadataLog>,
+    partition_size_limit: i32,
+) -> DescribeTopicPartitionsResponse {
+    let metadata = metadata_log.read().await;
+
+    // clients leaving the limit unset get the broker's, nobody gets more
+    let limit = match request.response_partition_limit {
+        limit if limit <= 0 => partition_size_limit,
+        limit => limit.min(partition_size_limit),
+    } as usize;
+
+    // each topic is described once, in the order it was first requested
+    let mut requested: Vec<&Option<String>> = Vec::new();
+    for name in &request.topics {
+        if !requested.contains(&name) {
+            requested.push(name);
+        }
+    }
+
+    // logs without any broker registration, like hand written ones, say
+    // nothing about which replicas are offline
+    let registered = metadata.registered_brokers();
+    let is_offline = |replica: &i32| !registered.is_empty() && !registered.contains(replica);
+
+    let mut topics = Vec::new();
+    for name in requested.iter().copied().flatten() {
+        // topics asked for by name are unknown as a topic or partition, not by id
+        let Some(topic) = metadata.topic_by_name(name) else {
+            topics.push(Topic {
+                error_code: ErrorCode::UnknownTopicOrPartition,
+                name: Some(name.clone()),
+                topic_id: Uuid::new(),
+                is_internal: false,
+                partitions: Vec::new(),
+                topic_authorized_operations: 0,
+            });
+            continue;
+        };
+
+        let mut partitions: Vec<Partition> = metadata
+            .partitions(&topic.topic_uuid)
+            .iter()
+            .map(|partition| Partition {
+                error_code: ErrorCode::NoError,
+                partition_index: partition.partition_id,
+                leader_id: partition.leader,
+                leader_epoch: partition.leader_epoch,
+                replica_nodes: partition.replicas.clone(),
+                isr_nodes: partition.isr.clone(),
+                eligible_leader_replicas: Vec::new(),
+                last_known_elr: Vec::new(),
+                offline_replicas: partition
+                    .replicas
+                    .iter()
+                    .copied()
+                    .filter(is_offline)
+                    .collect(),
+            })
+            .collect();
+        // in the order they were created in the log, clients expect them by index
+        partitions.sort_by_key(|p| p.partition_index);
+
+        topics.push(Topic {
+            error_code: ErrorCode::NoError,
+            name: Some(topic.topic_name.clone()),
+            topic_id: topic.topic_uuid.clone(),
+            is_internal: false,
+            partitions,
+            topic_authorized_operations: 0,
+        });
+    }
+
+    // a cursor from an earlier response skips what that one described
+    if let Some(cursor) = &request.cursor {
+        let name = Some(&cursor.topic_name);
+        if let Some(start) = topics.iter().position(|t| t.name.as_ref() == name) {
+            topics.drain(..start);
+            topics[0]
+                .partitions
+                .retain(|p| p.partition_index >= cursor.partition_index);
+        }
+    }
+
+    // past the limit, the cursor is where the next request picks up from
+    let mut next_cursor = None;
+    let mut remaining = limit;
+    for i in 0..topics.len() {
+        let topic = &mut topics[i];
+        if topic.partitions.len() > remaining {
+            next_cursor = Some(KCursor {
+                topic_name: topic.name.clone().unwrap_or_default(),
+                partition_index: topic.partitions[remaining].partition_index,
+            });
+            topic.partitions.truncate(remaining);
+            topics.truncate(i + 1);
+            break;
+        }
+        remaining -= topic.partitions.len();
+    }
+
+    // a null name can't refer to any topic
+    for _ in requested.iter().filter(|name| name.is_none()) {
+        topics.push(Topic {
+            error_code: ErrorCode::InvalidTopicException,
+            name: None,
+            topic_id: Uuid::new(),
+            is_internal: false,
+            partitions: Vec::new(),
+            topic_authorized_operations: 0,
+        });
+    }
+
+    DescribeTopicPartitionsResponse {
+        throttle_time_ms: 0,
+        topics,
+        next_cursor,
+    }
+}
+
+async fn send(
+    stream: &mut (impl AsyncWrite + Unpin),
+    request: &RequestHeader,
+    response: &Response,
+) -> std::io::Result<()> {
+    // a handler answering with another request's response would desync the client
+    debug_assert_eq!(
+        request.correlation_id, response.header.correlation_id,
+        "response does not match the request's correlation id"
+    );
+
+    let body = match &response.body {
+        ResponseBody::Fetch(r) => r.encode(),
+        ResponseBody::Produce(r) => r.encode(),
+        ResponseBody::ListOffsets(r) => r.encode(),
+        ResponseBody::Metadata(r) => r.encode(),
+        ResponseBody::OffsetCommit(r) => r.encode(),
+        ResponseBody::OffsetFetch(r) => r.encode(),
+        ResponseBody::ApiVersions(r) => r.encode(),
+        ResponseBody::AddPartitionsToTxn(r) => r.encode(),
+        ResponseBody::EndTxn(r) => r.encode(),
+        ResponseBody::ElectLeaders(r) => r.encode(),
+        ResponseBody::OffsetDelete(r) => r.encode(),
+        ResponseBody::DescribeCluster(r) => r.encode(),
+        ResponseBody::BrokerHeartbeat(r) => r.encode(),
+        ResponseBody::DescribeTopicPartitions(r) => r.encode(),
+    };
+
+    let mut msg = Vec::new();
+    msg.extend(response.header.correlation_id.to_be_bytes());
+
+    if response.header.include_tag_buffer {
+        msg.extend(encode_tag_buffer());
+    }
+
+    msg.extend(body);
+
+    stream.write_all(&(msg.len() as i32).encode()).await?;
+    stream.write_all(&msg).await
+}
+
+// the client went away, which isn't an error on our side
+fn is_disconnect(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+    )
+}
+
+// like read_exact, except that each read gives up after idle_timeout, so a
+// frame trickling in over many segments keeps the connection alive as long
+// as bytes keep arriving
+async fn read_exact_idle(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut [u8],
+    idle_timeout: Duration,
+) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match time::timeout(idle_timeout, stream.read(&mut buf[filled..])).await {
+            Ok(Ok(0)) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(Ok(n)) => filled += n,
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err(ErrorKind::TimedOut.into()),
+        }
+    }
+    Ok(())
+}
+
+// None once the client closed the connection between frames
+async fn read_frame(
+    stream: &mut (impl AsyncRead + Unpin),
+    idle_timeout: Duration,
+    max_size: usize,
+) -> std::io::Result<Option<Vec<u8>>> {
+    let mut message_size = [0; 4];
+    if let Err(err) = read_exact_idle(stream, &mut message_size, idle_timeout).await {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+
+    // checked before allocating, the size is whatever the client sent
+    let size = i32::from_be_bytes(message_size);
+    let size = match usize::try_from(size) {
+        Ok(size) if size <= max_size => size,
+        _ => {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("request size {} is not within 0 to {}", size, max_size),
+            ))
+        }
+    };
+    let mut message = vec![0; size];
+    read_exact_idle(stream, &mut message, idle_timeout).await?;
+    Ok(Some(message))
+}
+
+// the connection a task serves and the api key and correlation id of the
+// request it's handling, for panics to be reported with
+struct HandlerContext {
+    name: String,
+    request: Cell<Option<(i16, i32)>>,
+}
+
+tokio::task_local! {
+    static HANDLER: HandlerContext;
+}
+
+fn set_current_request(header: &RequestHeader) {
+    let request = Some((header.request_api_key, header.correlation_id));
+    let _ = HANDLER.try_with(|handler| handler.request.set(request));
+}
+
+// a connection's handler, named after its peer for the panics it may report
+fn handler_task<F: Future<Output = ()>>(peer: SocketAddr, handler: F) -> impl Future<Output = ()> {
+    let context = HandlerContext {
+        name: format!("{}-{}", HANDLER_TASK_PREFIX, peer),
+        request: Cell::new(None),
+    };
+    HANDLER.scope(context, handler)
+}
+
+// logs which connection and request a panic happened on, before the
+// default report with the message and backtrace
+fn install_panic_hook(log: impl Fn(String) + Send + Sync + 'static) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => info
+                .payload()
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_default(),
+        };
+        // tasks move between worker threads, outside of one the thread is named
+        let (name, request) = HANDLER
+            .try_with(|handler| (Some(handler.name.clone()), handler.request.get()))
+            .unwrap_or((thread::current().name().map(str::to_string), None));
+        log(panic_report(name.as_deref(), request, &message));
+        previous(info);
+    }));
+}
+
+fn panic_report(name: Option<&str>, request: Option<(i16, i32)>, message: &str) -> String {
+    let name = name.unwrap_or("<unnamed>");
+    match request {
+        Some((api_key, correlation_id)) => format!(
+            "error: {} panicked on api key {} correlation id {}: {}",
+            name, api_key, correlation_id, message
+        ),
+        None => format!("error: {} panicked: {}", name, message),
+    }
+}
+
+async fn handle_stream(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    broker: Arc<Broker>,
+    connection: Connection,
+    idle_timeout: Duration,
+    shutdown: Shutdown,
+) {
+    let max_size = broker.socket_request_max_bytes;
+    loop {
+        // a connection going idle, or the broker shutting down, closes it
+        let message = tokio::select! {
+            _ = shutdown.triggered() => break,
+            message = read_frame(&mut stream, idle_timeout, max_size) => message,
+        };
+        let message = match message {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(err) if err.kind() == ErrorKind::InvalidData => {
+                println!("error: closing connection: {}", err);
+                break;
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => break,
+            Err(err) if is_disconnect(&err) => break,
+            Err(err) => {
+                println!("error: closing connection: failed to read request: {}", err);
+                break;
+            }
+        };
+
+        let (answer, throttle) = match parse_request(&message, &connection) {
+            Ok(request) => {
+                set_current_request(&request.header);
+                let client = request.header.client_id.as_deref().unwrap_or_default();
+                let throttle = broker.quotas.lock().unwrap().record(client, message.len());
+
+                if matches!(
+                    request.body,
+                    RequestBody::Unimplemented | RequestBody::Unknown
+                ) && broker.unimplemented_apis == UnimplementedApis::Close
+                {
+                    println!(
+                        "error: closing connection to {} on unsupported API key: {}",
+                        quota_key(client),
+                        request.header.request_api_key
+                    );
+                    break;
+                }
+
+                // nor does a long polling fetch, or any other slow request, hold
+                // up the shutdown
+                let response = tokio::select! {
+                    _ = shutdown.triggered() => break,
+                    response = handle_request(&request, &broker, &connection) => response,
+                };
+                // producers with acks=0 don't wait for a response
+                if matches!(&request.body, RequestBody::Produce(produce) if produce.acks == 0) {
+                    (None, throttle)
+                } else {
+                    (Some((request.header, response)), throttle)
+                }
+            }
+            Err(ParseError::Body(header, err)) => {
+                set_current_request(&header);
+                let client = header.client_id.as_deref().unwrap_or_default();
+                let throttle = broker.quotas.lock().unwrap().record(client, message.len());
+
+                println!(
+                    "error: failed to parse request body from {}: {}",
+                    quota_key(client),
+                    err
+                );
+                match error_response(&header, ErrorCode::InvalidRequest) {
+                    Some(response) => (Some((header, response)), throttle),
+                    None => break,
+                }
+            }
+            Err(ParseError::Header(err)) => {
+                println!("error: failed to parse request header: {}", err);
+                break;
+            }
+        };
+
+        if let Some((header, mut response)) = answer {
+            response.body.throttle(throttle);
+            if let Err(err) = send(&mut stream, &header, &response).await {
+                if !is_disconnect(&err) {
+                    println!("error: failed to send response: {}", err);
+                }
+                break;
+            }
+        }
+
+        // like kafka, the response goes out right away and the client's next
+        // request waits out the throttle
+        if !throttle.is_zero() {
+            tokio::select! {
+                _ = shutdown.triggered() => break,
+                _ = time::sleep(throttle) => {}
+            }
+        }
+    }
+}
+
+fn parse_args() -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+
+    if !args.is_empty() {
+        args.get(1).cloned()
+    } else {
+        None
+    }
+}
+
+// the broker can't start without its properties file
+fn server_properties() -> HashMap<String, String> {
+    let Some(props_file) = parse_args() else {
+        println!("error: no properties file argument");
+        process::exit(1);
+    };
+
+    properties::read_properties(&props_file).unwrap_or_else(|err| {
+        println!("error: failed to read {}: {}", props_file, err);
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let properties = server_properties();
+    let stack_size = properties::handler_thread_stack_size(&properties, HANDLER_THREAD_STACK_SIZE);
+    handler_runtime(stack_size)
+        .expect("failed to start the runtime")
+        .block_on(run(properties));
+}
+
+// the runtime connections are handled on, with stack_size bytes of stack for
+// each of its worker threads
+fn handler_runtime(stack_size: usize) -> io::Result<runtime::Runtime> {
+    runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_stack_size(stack_size)
+        .build()
+}
+
+async fn run(properties: HashMap<String, String>) {
+    // listeners without a host bind to every interface
+    let (host, port) = properties::listener_address(&properties, CLIENT_LISTENER)
+        .unwrap_or((HOST.to_string(), PORT));
+    let bind_host = if host.is_empty() { "0.0.0.0" } else { &host };
+    let backlog = properties::socket_listen_backlog_size(&properties, SOCKET_LISTEN_BACKLOG_SIZE);
+    let listener = bind(&format!("{}:{}", bind_host, port), backlog)
+        .await
+        .expect("failed to bind listener");
+    let controller_listener = match properties::listener_address(&properties, CONTROLLER_LISTENER) {
+        Some((host, port)) => {
+            let host = if host.is_empty() { "0.0.0.0" } else { &host };
+            let listener = bind(&format!("{}:{}", host, port), backlog)
+                .await
+                .expect("failed to bind controller listener");
+            Some(listener)
+        }
+        None => None,
+    };
+    // clients can't connect to a wildcard address, they're given HOST instead
+    let (advertised_host, advertised_port) =
+        properties::advertised_address(&properties, CLIENT_LISTENER).unwrap_or((host, port));
+    let advertised_host = match advertised_host.as_str() {
+        "" | "0.0.0.0" | "::" | "[::]" => HOST.to_string(),
+        _ => advertised_host,
+    };
+    // the log dirs set in the properties file, LOG_DIR when it sets none
+    let log_dir = properties::log_dir(&properties, LOG_DIR);
+    let mut metadata_log = ClusterMetadataLog::new(log_dir.clone());
+    // a fresh data directory is formatted the way kafka-storage would
+    match metadata_log.format(MetadataVersion::default()) {
+        Ok(()) => println!("formatted a new metadata log in {:?}", log_dir),
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => panic!("failed to format the metadata log: {}", err),
+    }
+
+    let broker = Arc::new(Broker {
+        endpoint: NodeEndpoint {
+            node_id: NODE_ID,
+            host: advertised_host,
+            port: advertised_port,
+            rack: properties::broker_rack(&properties),
+        },
+        metadata_log: RwLock::new(metadata_log),
+        log_dir,
+        offsets: Mutex::new(OffsetStore::new()),
+        transactions: Mutex::new(TransactionCoordinator::new()),
+        segments: SegmentWriter::new(),
+        high_watermarks: Mutex::new(HashMap::new()),
+        follower_offsets: Mutex::new(HashMap::new()),
+        unimplemented_apis: unimplemented_apis(&properties, UNIMPLEMENTED_APIS),
+        fetch_sessions: Mutex::new(FetchSessionCache::new()),
+        quotas: Mutex::new(ClientQuotas::with_byte_rate(properties::client_byte_rate(
+            &properties,
+        ))),
+        partition_size_limit: MAX_REQUEST_PARTITION_SIZE_LIMIT,
+        socket_request_max_bytes: properties::socket_request_max_bytes(
+            &properties,
+            SOCKET_REQUEST_MAX_BYTES,
+        ),
+    });
+
+    install_panic_hook(|report| println!("{}", report));
+    let mut server = Server::new(listener, broker);
+    if let Some(controller_listener) = controller_listener {
+        server = server.with_controller_listener(controller_listener);
+    }
+    let signals = shutdown_signals().expect("failed to install signal handlers");
+    server.serve_until(signals).await;
+}
+
+// listens on the first address addr resolves to, with room for backlog
+// connections that haven't been accepted yet
+async fn bind(addr: &str, backlog: u32) -> io::Result<TcpListener> {
+    let addr = lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} resolves to no address", addr),
+        )
+    })?;
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    // as TcpListener::bind does, so that a restarted broker can take its
+    // port back while old connections sit in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+// the listeners and the broker behind them, served until shut down
+struct Server {
+    listener: TcpListener,
+    // for the other nodes of the cluster, when the properties configure one
+    controller_listener: Option<TcpListener>,
+    broker: Arc<Broker>,
+    shutdown: Shutdown,
+}
+
+impl Server {
+    fn new(listener: TcpListener, broker: Arc<Broker>) -> Server {
+        Server {
+            listener,
+            controller_listener: None,
+            broker,
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    fn with_controller_listener(self, controller_listener: TcpListener) -> Server {
+        Server {
+            controller_listener: Some(controller_listener),
+            ..self
+        }
+    }
+
+    // stops accepting connections; open ones are closed once the request
+    // they're handling, if any, is answered
+    fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    // serves until stop completes, such as on a shutdown signal, and then
+    // until the shutdown is done
+    async fn serve_until(&self, stop: impl Future<Output = ()>) {
+        tokio::join!(self.serve(), async {
+            stop.await;
+            println!("shutting down");
+            self.shutdown();
+        });
+    }
+
+    // returns once shut down and every connection is closed
+    async fn serve(&self) {
+        // loaded before the first connection, then kept up to date alongside them
+        reload_metadata(&self.broker).await;
+        let mut handlers = JoinSet::new();
+        handlers.spawn(reload_metadata_periodically(
+            Arc::clone(&self.broker),
+            self.shutdown.clone(),
+        ));
+        handlers.spawn(delete_expired_segments_periodically(
+            Arc::clone(&self.broker),
+            self.shutdown.clone(),
+        ));
+
+        loop {
+            let (accepted, listener_name) = tokio::select! {
+                _ = self.shutdown.triggered() => break,
+                accepted = self.listener.accept() => (accepted, CLIENT_LISTENER),
+                accepted = accept(&self.controller_listener) => (accepted, CONTROLLER_LISTENER),
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    println!("error: {}", e);
+                    continue;
+                }
+            };
+
+            let broker = Arc::clone(&self.broker);
+            let connection = Connection::new(listener_name);
+            let shutdown = self.shutdown.clone();
+            handlers.spawn(handler_task(
+                peer,
+                handle_stream(stream, broker, connection, CONNECTIONS_MAX_IDLE, shutdown),
+            ));
+
+            while handlers.try_join_next().is_some() {}
+        }
+
+        while handlers.join_next().await.is_some() {}
+    }
+}
+
+// never accepts anything without a listener
+async fn accept(listener: &Option<TcpListener>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn reload_metadata_periodically(broker: Arc<Broker>, shutdown: Shutdown) {
+    let mut interval = time::interval(METADATA_RELOAD_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.triggered() => break,
+            _ = interval.tick() => reload_metadata(&broker).await,
+        }
+    }
+}
+
+async fn delete_expired_segments_periodically(broker: Arc<Broker>, shutdown: Shutdown) {
+    let mut interval = time::interval(LOG_RETENTION_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.triggered() => break,
+            _ = interval.tick() => {
+                let metadata = broker.metadata_log.read().await;
+                blocking_io(|| delete_expired_segments(&broker, &metadata, now_ms()));
+            }
+        }
+    }
+}
+
+// the retention pass over every partition's log. A partition that fails is
+// reported and left for the next pass
+fn delete_expired_segments(broker: &Broker, metadata: &ClusterMetadataLog, now_ms: i64) {
+    for topic in metadata.topics() {
+        let config = LogConfig::for_topic(metadata, &topic.topic_name);
+        for partition in metadata.partitions(&topic.topic_uuid) {
+            let dir = replica_log_dir(broker, partition)
+                .partition_dir(&topic.topic_name, partition.partition_id);
+            if let Err(err) = config.delete_expired(&dir, now_ms) {
+                println!(
+                    "error: failed to delete expired segments of {}-{}: {}",
+                    topic.topic_name, partition.partition_id, err
+                );
+            }
+        }
+    }
+}
+
+// on a blocking thread, as it reads and stats files. A log that can't be read
+// is reported and connections keep being served the metadata last loaded
+async fn reload_metadata(broker: &Arc<Broker>) {
+    let broker = Arc::clone(broker);
+    // checked under the read lock, so that requests aren't held up by a
+    // reload with nothing to read
+    let reloaded = task::spawn_blocking(move || {
+        let changed = broker.metadata_log.blocking_read().changed()?;
+        match changed {
+            true => broker.metadata_log.blocking_write().reload_if_changed(),
+            false => Ok(()),
+        }
+    })
+    .await;
+    match reloaded {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => println!("error: failed to reload cluster metadata: {}", err),
+        Err(err) => println!("error: cluster metadata reload failed: {}", err),
+    }
+}
+
+// completes on SIGINT or SIGTERM. The handlers are installed right away
+// rather than on first poll, so a signal arriving in between isn't missed
+#[cfg(unix)]
+fn shutdown_signals() -> std::io::Result<impl Future<Output = ()>> {
+    let mut interrupt = signal::unix::signal(SignalKind::interrupt())?;
+    let mut terminate = signal::unix::signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signals() -> std::io::Result<impl Future<Output = ()>> {
+    Ok(async {
+        let _ = signal::ctrl_c().await;
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        env, fs,
+        future::Future,
+        io::{Cursor, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        path::PathBuf,
+        pin::Pin,
+        process,
+        sync::{mpsc, Arc, Mutex},
+        task::{Context, Poll},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+        runtime,
+        sync::RwLock,
+        task::JoinSet,
+    };
+
+    use crate::{
+        api::{
+            AbortedTransaction, AddPartitionsToTxnRequest, AddPartitionsToTxnTopic,
+            ApiVersionsRequest, ApiVersionsResponse, BrokerHeartbeatRequest,
+            DescribeTopicPartitionsRequest, ElectLeadersRequest, ElectLeadersTopic, Encoder,
+            EndTxnRequest, ErrorCode, FetchRequest, FetchRequestPartition, FetchRequestTopic,
+            FetchResponsePartition, FetchResponseResponse, ForgottenTopicsData, ListOffsetsRequest,
+            ListOffsetsRequestPartition, ListOffsetsRequestTopic, MetadataRequest,
+            MetadataRequestTopic, NodeEndpoint, OffsetCommitRequestPartition,
+            OffsetCommitRequestTopic, OffsetDeleteRequest, OffsetDeleteRequestTopic,
+            OffsetFetchRequestTopic, ProducePartitionData, ProduceRequest, ProduceTopicData,
+            CONSUMER_REPLICA_ID, EARLIEST_TIMESTAMP, LATEST_TIMESTAMP, READ_COMMITTED,
+        },
+        bind, blocking_io, client_api_keys,
+        crc::crc32c,
+        delete_expired_segments, error_response,
+        fetch_session::FetchSessionCache,
+        handle_add_partitions_to_txn, handle_apiversions, handle_broker_heartbeat,
+        handle_describe_cluster, handle_describe_topic_partitions, handle_elect_leaders,
+        handle_end_txn, handle_fetch, handle_list_offsets, handle_metadata, handle_offset_commit,
+        handle_offset_delete, handle_offset_fetch, handle_produce, handle_request, handle_stream,
+        handler_runtime, handler_task, install_panic_hook, is_unimplemented_api,
+        log_config::{RETENTION_MS, SEGMENT_BYTES},
+        log_dir::{segment_paths, LogDir},
+        metadata_log,
+        metadata_log::{
+            ClusterMetadataLog, ConfigRecord, MetadataVersion, PartitionRecord, RecordBatch,
+            RecordBody, RegisterBrokerRecord, TopicRecord, UnregisterBrokerRecord, TOPIC_RESOURCE,
+        },
+        node_endpoints, now_ms,
+        offsets::{CommittedOffset, OffsetStore},
+        parse_request, parse_request_header,
+        primitives::Uuid,
+        quota::ClientQuotas,
+        reload_metadata, response_header,
+        segment::{self, Segment, SegmentWriter},
+        send, set_current_request,
+        slice::SliceReader,
+        txn::{control_batch, TransactionCoordinator},
+        unimplemented_apis, ApiKey, Broker, Connection, DescribeClusterRequest,
+        DescribeTopicPartitionsResponse, LogConfig, OffsetCommitRequest, OffsetFetchRequest,
+        ProduceResponsePartition, RequestBody, RequestHeader, ResponseBody, Server, Shutdown,
+        UnimplementedApis, BROKER_ENDPOINT_TYPE, CLIENT_LISTENER, CONTROLLER_LISTENER,
+        MAX_REQUEST_PARTITION_SIZE_LIMIT, METADATA_RELOAD_INTERVAL, SOCKET_LISTEN_BACKLOG_SIZE,
+        SOCKET_REQUEST_MAX_BYTES,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("kafka-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn broker() -> Broker {
+        Broker {
+            endpoint: NodeEndpoint {
+                node_id: 1,
+                host: "localhost".to_string(),
+                port: 9092,
+                rack: None,
+            },
+            log_dir: LogDir::new("/nonexistent"),
+            metadata_log: RwLock::new(ClusterMetadataLog::new(LogDir::new("/nonexistent"))),
+            offsets: Mutex::new(OffsetStore::new()),
+            transactions: Mutex::new(TransactionCoordinator::new()),
+            segments: SegmentWriter::new(),
+            high_watermarks: Mutex::new(HashMap::new()),
+            follower_offsets: Mutex::new(HashMap::new()),
+            unimplemented_apis: UnimplementedApis::Reject,
+            fetch_sessions: Mutex::new(FetchSessionCache::new()),
+            quotas: Mutex::new(ClientQuotas::new()),
+            partition_size_limit: MAX_REQUEST_PARTITION_SIZE_LIMIT,
+            socket_request_max_bytes: SOCKET_REQUEST_MAX_BYTES,
+        }
+    }
+
+    fn fetch_request(
+        session_id: i32,
+        session_epoch: i32,
+        partitions: Vec<(Uuid, i32)>,
+    ) -> FetchRequest {
+        FetchRequest {
+            replica_id: CONSUMER_REPLICA_ID,
+            max_wait_ms: 0,
+            min_bytes: 1,
+            max_bytes: 1048576,
+            isolation_level: 0,
+            session_id,
+            session_epoch,
+            topics: partitions
+                .into_iter()
+                .map(|(topic_id, partition)| FetchRequestTopic {
+                    topic_id,
+                    partitions: vec![FetchRequestPartition {
+                        partition,
+                        current_leader_epoch: -1,
+                        fetch_offset: 0,
+                        last_fetched_epoch: -1,
+                        log_start_offset: -1,
+                        partition_max_bytes: 1048576,
+                    }],
+                })
+                .collect(),
+            forgotten_topics_data: vec![],
+            rack_id: String::new(),
+        }
+    }
+
+    fn request_header(request_api_key: i16, request_api_version: i16) -> RequestHeader {
+        RequestHeader {
+            request_api_key,
+            request_api_version,
+            correlation_id: 1,
+            client_id: None,
+        }
+    }
+
+    fn spawn_server(idle_timeout: Duration) -> SocketAddr {
+        spawn_broker(broker(), idle_timeout)
+    }
+
+    // runs the future on a thread of its own, on a single threaded runtime so
+    // that a handler blocking it would hold up every other connection too
+    fn spawn_runtime<F>(future: F) -> thread::JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        thread::spawn(move || {
+            runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(future)
+        })
+    }
+
+    // the server side of a connection accepted with std, for the runtime the
+    // handler runs on
+    fn tokio_stream(stream: TcpStream) -> tokio::net::TcpStream {
+        stream.set_nonblocking(true).unwrap();
+        tokio::net::TcpStream::from_std(stream).unwrap()
+    }
+
+    fn spawn_broker(broker: Broker, idle_timeout: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let broker = Arc::new(broker);
+
+        spawn_runtime(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let broker = Arc::clone(&broker);
+                let connection = Connection::new(CLIENT_LISTENER);
+                tokio::spawn(handle_stream(
+                    stream,
+                    broker,
+                    connection,
+                    idle_timeout,
+                    Shutdown::new(),
+                ));
+            }
+        });
+
+        addr
+    }
+
+    fn api_versions_request(correlation_id: i32) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend(18i16.to_be_bytes());
+        msg.extend(4i16.to_be_bytes());
+        msg.extend(correlation_id.to_be_bytes());
+        msg.extend(4i16.to_be_bytes());
+        msg.extend(b"test");
+        msg.push(0);
+        msg.extend([2, b'a', 2, b'1', 0]);
+
+        let mut frame = (msg.len() as i32).to_be_bytes().to_vec();
+        frame.extend(msg);
+        frame
+    }
+
+    // a BrokerHeartbeat v1 request, which clients don't get an implementation
+    // of, the body isn't looked at
+    fn broker_heartbeat_request(correlation_id: i32) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend(63i16.to_be_bytes());
+        msg.extend(1i16.to_be_bytes());
+        msg.extend(correlation_id.to_be_bytes());
+        msg.extend((-1i16).to_be_bytes());
+        msg.push(0);
+        msg.extend([0, 1, 0, 0, 0, 0, 0]);
+
+        let mut frame = (msg.len() as i32).to_be_bytes().to_vec();
+        frame.extend(msg);
+        frame
+    }
+
+    fn read_response(stream: &mut TcpStream) -> Vec<u8> {
+        let mut size = [0; 4];
+        stream.read_exact(&mut size).unwrap();
+        let mut response = vec![0; i32::from_be_bytes(size) as usize];
+        stream.read_exact(&mut response).unwrap();
+        response
+    }
+
+    // a metadata batch creating the topic with partitions 0 to partitions - 1
+    fn topic_batch(name: &str, topic_uuid: &Uuid, partitions: i32) -> RecordBatch {
+        let mut records = vec![RecordBody::Topic(TopicRecord {
+            topic_name: name.to_string(),
+            topic_uuid: topic_uuid.clone(),
+        })];
+        records.extend((0..partitions).map(|p| metadata_log::test::partition(topic_uuid, p, 1)));
+        RecordBatch::new(0, records)
+    }
+
+    // a connection whose requests are all there up front and that closes
+    // once they've been read, collecting the responses written to it
+    struct MemoryStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl MemoryStream {
+        fn new(frames: &[Vec<u8>]) -> Self {
+            MemoryStream {
+                input: Cursor::new(frames.concat()),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl AsyncRead for MemoryStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for MemoryStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.output).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // runs a connection over the frames until they run out
+    async fn handle_memory_stream(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut stream = MemoryStream::new(frames);
+        handle_stream(
+            &mut stream,
+            Arc::new(broker()),
+            Connection::new(CLIENT_LISTENER),
+            Duration::from_secs(5),
+            Shutdown::new(),
+        )
+        .await;
+        stream.output
+    }
+
+    #[tokio::test]
+    async fn test_throttled_client() {
+        let mut broker = broker();
+        broker.quotas = Mutex::new(ClientQuotas::with_byte_rate(Some(16)));
+        let frames = [api_versions_request(1)];
+        let mut stream = MemoryStream::new(&frames);
+
+        let start = Instant::now();
+        handle_stream(
+            &mut stream,
+            Arc::new(broker),
+            Connection::new(CLIENT_LISTENER),
+            Duration::from_secs(5),
+            Shutdown::new(),
+        )
+        .await;
+
+        // the 20 byte request is a quarter of a second over the 16 bytes a
+        // client can send in a one second window
+        let response = &read_responses(&mut Cursor::new(stream.output), 1)[&1];
+        let position = 4 + 2 + 1 + 7 * client_api_keys().len();
+        let throttle_time_ms =
+            i32::from_be_bytes(response[position..position + 4].try_into().unwrap());
+        assert_eq!(250, throttle_time_ms);
+        // and the connection wasn't read from again until it was over
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    // writes every frame before reading any response
+    fn send_pipelined(stream: &mut TcpStream, frames: &[Vec<u8>]) {
+        stream.write_all(&frames.concat()).unwrap();
+    }
+
+    // the next count responses keyed by correlation id, so that tests don't
+    // depend on the order the server answers pipelined requests in
+    fn read_responses(reader: &mut impl Read, count: usize) -> HashMap<i32, Vec<u8>> {
+        let mut responses = HashMap::new();
+        for _ in 0..count {
+            let mut size = [0; 4];
+            reader.read_exact(&mut size).unwrap();
+            let mut response = vec![0; i32::from_be_bytes(size) as usize];
+            reader.read_exact(&mut response).unwrap();
+
+            let correlation_id = i32::from_be_bytes(response[0..4].try_into().unwrap());
+            let duplicate = responses.insert(correlation_id, response);
+            assert!(
+                duplicate.is_none(),
+                "correlation id {} answered twice",
+                correlation_id
+            );
+        }
+        responses
+    }
+
+    #[test]
+    fn test_read_responses_out_of_order() {
+        let mut frames = Vec::new();
+        for correlation_id in [3i32, 1, 2] {
+            let mut response = correlation_id.to_be_bytes().to_vec();
+            response.push(correlation_id as u8);
+            frames.extend((response.len() as i32).to_be_bytes());
+            frames.extend(response);
+        }
+
+        let responses = read_responses(&mut &frames[..], 3);
+        let mut correlation_ids: Vec<_> = responses.keys().copied().collect();
+        correlation_ids.sort();
+        assert_eq!(vec![1, 2, 3], correlation_ids);
+        for (correlation_id, response) in responses {
+            assert_eq!(correlation_id as u8, response[4]);
+        }
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let addr = spawn_server(Duration::from_secs(5));
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        let correlation_ids: Vec<i32> = (100..110).collect();
+        let frames: Vec<_> = correlation_ids
+            .iter()
+            .map(|id| match id % 2 {
+                0 => api_versions_request(*id),
+                _ => broker_heartbeat_request(*id),
+            })
+            .collect();
+        send_pipelined(&mut stream, &frames);
+
+        let responses = read_responses(&mut stream, frames.len());
+        for id in correlation_ids {
+            let response = &responses[&id];
+            let error_code = i16::from_be_bytes(response[4..6].try_into().unwrap());
+            let expected = match id % 2 {
+                0 => ErrorCode::NoError,
+                _ => ErrorCode::UnsupportedVersion,
+            };
+            assert_eq!(expected.code(), error_code);
+        }
+    }
+
+    #[test]
+    fn test_frame_split_across_slow_segments() {
+        let idle_timeout = Duration::from_millis(200);
+        let addr = spawn_server(idle_timeout);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+
+        // the whole frame takes several idle timeouts to arrive
+        let frame = api_versions_request(7);
+        for byte in &frame {
+            stream.write_all(&[*byte]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let response = read_response(&mut stream);
+        assert_eq!(7, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+        assert_eq!(0, i16::from_be_bytes(response[4..6].try_into().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_offset_commit_then_fetch() {
+        let broker = broker();
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 2)]);
+
+        let commit = |partition_index, committed_offset| OffsetCommitRequestPartition {
+            partition_index,
+            committed_offset,
+            committed_leader_epoch: 3,
+            committed_metadata: Some("m".to_string()),
+        };
+        let request = OffsetCommitRequest {
+            group_id: "group".to_string(),
+            generation_id: 1,
+            member_id: "member".to_string(),
+            group_instance_id: None,
+            retention_time_ms: 60_000,
+            topics: vec![
+                OffsetCommitRequestTopic {
+                    name: "foo".to_string(),
+                    partitions: vec![commit(0, 42), commit(1, 7)],
+                },
+                OffsetCommitRequestTopic {
+                    name: "bar".to_string(),
+                    partitions: vec![commit(0, 1)],
+                },
+            ],
+        };
+        let before = now_ms();
+        let response = handle_offset_commit(&request_header(8, 8), &request, &broker).await;
+        let errors: Vec<_> = response
+            .topics
+            .iter()
+            .flat_map(|topic| topic.partitions.iter().map(|p| p.error_code))
+            .collect();
+        assert_eq!(
+            vec![
+                ErrorCode::NoError,
+                ErrorCode::NoError,
+                ErrorCode::UnknownTopicOrPartition
+            ],
+            errors
+        );
+
+        // kept for the retention from the time of the commit
+        let committed = broker.offsets.lock().unwrap().fetch("group", "foo", 0);
+        let expire_timestamp = committed.expire_timestamp.unwrap();
+        assert!(expire_timestamp >= before + 60_000);
+        assert!(expire_timestamp <= now_ms() + 60_000);
+
+        let mut request = OffsetFetchRequest {
+            group_id: "group".to_string(),
+            topics: Some(vec![OffsetFetchRequestTopic {
+                name: "foo".to_string(),
+                partition_indexes: vec![1, 2],
+            }]),
+            require_stable: false,
+        };
+        let response = handle_offset_fetch(&request_header(9, 7), &request, &broker.offsets);
+        let partitions = &response.topics[0].partitions;
+        assert_eq!(7, partitions[0].committed_offset);
+        assert_eq!(3, partitions[0].committed_leader_epoch);
+        assert_eq!(Some("m".to_string()), partitions[0].metadata);
+        assert_eq!(-1, partitions[1].committed_offset);
+
+        // without topics, everything the group committed
+        request.topics = None;
+        let response = handle_offset_fetch(&request_header(9, 7), &request, &broker.offsets);
+        assert_eq!(1, response.topics.len());
+        let offsets: Vec<_> = response.topics[0]
+            .partitions
+            .iter()
+            .map(|p| (p.partition_index, p.committed_offset))
+            .collect();
+        assert_eq!(vec![(0, 42), (1, 7)], offsets);
+    }
+
+    #[test]
+    fn test_offset_delete() {
+        let broker = broker();
+        broker.offsets.lock().unwrap().commit(
+            "group",
+            "foo",
+            0,
+            CommittedOffset {
+                offset: 42,
+                leader_epoch: -1,
+                metadata: None,
+                expire_timestamp: None,
+            },
+        );
+
+        let request = OffsetDeleteRequest {
+            group_id: "group".to_string(),
+            topics: vec![OffsetDeleteRequestTopic {
+                name: "foo".to_string(),
+                partitions: vec![0],
+            }],
+        };
+        let response = handle_offset_delete(&request_header(47, 0), &request, &broker.offsets);
+
+        assert!(response.error_code == ErrorCode::NoError);
+        assert_eq!(1, response.topics.len());
+        assert!(response.topics[0].partitions[0].error_code == ErrorCode::NoError);
+        assert_eq!(
+            -1,
+            broker
+                .offsets
+                .lock()
+                .unwrap()
+                .fetch("group", "foo", 0)
+                .offset
+        );
+    }
+
+    #[test]
+    fn test_offset_delete_unknown_group() {
+        let broker = broker();
+        let request = OffsetDeleteRequest {
+            group_id: "missing".to_string(),
+            topics: Vec::new(),
+        };
+        let response = handle_offset_delete(&request_header(47, 0), &request, &broker.offsets);
+
+        assert!(response.error_code == ErrorCode::GroupIdNotFound);
+    }
+
+    #[tokio::test]
+    async fn test_describe_topic_partitions_null_name() {
+        let broker = broker();
+        let request = DescribeTopicPartitionsRequest {
+            topics: vec![None],
+            response_partition_limit: 100,
+            cursor: None,
+        };
+        let response = handle_describe_topic_partitions(
+            &request_header(75, 0),
+            &request,
+            &broker.metadata_log,
+            broker.partition_size_limit,
+        )
+        .await;
+
+        assert_eq!(1, response.topics.len());
+        assert!(response.topics[0].error_code == ErrorCode::InvalidTopicException);
+        assert!(response.topics[0].name.is_none());
+    }
+
+    #[test]
+    fn test_truncated_body_gets_error_response() {
+        let addr = spawn_server(Duration::from_secs(5));
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        // a Fetch v16 header followed by only part of the body
+        let mut msg = Vec::new();
+        msg.extend(1i16.to_be_bytes());
+        msg.extend(16i16.to_be_bytes());
+        msg.extend(99i32.to_be_bytes());
+        msg.extend((-1i16).to_be_bytes());
+        msg.push(0);
+        msg.extend([0, 0, 1]);
+
+        let mut frame = (msg.len() as i32).to_be_bytes().to_vec();
+        frame.extend(msg);
+        stream.write_all(&frame).unwrap();
+
+        let response = read_response(&mut stream);
+        assert_eq!(99, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+        // correlation id, tag buffer and throttle time precede the error code
+        assert_eq!(
+            ErrorCode::InvalidRequest.code(),
+            i16::from_be_bytes(response[9..11].try_into().unwrap())
+        );
+    }
+
+    // an ApiVersions v0 body, with the error and every API the client can use
+    fn assert_unsupported_api_response(body: &[u8]) {
+        let expected = ApiVersionsResponse {
+            version: 0,
+            error_code: ErrorCode::UnsupportedVersion.code(),
+            api_keys: client_api_keys(),
+            throttle_time_ms: 0,
+        }
+        .encode();
+        assert_eq!(expected, body);
+    }
+
+    #[test]
+    fn test_unimplemented_apis_from_properties() {
+        let properties =
+            |value: &str| HashMap::from([("unimplemented.apis".to_string(), value.to_string())]);
+        assert_eq!(
+            UnimplementedApis::Close,
+            unimplemented_apis(&properties("close"), UnimplementedApis::Reject)
+        );
+        assert_eq!(
+            UnimplementedApis::Reject,
+            unimplemented_apis(&properties("reject"), UnimplementedApis::Close)
+        );
+        assert_eq!(
+            UnimplementedApis::Close,
+            unimplemented_apis(&properties("lenient"), UnimplementedApis::Close)
+        );
+        assert_eq!(
+            UnimplementedApis::Reject,
+            unimplemented_apis(&HashMap::new(), UnimplementedApis::Reject)
+        );
+    }
+
+    #[test]
+    fn test_unimplemented_api_gets_error_response() {
+        let addr = spawn_server(Duration::from_secs(5));
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream.write_all(&broker_heartbeat_request(7)).unwrap();
+
+        let response = read_response(&mut stream);
+        assert_eq!(7, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+        assert_unsupported_api_response(&response[4..]);
+
+        // the connection stays usable
+        stream.write_all(&api_versions_request(8)).unwrap();
+        let response = read_response(&mut stream);
+        assert_eq!(8, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+    }
+
+    #[test]
+    fn test_unimplemented_api_closes_connection_when_strict() {
+        let mut broker = broker();
+        broker.unimplemented_apis = UnimplementedApis::Close;
+        let addr = spawn_broker(broker, Duration::from_secs(5));
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream.write_all(&broker_heartbeat_request(7)).unwrap();
+
+        let mut buf = [0; 1];
+        assert_eq!(0, stream.read(&mut buf).unwrap());
+    }
+
+    fn unknown_api_request(correlation_id: i32) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend(9999i16.to_be_bytes());
+        msg.extend(0i16.to_be_bytes());
+        msg.extend(correlation_id.to_be_bytes());
+        msg.extend((-1i16).to_be_bytes());
+        msg.push(0);
+        msg.extend([1, 2, 3]);
+
+        let mut frame = (msg.len() as i32).to_be_bytes().to_vec();
+        frame.extend(msg);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_sequential_requests_on_memory_stream() {
+        let output = handle_memory_stream(&[
+            api_versions_request(1),
+            unknown_api_request(2),
+            api_versions_request(3),
+        ])
+        .await;
+
+        // answered in order, the unknown API not getting in the way of the next
+        let mut reader = Cursor::new(output);
+        for correlation_id in 1..=3 {
+            let response = read_responses(&mut reader, 1);
+            assert!(response.contains_key(&correlation_id));
+        }
+        assert_eq!(reader.get_ref().len() as u64, reader.position());
+
+        // nothing to answer once the client has closed
+        assert!(handle_memory_stream(&[]).await.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_api_gets_error_response() {
+        let addr = spawn_server(Duration::from_secs(5));
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream.write_all(&unknown_api_request(7)).unwrap();
+
+        let response = read_response(&mut stream);
+        assert_eq!(7, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+        assert_unsupported_api_response(&response[4..]);
+
+        // the connection stays usable
+        stream.write_all(&api_versions_request(8)).unwrap();
+        let response = read_response(&mut stream);
+        assert_eq!(8, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+    }
+
+    #[test]
+    fn test_unknown_api_closes_connection_when_strict() {
+        let mut broker = broker();
+        broker.unimplemented_apis = UnimplementedApis::Close;
+        let addr = spawn_broker(broker, Duration::from_secs(5));
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream.write_all(&unknown_api_request(7)).unwrap();
+
+        let mut buf = [0; 1];
+        assert_eq!(0, stream.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_header_closes_connection() {
+        let addr = spawn_server(Duration::from_secs(5));
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        stream.write_all(&[0, 0, 0, 3, 0, 1, 0]).unwrap();
+
+        let mut buf = [0; 1];
+        assert_eq!(0, stream.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_oversized_request_closes_connection() {
+        let mut broker = broker();
+        broker.socket_request_max_bytes = 1024;
+        let addr = spawn_broker(broker, Duration::from_secs(5));
+
+        // a request within the limit is answered
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&api_versions_request(1)).unwrap();
+        assert_eq!(
+            1,
+            i32::from_be_bytes(read_response(&mut stream)[0..4].try_into().unwrap())
+        );
+
+        // the size alone closes the connection, nothing is allocated for it
+        for size in [i32::MAX, 1025, -1] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&size.to_be_bytes()).unwrap();
+
+            let mut buf = [0; 1];
+            assert_eq!(0, stream.read(&mut buf).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "correlation id")]
+    async fn test_send_mismatched_correlation_id() {
+        let mut response = error_response(&request_header(18, 4), ErrorCode::NoError).unwrap();
+        response.header.correlation_id = 2;
+
+        send(&mut Vec::new(), &request_header(18, 4), &response)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_end_txn_appends_commit_marker() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("end-txn"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        let request = AddPartitionsToTxnRequest {
+            transactional_id: "txn".to_string(),
+            producer_id: 7,
+            producer_epoch: 0,
+            topics: vec![AddPartitionsToTxnTopic {
+                name: "foo".to_string(),
+                partitions: vec![0],
+            }],
+        };
+        let response =
+            handle_add_partitions_to_txn(&request_header(24, 3), &request, &broker.transactions);
+        assert!(response.results[0].results[0].partition_error_code == ErrorCode::NoError);
+
+        let request = EndTxnRequest {
+            transactional_id: "txn".to_string(),
+            producer_id: 7,
+            producer_epoch: 0,
+            committed: true,
+        };
+        let response = handle_end_txn(&request_header(26, 3), &request, &broker).await;
+        assert!(response.error_code == ErrorCode::NoError);
+
+        let path = broker.log_dir.segment_path("foo", 0, 0);
+        let segment = Segment::open(path).unwrap();
+        assert_eq!(1, segment.batches().len());
+
+        let data = segment.read_from(0);
+        // transactional and control attributes, then the commit key type
+        assert_eq!([0x00, 0x30], data[21..23]);
+        assert_eq!(7, i64::from_be_bytes(data[43..51].try_into().unwrap()));
+        assert_eq!(1, i16::from_be_bytes(data[68..70].try_into().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_end_txn_marker_errors() {
+        let mut broker = broker();
+        let dir = temp_dir("end-txn-errors");
+        broker.log_dir = LogDir::new(dir.join("logs"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        async fn end_txn(broker: &Broker, topics: &[&str]) -> ErrorCode {
+            let request = AddPartitionsToTxnRequest {
+                transactional_id: "txn".to_string(),
+                producer_id: 7,
+                producer_epoch: 0,
+                topics: topics
+                    .iter()
+                    .map(|name| AddPartitionsToTxnTopic {
+                        name: name.to_string(),
+                        partitions: vec![0],
+                    })
+                    .collect(),
+            };
+            handle_add_partitions_to_txn(&request_header(24, 3), &request, &broker.transactions);
+
+            let request = EndTxnRequest {
+                transactional_id: "txn".to_string(),
+                producer_id: 7,
+                producer_epoch: 0,
+                committed: false,
+            };
+            handle_end_txn(&request_header(26, 3), &request, broker)
+                .await
+                .error_code
+        }
+
+        // names that aren't topics, nor could be, are never written outside the log dir
+        assert_eq!(
+            ErrorCode::UnknownTopicOrPartition,
+            end_txn(&broker, &["bar", "../escaped"]).await
+        );
+        assert!(!dir.join("escaped-0").exists());
+        assert!(!broker.log_dir.partition_dir("bar", 0).exists());
+
+        // the known partition is still marked when another one isn't
+        assert_eq!(
+            ErrorCode::UnknownTopicOrPartition,
+            end_txn(&broker, &["foo", "bar"]).await
+        );
+        let path = broker.log_dir.segment_path("foo", 0, 0);
+        assert_eq!(1, Segment::open(&path).unwrap().batches().len());
+
+        // a marker that can't be written is a storage error, not a panic
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        broker.segments = SegmentWriter::new();
+        assert_eq!(
+            ErrorCode::KafkaStorageError,
+            end_txn(&broker, &["foo"]).await
+        );
+    }
+
+    #[test]
+    fn test_node_endpoints_for_preferred_replica() {
+        let broker = broker();
+        let mut responses = vec![FetchResponseResponse {
+            topic_id: Uuid::new(),
+            partitions: vec![FetchResponsePartition::new(0, vec![], 0)],
+        }];
+        assert!(node_endpoints(&responses, &broker.endpoint).is_empty());
+
+        responses[0].partitions[0].preferred_read_replica = 1;
+        let endpoints = node_endpoints(&responses, &broker.endpoint);
+        assert_eq!(1, endpoints.len());
+        assert_eq!("localhost", endpoints[0].host);
+        assert_eq!(9092, endpoints[0].port);
+    }
+
+    #[tokio::test]
+    async fn test_describe_topic_without_partitions() {
+        let broker = broker();
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(
+                0,
+                vec![RecordBody::Topic(TopicRecord {
+                    topic_name: "foo".to_string(),
+                    topic_uuid: Uuid { uuid: [1; 16] },
+                })],
+            )]);
+
+        let request = DescribeTopicPartitionsRequest {
+            topics: vec![Some("foo".to_string())],
+            response_partition_limit: 100,
+            cursor: None,
+        };
+        let response = handle_describe_topic_partitions(
+            &request_header(75, 0),
+            &request,
+            &broker.metadata_log,
+            broker.partition_size_limit,
+        )
+        .await;
+
+        assert_eq!(1, response.topics.len());
+        let topic = &response.topics[0];
+        assert_eq!(ErrorCode::NoError, topic.error_code);
+        assert_eq!(Uuid { uuid: [1; 16] }, topic.topic_id);
+        assert!(topic.partitions.is_empty());
+
+        // error code, name, topic id and is_internal precede the partitions
+        let encoded = topic.encode();
+        assert_eq!(1, encoded[2 + 4 + 16 + 1]);
+    }
+
+    #[tokio::test]
+    async fn test_describe_topic_requested_twice() {
+        let broker = broker();
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(
+                0,
+                vec![RecordBody::Topic(TopicRecord {
+                    topic_name: "foo".to_string(),
+                    topic_uuid: Uuid { uuid: [1; 16] },
+                })],
+            )]);
+
+        let request = DescribeTopicPartitionsRequest {
+            topics: vec![Some("foo".to_string()), Some("foo".to_string()), None, None],
+            response_partition_limit: 100,
+            cursor: None,
+        };
+        let response = handle_describe_topic_partitions(
+            &request_header(75, 0),
+            &request,
+            &broker.metadata_log,
+            broker.partition_size_limit,
+        )
+        .await;
+
+        assert_eq!(2, response.topics.len());
+        assert_eq!(Some("foo".to_string()), response.topics[0].name);
+        assert_eq!(
+            ErrorCode::InvalidTopicException,
+            response.topics[1].error_code
+        );
+    }
+
+    #[tokio::test]
+    async fn test_describe_unknown_topic_by_name() {
+        let broker = broker();
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(
+                0,
+                vec![RecordBody::Topic(TopicRecord {
+                    topic_name: "foo".to_string(),
+                    topic_uuid: Uuid { uuid: [1; 16] },
+                })],
+            )]);
+
+        let request = DescribeTopicPartitionsRequest {
+            topics: vec![Some("foo".to_string()), Some("bar".to_string())],
+            response_partition_limit: 100,
+            cursor: None,
+        };
+        let response = handle_describe_topic_partitions(
+            &request_header(75, 0),
+            &request,
+            &broker.metadata_log,
+            broker.partition_size_limit,
+        )
+        .await;
+
+        assert_eq!(2, response.topics.len());
+        assert_eq!(ErrorCode::NoError, response.topics[0].error_code);
+        let bar = &response.topics[1];
+        assert_eq!(Some("bar".to_string()), bar.name);
+        assert_eq!(ErrorCode::UnknownTopicOrPartition, bar.error_code);
+        assert_eq!(3, bar.error_code.code());
+        assert_eq!(Uuid::new(), bar.topic_id);
+    }
+
+    #[tokio::test]
+    async fn test_describe_topics_in_request_order() {
+        let broker = broker();
+        let (foo, baz) = (Uuid { uuid: [1; 16] }, Uuid { uuid: [3; 16] });
+        broker.metadata_log.write().await.set_batches(vec![
+            topic_batch("foo", &foo, 2),
+            topic_batch("baz", &baz, 1),
+        ]);
+
+        let request = DescribeTopicPartitionsRequest {
+            topics: ["baz", "bar", "foo"]
+                .iter()
+                .map(|name| Some(name.to_string()))
+                .collect(),
+            response_partition_limit: 100,
+            cursor: None,
+        };
+        let response = handle_describe_topic_partitions(
+            &request_header(75, 0),
+            &request,
+            &broker.metadata_log,
+            broker.partition_size_limit,
+        )
+        .await;
+
+        let topics: Vec<_> = response
+            .topics
+            .iter()
+            .map(|t| {
+                let name = t.name.clone().unwrap();
+                (name, t.error_code, t.topic_id.clone(), t.partitions.len())
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("baz".to_string(), ErrorCode::NoError, baz, 1),
+                (
+                    "bar".to_string(),
+                    ErrorCode::UnknownTopicOrPartition,
+                    Uuid::new(),
+                    0
+                ),
+                ("foo".to_string(), ErrorCode::NoError, foo, 2),
+            ],
+            topics
+        );
+    }
+
+    #[test]
+    fn test_describe_cluster_rack() {
+        let mut broker = broker();
+        let request = DescribeClusterRequest {
+            include_cluster_authorized_operations: false,
+            endpoint_type: BROKER_ENDPOINT_TYPE,
+        };
+
+        for rack in [Some("rack-a"), None] {
+            broker.endpoint.rack = rack.map(str::to_string);
+            let response = handle_describe_cluster(&request_header(60, 1), &request, &broker);
+
+            assert_eq!(ErrorCode::NoError, response.error_code);
+            assert_eq!(rack, response.brokers[0].rack.as_deref());
+            // a null rack is a zero length, a set one its length plus one
+            let rack_bytes = match rack {
+                Some(rack) => [&[rack.len() as u8 + 1], rack.as_bytes()].concat(),
+                None => vec![0],
+            };
+            let mut broker_bytes = 9092i32.to_be_bytes().to_vec();
+            broker_bytes.extend(rack_bytes);
+            let encoded = response.encode();
+            assert!(encoded
+                .windows(broker_bytes.len())
+                .any(|window| window == broker_bytes));
+        }
+
+        // there's no controller listener to tell clients about
+        let request = DescribeClusterRequest {
+            include_cluster_authorized_operations: false,
+            endpoint_type: 2,
+        };
+        let response = handle_describe_cluster(&request_header(60, 1), &request, &broker);
+        assert_eq!(ErrorCode::UnsupportedEndpointType, response.error_code);
+        assert!(response.brokers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_describe_partitions_sorted() {
+        let broker = broker();
+        let foo = Uuid { uuid: [1; 16] };
+        let mut records = vec![RecordBody::Topic(TopicRecord {
+            topic_name: "foo".to_string(),
+            topic_uuid: foo.clone(),
+        })];
+        records.extend([2, 0, 1].map(|p| metadata_log::test::partition(&foo, p, 1)));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(0, records)]);
+
+        let request = DescribeTopicPartitionsRequest {
+            topics: vec![Some("foo".to_string())],
+            response_partition_limit: 100,
+            cursor: None,
+        };
+        let response = handle_describe_topic_partitions(
+            &request_header(75, 0),
+            &request,
+            &broker.metadata_log,
+            broker.partition_size_limit,
+        )
+        .await;
+
+        let partitions: Vec<i32> = response.topics[0]
+            .partitions
+            .iter()
+            .map(|p| p.partition_index)
+            .collect();
+        assert_eq!(vec![0, 1, 2], partitions);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_unknown_topic_by_id() {
+        let broker = broker();
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+        let request = fetch_request(0, -1, vec![(Uuid { uuid: [9; 16] }, 0), (foo, 0)]);
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+
+        assert_eq!(ErrorCode::NoError, response.error_code);
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(ErrorCode::UnknownTopicId, partition.error_code);
+        assert_eq!(100, partition.error_code.code());
+        assert!(partition.records.is_empty());
+        let partition = &response.responses[1].partitions[0];
+        assert_eq!(ErrorCode::NoError, partition.error_code);
+
+        // only a failure of the request as a whole is reported at the top
+        let request = fetch_request(12345, 1, vec![(Uuid { uuid: [9; 16] }, 0)]);
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        assert_eq!(ErrorCode::FetchSessionIdNotFound, response.error_code);
+        assert!(response.responses.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_topics() {
+        let broker = broker();
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(
+                0,
+                vec![
+                    RecordBody::Topic(TopicRecord {
+                        topic_name: "foo".to_string(),
+                        topic_uuid: foo.clone(),
+                    }),
+                    // created out of order
+                    metadata_log::test::partition(&foo, 1, 1),
+                    metadata_log::test::partition(&foo, 0, 1),
+                ],
+            )]);
+
+        let mut request = MetadataRequest {
+            topics: None,
+            allow_auto_topic_creation: false,
+            include_topic_authorized_operations: false,
+        };
+        let response = handle_metadata(&request_header(3, 12), &request, &broker).await;
+        assert_eq!(1, response.brokers.len());
+        assert_eq!(broker.endpoint.node_id, response.controller_id);
+        assert_eq!(1, response.topics.len());
+        assert_eq!(Some("foo".to_string()), response.topics[0].name);
+        let partitions: Vec<i32> = response.topics[0]
+            .partitions
+            .iter()
+            .map(|p| p.partition_index)
+            .collect();
+        assert_eq!(vec![0, 1], partitions);
+
+        request.topics = Some(vec![
+            MetadataRequestTopic {
+                topic_id: Uuid::new(),
+                name: Some("bar".to_string()),
+            },
+            MetadataRequestTopic {
+                topic_id: Uuid { uuid: [2; 16] },
+                name: None,
+            },
+        ]);
+        let response = handle_metadata(&request_header(3, 12), &request, &broker).await;
+        let error_codes: Vec<ErrorCode> = response.topics.iter().map(|t| t.error_code).collect();
+        assert_eq!(
+            vec![
+                ErrorCode::UnknownTopicOrPartition,
+                ErrorCode::UnknownTopicId
+            ],
+            error_codes
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metadata_response_size_limit() {
+        let mut broker = broker();
+        let batches = (0..50u8)
+            .map(|i| topic_batch(&format!("topic-{}", i), &Uuid { uuid: [i; 16] }, 20))
+            .collect();
+        broker.metadata_log.write().await.set_batches(batches);
+
+        let request = MetadataRequest {
+            topics: None,
+            allow_auto_topic_creation: false,
+            include_topic_authorized_operations: false,
+        };
+        let response = handle_metadata(&request_header(3, 12), &request, &broker).await;
+        let size = response.encode().len();
+        assert!(response
+            .topics
+            .iter()
+            .all(|t| t.error_code == ErrorCode::NoError && t.partitions.len() == 20));
+
+        // one byte short of the full response, every topic is refused instead
+        broker.socket_request_max_bytes = size - 1;
+        let response = handle_metadata(&request_header(3, 12), &request, &broker).await;
+        assert_eq!(50, response.topics.len());
+        assert!(response
+            .topics
+            .iter()
+            .all(|t| t.error_code == ErrorCode::InvalidRequest && t.partitions.is_empty()));
+        assert!(response.encode().len() <= broker.socket_request_max_bytes);
+    }
+
+    #[tokio::test]
+    async fn test_describe_topic_partitions_default_limit() {
+        let mut broker = broker();
+        let foo = Uuid { uuid: [1; 16] };
+        let mut records = vec![RecordBody::Topic(TopicRecord {
+            topic_name: "foo".to_string(),
+            topic_uuid: foo.clone(),
+        })];
+        for partition_id in 0..3 {
+            records.push(RecordBody::Partition(PartitionRecord {
+                partition_id,
+                topic_id: foo.clone(),
+                replicas: vec![1],
+                isr: vec![1],
+                removing_replicas: vec![],
+                adding_replicas: vec![],
+                leader: 1,
+                leader_epoch: 0,
+                partition_epoch: 0,
+                directories: vec![],
+                leader_recovery_state: 0,
+            }));
+        }
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(0, records)]);
+
+        let request = DescribeTopicPartitionsRequest {
+            topics: vec![Some("foo".to_string())],
+            response_partition_limit: 0,
+            cursor: None,
+        };
+        async fn describe(
+            broker: &Broker,
+            request: &DescribeTopicPartitionsRequest,
+        ) -> DescribeTopicPartitionsResponse {
+            handle_describe_topic_partitions(
+                &request_header(75, 0),
+                request,
+                &broker.metadata_log,
+                broker.partition_size_limit,
+            )
+            .await
+        }
+
+        // fewer partitions than the default, so all of them
+        let response = describe(&broker, &request).await;
+        assert_eq!(3, response.topics[0].partitions.len());
+        assert!(response.next_cursor.is_none());
+
+        broker.partition_size_limit = 2;
+        let response = describe(&broker, &request).await;
+        assert_eq!(2, response.topics[0].partitions.len());
+        let cursor = response.next_cursor.unwrap();
+        assert_eq!("foo", cursor.topic_name);
+        assert_eq!(2, cursor.partition_index);
+    }
+
+    #[tokio::test]
+    async fn test_describe_topic_partitions_pages() {
+        let broker = broker();
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 5)]);
+
+        let mut request = DescribeTopicPartitionsRequest {
+            topics: vec![Some("foo".to_string())],
+            response_partition_limit: 2,
+            cursor: None,
+        };
+        let mut pages = Vec::new();
+        loop {
+            let response = handle_describe_topic_partitions(
+                &request_header(75, 0),
+                &request,
+                &broker.metadata_log,
+                broker.partition_size_limit,
+            )
+            .await;
+            let partitions: Vec<i32> = response.topics[0]
+                .partitions
+                .iter()
+                .map(|p| p.partition_index)
+                .collect();
+            pages.push(partitions);
+            request.cursor = response.next_cursor;
+            if request.cursor.is_none() {
+                break;
+            }
+        }
+
+        assert_eq!(vec![vec![0, 1], vec![2, 3], vec![4]], pages);
+    }
+
+    #[test]
+    fn test_parse_api_versions_v0() {
+        let mut message = Vec::new();
+        message.extend((ApiKey::ApiVersions as i16).to_be_bytes());
+        message.extend(0i16.to_be_bytes());
+        message.extend(7i32.to_be_bytes());
+        message.extend(1i16.to_be_bytes());
+        message.push(b'c');
+
+        let request = parse_request(&message, &Connection::new(CLIENT_LISTENER))
+            .ok()
+            .unwrap();
+        assert_eq!(7, request.header.correlation_id);
+        assert_eq!(Some("c"), request.header.client_id.as_deref());
+        assert!(matches!(
+            request.body,
+            RequestBody::ApiVersions(body) if body == ApiVersionsRequest::default()
+        ));
+
+        // whatever follows the header is left alone
+        message.push(0xaa);
+        let mut reader = SliceReader::new(&message);
+        parse_request_header(&mut reader).unwrap();
+        assert_eq!([0xaa], reader.remaining());
+    }
+
+    #[test]
+    fn test_request_header_versions() {
+        let header = |api_key: ApiKey, version: i16, client_id: bool| {
+            let mut message = Vec::new();
+            message.extend((api_key as i16).to_be_bytes());
+            message.extend(version.to_be_bytes());
+            message.extend(7i32.to_be_bytes());
+            if client_id {
+                message.extend(1i16.to_be_bytes());
+                message.push(b'c');
+            }
+            message
+        };
+
+        // v2, the client id followed by a tag buffer
+        let mut message = header(ApiKey::Metadata, 12, true);
+        message.extend([1, 0, 1, 0xbb, 0xaa]);
+        let mut reader = SliceReader::new(&message);
+        let parsed = parse_request_header(&mut reader).unwrap();
+        assert_eq!(Some("c"), parsed.client_id.as_deref());
+        assert_eq!([0xaa], reader.remaining());
+        assert!(response_header(&parsed).include_tag_buffer);
+
+        // v1, the client id alone
+        for (api_key, version) in [(ApiKey::Metadata, 8), (ApiKey::OffsetDelete, 0)] {
+            let mut message = header(api_key, version, true);
+            message.push(0xaa);
+            let mut reader = SliceReader::new(&message);
+            let parsed = parse_request_header(&mut reader).unwrap();
+            assert_eq!(Some("c"), parsed.client_id.as_deref());
+            assert_eq!([0xaa], reader.remaining());
+            assert!(!response_header(&parsed).include_tag_buffer);
+        }
+
+        // v0, not even a client id
+        let mut message = header(ApiKey::ControlledShutdown, 0, false);
+        message.push(0xaa);
+        let mut reader = SliceReader::new(&message);
+        let parsed = parse_request_header(&mut reader).unwrap();
+        assert_eq!(7, parsed.correlation_id);
+        assert_eq!(None, parsed.client_id);
+        assert_eq!([0xaa], reader.remaining());
+    }
+
+    #[test]
+    fn test_parse_null_client_id() {
+        let mut message = Vec::new();
+        message.extend((ApiKey::ApiVersions as i16).to_be_bytes());
+        message.extend(0i16.to_be_bytes());
+        message.extend(7i32.to_be_bytes());
+        message.extend((-1i16).to_be_bytes());
+        let header = parse_request_header(&mut SliceReader::new(&message)).unwrap();
+        assert_eq!(None, header.client_id);
+
+        message.truncate(8);
+        message.extend(0i16.to_be_bytes());
+        let header = parse_request_header(&mut SliceReader::new(&message)).unwrap();
+        assert_eq!(Some(String::new()), header.client_id);
+    }
+
+    #[tokio::test]
+    async fn test_client_id_with_invalid_utf8() {
+        let mut message = Vec::new();
+        message.extend((ApiKey::ApiVersions as i16).to_be_bytes());
+        message.extend(0i16.to_be_bytes());
+        message.extend(7i32.to_be_bytes());
+        message.extend(3i16.to_be_bytes());
+        message.extend([b'a', 0xff, b'b']);
+
+        let request = parse_request(&message, &Connection::new(CLIENT_LISTENER))
+            .ok()
+            .unwrap();
+        assert_eq!(Some("a\u{fffd}b".to_string()), request.header.client_id);
+
+        let response = handle_request(&request, &broker(), &Connection::new(CLIENT_LISTENER)).await;
+        assert_eq!(7, response.header.correlation_id);
+        assert!(matches!(response.body, ResponseBody::ApiVersions(_)));
+    }
+
+    #[tokio::test]
+    async fn test_describe_offline_replicas() {
+        let broker = broker();
+        let foo = Uuid { uuid: [1; 16] };
+        let register = |broker_id| {
+            RecordBody::RegisterBroker(RegisterBrokerRecord {
+                broker_id,
+                is_migrating_zk_broker: false,
+                incarnation_id: Uuid { uuid: [2; 16] },
+                broker_epoch: 1,
+                endpoints: vec![],
+                features: vec![],
+                rack: None,
+                fenced: false,
+                in_controlled_shutdown: false,
+                log_dirs: vec![],
+            })
+        };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(
+                0,
+                vec![
+                    register(1),
+                    register(2),
+                    register(3),
+                    RecordBody::UnregisterBroker(UnregisterBrokerRecord {
+                        broker_id: 3,
+                        broker_epoch: 1,
+                    }),
+                    RecordBody::Topic(TopicRecord {
+                        topic_name: "foo".to_string(),
+                        topic_uuid: foo.clone(),
+                    }),
+                    RecordBody::Partition(PartitionRecord {
+                        partition_id: 0,
+                        topic_id: foo,
+                        replicas: vec![1, 2, 3],
+                        isr: vec![1, 2],
+                        removing_replicas: vec![],
+                        adding_replicas: vec![],
+                        leader: 1,
+                        leader_epoch: 0,
+                        partition_epoch: 0,
+                        directories: vec![],
+                        leader_recovery_state: 0,
+                    }),
+                ],
+            )]);
+
+        let request = DescribeTopicPartitionsRequest {
+            topics: vec![Some("foo".to_string())],
+            response_partition_limit: 100,
+            cursor: None,
+        };
+        let response = handle_describe_topic_partitions(
+            &request_header(75, 0),
+            &request,
+            &broker.metadata_log,
+            broker.partition_size_limit,
+        )
+        .await;
+
+        assert_eq!(vec![3], response.topics[0].partitions[0].offline_replicas);
+    }
+
+    #[test]
+    fn test_api_versions_per_listener() {
+        let request = ApiVersionsRequest {
+            client_software_name: "test".to_string(),
+            client_software_version: "1".to_string(),
+        };
+        let header = request_header(18, 4);
+
+        let client = handle_apiversions(&header, &request, &Connection::new(CLIENT_LISTENER));
+        let controller =
+            handle_apiversions(&header, &request, &Connection::new(CONTROLLER_LISTENER));
+
+        let keys = |response: &ApiVersionsResponse| -> Vec<i16> {
+            response.api_keys.iter().map(|k| k.api_key).collect()
+        };
+        assert!(keys(&client).iter().all(|k| keys(&controller).contains(k)));
+        assert_ne!(keys(&client), keys(&controller));
+
+        // the control plane APIs are only advertised on the controller listener
+        let heartbeat = ApiKey::BrokerHeartbeat as i16;
+        assert!(!keys(&client).contains(&heartbeat));
+        assert!(keys(&controller).contains(&heartbeat));
+
+        // only APIs with a handler are advertised, on either listener
+        for key in keys(&controller) {
+            assert!(!is_unimplemented_api(key), "api key {}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broker_heartbeat() {
+        let broker = broker();
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(
+                0,
+                vec![RecordBody::RegisterBroker(RegisterBrokerRecord {
+                    broker_id: 2,
+                    is_migrating_zk_broker: false,
+                    incarnation_id: Uuid { uuid: [2; 16] },
+                    broker_epoch: 1,
+                    endpoints: vec![],
+                    features: vec![],
+                    rack: None,
+                    fenced: false,
+                    in_controlled_shutdown: false,
+                    log_dirs: vec![],
+                })],
+            )]);
+
+        let mut request = BrokerHeartbeatRequest {
+            broker_id: 2,
+            broker_epoch: 1,
+            current_metadata_offset: -1,
+            want_fence: false,
+            want_shut_down: true,
+        };
+        let header = request_header(63, 1);
+        let response = handle_broker_heartbeat(&header, &request, &broker).await;
+        assert_eq!(ErrorCode::NoError, response.error_code);
+        assert!(!response.is_caught_up);
+        assert!(!response.is_fenced);
+        assert!(response.should_shut_down);
+
+        // caught up once the only record is applied
+        request.current_metadata_offset = 0;
+        let response = handle_broker_heartbeat(&header, &request, &broker).await;
+        assert!(response.is_caught_up);
+
+        request.broker_id = 3;
+        let response = handle_broker_heartbeat(&header, &request, &broker).await;
+        assert_eq!(ErrorCode::BrokerIdNotRegistered, response.error_code);
+    }
+
+    #[tokio::test]
+    async fn test_controller_listener() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("controller-listener"));
+        let mut metadata_log = ClusterMetadataLog::new(broker.log_dir.clone());
+        metadata_log.format(MetadataVersion::default()).unwrap();
+        broker.metadata_log = RwLock::new(metadata_log);
+
+        let listener = bind("127.0.0.1:0", SOCKET_LISTEN_BACKLOG_SIZE)
+            .await
+            .unwrap();
+        let controller_listener = bind("127.0.0.1:0", SOCKET_LISTEN_BACKLOG_SIZE)
+            .await
+            .unwrap();
+        let client_addr = listener.local_addr().unwrap();
+        let controller_addr = controller_listener.local_addr().unwrap();
+        let server =
+            Server::new(listener, Arc::new(broker)).with_controller_listener(controller_listener);
+
+        // a well formed heartbeat, from a broker that never registered
+        let mut heartbeat = Vec::new();
+        heartbeat.extend(63i16.to_be_bytes());
+        heartbeat.extend(1i16.to_be_bytes());
+        heartbeat.extend(2i32.to_be_bytes());
+        heartbeat.extend((-1i16).to_be_bytes());
+        heartbeat.push(0);
+        heartbeat.extend(2i32.to_be_bytes());
+        heartbeat.extend(1i64.to_be_bytes());
+        heartbeat.extend((-1i64).to_be_bytes());
+        heartbeat.extend([0, 0, 0]);
+        let mut heartbeat_frame = (heartbeat.len() as i32).to_be_bytes().to_vec();
+        heartbeat_frame.extend(heartbeat);
+
+        // the ApiVersions response without its header, and the BrokerHeartbeat one
+        let exchange = |addr: SocketAddr| {
+            let frames = [api_versions_request(1), heartbeat_frame.clone()];
+            async move {
+                let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let mut responses = Vec::new();
+                for frame in frames {
+                    stream.write_all(&frame).await.unwrap();
+                    let mut size = [0; 4];
+                    stream.read_exact(&mut size).await.unwrap();
+                    let mut response = vec![0; i32::from_be_bytes(size) as usize];
+                    stream.read_exact(&mut response).await.unwrap();
+                    responses.push(response);
+                }
+                (responses[0][4..].to_vec(), responses[1].clone())
+            }
+        };
+
+        let request = ApiVersionsRequest {
+            client_software_name: "test".to_string(),
+            client_software_version: "1".to_string(),
+        };
+        let header = request_header(18, 4);
+        let api_versions = |listener: &str| {
+            handle_apiversions(&header, &request, &Connection::new(listener)).encode()
+        };
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let clients = async {
+            let (versions, heartbeat) = exchange(client_addr).await;
+            assert_eq!(api_versions(CLIENT_LISTENER), versions);
+            // not served, so answered as any unimplemented API is
+            assert_unsupported_api_response(&heartbeat[4..]);
+
+            let (versions, heartbeat) = exchange(controller_addr).await;
+            assert_eq!(api_versions(CONTROLLER_LISTENER), versions);
+            let error_code = i16::from_be_bytes(heartbeat[9..11].try_into().unwrap());
+            assert_eq!(ErrorCode::BrokerIdNotRegistered.code(), error_code);
+            stop.send(()).unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(
+                server.serve_until(async { stopped.await.unwrap() }),
+                clients
+            )
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_follower_fetch_reads_past_high_watermark() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("follower-fetch"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        for records in [b"a", b"b", b"c"] {
+            broker
+                .segments
+                .append(
+                    &dir,
+                    &LogConfig::default(),
+                    0,
+                    &mut segment::test::batch(0, 0, records),
+                )
+                .unwrap();
+        }
+        broker
+            .high_watermarks
+            .lock()
+            .unwrap()
+            .insert(("foo".to_string(), 0), 2);
+
+        let mut request = fetch_request(0, -1, vec![(Uuid { uuid: [1; 16] }, 0)]);
+        let consumer = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        request.replica_id = 2;
+        let follower = handle_fetch(&request_header(1, 16), &request, &broker).await;
+
+        let consumer = &consumer.responses[0].partitions[0];
+        let follower = &follower.responses[0].partitions[0];
+        assert_eq!(2, consumer.high_watermark);
+        assert_eq!(2, follower.high_watermark);
+
+        let batches = |records: &[u8]| Segment::from_bytes(records.to_vec()).batches().len();
+        assert_eq!(2, batches(&consumer.records));
+        assert_eq!(3, batches(&follower.records));
+    }
+
+    #[tokio::test]
+    async fn test_produce_advances_high_watermark() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("produce-high-watermark"));
+        let foo = Uuid { uuid: [1; 16] };
+        let bar = Uuid { uuid: [2; 16] };
+        // bar is replicated to broker 2 as well
+        let RecordBody::Partition(mut partition) = metadata_log::test::partition(&bar, 0, 1) else {
+            unreachable!()
+        };
+        partition.replicas = vec![1, 2];
+        partition.isr = vec![1, 2];
+        broker.metadata_log.write().await.set_batches(vec![
+            topic_batch("foo", &foo, 1),
+            RecordBatch::new(
+                1,
+                vec![
+                    RecordBody::Topic(TopicRecord {
+                        topic_name: "bar".to_string(),
+                        topic_uuid: bar.clone(),
+                    }),
+                    RecordBody::Partition(partition),
+                ],
+            ),
+        ]);
+
+        let records = segment::test::produced_batch(&[b"a", b"b"]);
+        for topic in ["foo", "bar"] {
+            handle_produce(
+                &request_header(0, 11),
+                &produce_request(topic, &records),
+                &broker,
+            )
+            .await;
+        }
+        let high_watermark =
+            |topic: &str| broker.high_watermarks.lock().unwrap()[&(topic.to_string(), 0)];
+        assert_eq!(2, high_watermark("foo"));
+        assert_eq!(0, high_watermark("bar"));
+
+        // committed once the follower fetches past the records
+        let mut request = fetch_request(0, -1, vec![(bar.clone(), 0)]);
+        request.replica_id = 2;
+        handle_fetch(&request_header(1, 16), &request, &broker).await;
+        assert_eq!(0, high_watermark("bar"));
+        request.topics[0].partitions[0].fetch_offset = 2;
+        handle_fetch(&request_header(1, 16), &request, &broker).await;
+        assert_eq!(2, high_watermark("bar"));
+    }
+
+    fn produce_request<'a>(topic: &str, records: &'a [u8]) -> ProduceRequest<'a> {
+        ProduceRequest {
+            transactional_id: None,
+            acks: -1,
+            timeout_ms: 1000,
+            topic_data: vec![ProduceTopicData {
+                name: topic.to_string(),
+                partition_data: vec![ProducePartitionData {
+                    index: 0,
+                    records: Some(records),
+                }],
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_produce_then_fetch() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("produce-fetch"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(
+                0,
+                vec![
+                    RecordBody::Topic(TopicRecord {
+                        topic_name: "foo".to_string(),
+                        topic_uuid: foo.clone(),
+                    }),
+                    RecordBody::Partition(PartitionRecord {
+                        partition_id: 0,
+                        topic_id: foo.clone(),
+                        replicas: vec![1],
+                        isr: vec![1],
+                        removing_replicas: vec![],
+                        adding_replicas: vec![],
+                        leader: 1,
+                        leader_epoch: 0,
+                        partition_epoch: 0,
+                        directories: vec![],
+                        leader_recovery_state: 0,
+                    }),
+                ],
+            )]);
+
+        let first = segment::test::produced_batch(&[b"a", b"b"]);
+        let second = segment::test::produced_batch(&[b"c"]);
+        async fn produce(broker: &Broker, records: &[u8]) -> ProduceResponsePartition {
+            let mut response = handle_produce(
+                &request_header(0, 11),
+                &produce_request("foo", records),
+                broker,
+            )
+            .await;
+            response.responses.remove(0).partition_responses.remove(0)
+        }
+
+        let partition = produce(&broker, &first).await;
+        assert_eq!(ErrorCode::NoError, partition.error_code);
+        assert_eq!(0, partition.base_offset);
+        assert_eq!(2, produce(&broker, &second).await.base_offset);
+
+        let request = fetch_request(0, -1, vec![(foo, 0)]);
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let records = &response.responses[0].partitions[0].records;
+        let segment = Segment::from_bytes(records.clone());
+        let batches = segment.batches();
+        assert_eq!(2, batches.len());
+        assert_eq!(first[8..], records[8..batches[0].size]);
+        assert_eq!(2, batches[1].base_offset);
+    }
+
+    #[tokio::test]
+    async fn test_produce_log_start_and_storage_error() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("produce-log-start"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 2)]);
+
+        // offsets before 3 are gone from the log
+        let path = broker.log_dir.segment_path("foo", 0, 0);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, segment::test::batch(3, 0, b"a")).unwrap();
+
+        let records = segment::test::produced_batch(&[b"b"]);
+        let produce = |partition| {
+            let mut request = produce_request("foo", &records);
+            request.topic_data[0].partition_data[0].index = partition;
+            request
+        };
+        let response = handle_produce(&request_header(0, 11), &produce(0), &broker).await;
+        let partition = &response.responses[0].partition_responses[0];
+        assert_eq!(ErrorCode::NoError, partition.error_code);
+        assert_eq!(4, partition.base_offset);
+        assert_eq!(3, partition.log_start_offset);
+
+        // a segment that can't be written fails the partition, not the connection
+        let path = broker.log_dir.segment_path("foo", 1, 0);
+        fs::create_dir_all(&path).unwrap();
+        let response = handle_produce(&request_header(0, 11), &produce(1), &broker).await;
+        let partition = &response.responses[0].partition_responses[0];
+        assert_eq!(ErrorCode::KafkaStorageError, partition.error_code);
+        assert_eq!(-1, partition.base_offset);
+    }
+
+    #[tokio::test]
+    async fn test_produce_compressed_batch() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("produce-compressed"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+
+        let records = segment::test::compressed_batch(&[b"a", b"b", b"c"]);
+        let response = handle_produce(
+            &request_header(0, 11),
+            &produce_request("foo", &records),
+            &broker,
+        )
+        .await;
+        let partition = &response.responses[0].partition_responses[0];
+        assert_eq!(ErrorCode::NoError, partition.error_code);
+        assert_eq!(0, partition.base_offset);
+
+        // stored compressed, as it was sent
+        let request = fetch_request(0, -1, vec![(foo, 0)]);
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(3, partition.high_watermark);
+        assert_eq!(records[8..], partition.records[8..]);
+    }
+
+    #[tokio::test]
+    async fn test_produce_to_unknown_partition() {
+        let broker = broker();
+        let records = segment::test::produced_batch(&[b"a"]);
+        let response = handle_produce(
+            &request_header(0, 11),
+            &produce_request("foo", &records),
+            &broker,
+        )
+        .await;
+
+        let partition = &response.responses[0].partition_responses[0];
+        assert_eq!(ErrorCode::UnknownTopicOrPartition, partition.error_code);
+        assert_eq!(-1, partition.base_offset);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_multiple_topics() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("fetch-multiple-topics"));
+        let (foo, bar) = (Uuid { uuid: [1; 16] }, Uuid { uuid: [2; 16] });
+        broker.metadata_log.write().await.set_batches(vec![
+            topic_batch("foo", &foo, 1),
+            topic_batch("bar", &bar, 1),
+        ]);
+        for (topic, records) in [("foo", b"a"), ("bar", b"b")] {
+            let dir = broker.log_dir.partition_dir(topic, 0);
+            broker
+                .segments
+                .append(
+                    &dir,
+                    &LogConfig::default(),
+                    0,
+                    &mut segment::test::batch(0, 0, records),
+                )
+                .unwrap();
+        }
+
+        // without a session, and opening one, which sorts its partitions
+        for session_epoch in [-1, 0] {
+            let request = fetch_request(0, session_epoch, vec![(bar.clone(), 0), (foo.clone(), 0)]);
+            let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+
+            let topics: Vec<&Uuid> = response.responses.iter().map(|r| &r.topic_id).collect();
+            assert_eq!(vec![&bar, &foo], topics);
+            for response in &response.responses {
+                let partition = &response.partitions[0];
+                assert_eq!(ErrorCode::NoError, partition.error_code);
+                assert_eq!(1, partition.high_watermark);
+            }
+            assert_eq!(b"b", &response.responses[0].partitions[0].records[61..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_requested_partitions() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("fetch-partitions"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 2)]);
+        for (partition, records) in [(0, &b"ab"[..]), (1, &b"c"[..])] {
+            let dir = broker.log_dir.partition_dir("foo", partition);
+            for record in records {
+                broker
+                    .segments
+                    .append(
+                        &dir,
+                        &LogConfig::default(),
+                        0,
+                        &mut segment::test::batch(0, 0, &[*record]),
+                    )
+                    .unwrap();
+            }
+        }
+
+        let mut request = fetch_request(0, -1, vec![(foo.clone(), 0), (foo.clone(), 1), (foo, 2)]);
+        request.topics[0].partitions[0].fetch_offset = 1;
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+
+        let partitions: Vec<_> = response
+            .responses
+            .iter()
+            .flat_map(|r| r.partitions.iter())
+            .map(|p| (p.partition_index, p.error_code, p.high_watermark))
+            .collect();
+        assert_eq!(
+            vec![
+                (0, ErrorCode::NoError, 2),
+                (1, ErrorCode::NoError, 1),
+                (2, ErrorCode::UnknownTopicOrPartition, 0)
+            ],
+            partitions
+        );
+        // partition 0 read from its fetch offset on
+        let records = &response.responses[0].partitions[0].records;
+        assert_eq!(1, i64::from_be_bytes(records[..8].try_into().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_partition_in_assigned_log_dir() {
+        let mut broker = broker();
+        let base = temp_dir("assigned-log-dir");
+        let (first, second) = (base.join("first"), base.join("second"));
+        fs::create_dir_all(first.join("foo-0")).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        fs::write(
+            second.join("meta.properties"),
+            "directory.id=AgICAgICAgICAgICAgICAg\n",
+        )
+        .unwrap();
+        broker.log_dir = LogDir::with_dirs([&first, &second]);
+
+        // the replica of this broker assigned to the second log dir, even
+        // though the first one has a directory for the partition
+        let foo = Uuid { uuid: [1; 16] };
+        let mut batch = topic_batch("foo", &foo, 1);
+        if let RecordBody::Partition(partition) = &mut batch.records[1].value.body {
+            partition.replicas = vec![broker.endpoint.node_id];
+            partition.directories = vec![Uuid { uuid: [2; 16] }];
+        }
+        broker.metadata_log.write().await.set_batches(vec![batch]);
+
+        let records = segment::test::produced_batch(&[b"a"]);
+        let response = handle_produce(
+            &request_header(0, 11),
+            &produce_request("foo", &records),
+            &broker,
+        )
+        .await;
+        assert_eq!(
+            ErrorCode::NoError,
+            response.responses[0].partition_responses[0].error_code
+        );
+        assert!(second.join("foo-0/00000000000000000000.log").is_file());
+        assert!(!first.join("foo-0/00000000000000000000.log").exists());
+
+        let request = fetch_request(0, -1, vec![(foo, 0)]);
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(1, partition.high_watermark);
+        assert_eq!(records[12..], partition.records[12..]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_from_offset() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("fetch-from-offset"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+
+        // offsets 5 and 6 in one batch, 7 in the next
+        let mut log = segment::test::batch(5, 1, b"ab");
+        log.extend(segment::test::batch(7, 0, b"c"));
+        let path = broker.log_dir.segment_path("foo", 0, 5);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, log).unwrap();
+
+        async fn fetch(broker: &Broker, foo: &Uuid, fetch_offset: i64) -> FetchResponsePartition {
+            let mut request = fetch_request(0, -1, vec![(foo.clone(), 0)]);
+            request.topics[0].partitions[0].fetch_offset = fetch_offset;
+            let mut response = handle_fetch(&request_header(1, 16), &request, broker).await;
+            response.responses.remove(0).partitions.remove(0)
+        }
+        let base_offsets = |records: &[u8]| -> Vec<i64> {
+            let segment = Segment::from_bytes(records.to_vec());
+            segment.batches().iter().map(|b| b.base_offset).collect()
+        };
+
+        let partition = fetch(&broker, &foo, 7).await;
+        assert_eq!(ErrorCode::NoError, partition.error_code);
+        assert_eq!(vec![7], base_offsets(&partition.records));
+        assert_eq!(5, partition.log_start_offset);
+        assert_eq!(8, partition.high_watermark);
+
+        // the whole batch holding the offset, clients skip what they've seen
+        assert_eq!(
+            vec![5, 7],
+            base_offsets(&fetch(&broker, &foo, 6).await.records)
+        );
+
+        for fetch_offset in [4, 9] {
+            let partition = fetch(&broker, &foo, fetch_offset).await;
+            assert_eq!(ErrorCode::OffsetOutOfRange, partition.error_code);
+            assert!(partition.records.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_max_bytes() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("fetch-max-bytes"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 2)]);
+        for partition in [0, 1] {
+            let dir = broker.log_dir.partition_dir("foo", partition);
+            for record in b"ab" {
+                broker
+                    .segments
+                    .append(
+                        &dir,
+                        &LogConfig::default(),
+                        0,
+                        &mut segment::test::batch(0, 0, &[*record]),
+                    )
+                    .unwrap();
+            }
+        }
+        let batch_len = segment::test::batch(0, 0, b"a").len();
+
+        let cases = [
+            // whole batches only, and no room left for the second partition
+            (batch_len + 1, vec![batch_len, 0]),
+            (2 * batch_len + 1, vec![2 * batch_len, 0]),
+            (3 * batch_len, vec![2 * batch_len, batch_len]),
+            // the first batch goes out even when it's larger than max_bytes
+            (0, vec![batch_len, 0]),
+        ];
+        for (max_bytes, sizes) in cases {
+            let mut request = fetch_request(0, -1, vec![(foo.clone(), 0), (foo.clone(), 1)]);
+            request.max_bytes = max_bytes as i32;
+            let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+            let partitions = &response.responses[0].partitions;
+            let records: Vec<usize> = partitions.iter().map(|p| p.records.len()).collect();
+            assert_eq!(sizes, records);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_partition_and_response_max_bytes() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("fetch-two-level-max-bytes"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 4)]);
+        for partition in 0..4 {
+            let dir = broker.log_dir.partition_dir("foo", partition);
+            for record in b"ab" {
+                broker
+                    .segments
+                    .append(
+                        &dir,
+                        &LogConfig::default(),
+                        0,
+                        &mut segment::test::batch(0, 0, &[*record]),
+                    )
+                    .unwrap();
+            }
+        }
+        let batch_len = segment::test::batch(0, 0, b"a").len();
+
+        // one batch per partition, and room for two of them overall
+        let partitions = (0..4).map(|p| (foo.clone(), p)).collect();
+        let mut request = fetch_request(0, -1, partitions);
+        request.max_bytes = (3 * batch_len - 1) as i32;
+        for topic in &mut request.topics {
+            topic.partitions[0].partition_max_bytes = (batch_len + 1) as i32;
+        }
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+
+        let sizes: Vec<usize> = response.responses[0]
+            .partitions
+            .iter()
+            .map(|p| p.records.len())
+            .collect();
+        assert_eq!(vec![batch_len, batch_len, 0, 0], sizes);
+        assert!(sizes.iter().sum::<usize>() <= request.max_bytes as usize);
+    }
+
+    #[test]
+    fn test_blocking_io_leaves_other_tasks_running() {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // the only worker waits on a task that can only run if it's handed off
+        let received = runtime.block_on(async {
+            let (sender, receiver) = mpsc::channel();
+            let blocked = tokio::spawn(async move {
+                blocking_io(|| receiver.recv_timeout(Duration::from_secs(5)))
+            });
+            tokio::spawn(async move { sender.send(()).unwrap() });
+            blocked.await.unwrap()
+        });
+        assert_eq!(Ok(()), received);
+
+        // without workers to hand off to, the I/O just runs
+        assert_eq!(1, blocking_io(|| 1));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_waits_for_min_bytes() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("fetch-min-bytes"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+
+        let mut request = fetch_request(0, -1, vec![(foo.clone(), 0)]);
+        request.max_wait_ms = 100;
+        let start = Instant::now();
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(response.responses[0].partitions[0].records.is_empty());
+
+        // records showing up while waiting end the wait early
+        request.max_wait_ms = 10_000;
+        let start = Instant::now();
+        let append = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let dir = broker.log_dir.partition_dir("foo", 0);
+            broker
+                .segments
+                .append(
+                    &dir,
+                    &LogConfig::default(),
+                    0,
+                    &mut segment::test::batch(0, 0, b"a"),
+                )
+                .unwrap();
+        };
+        let header = request_header(1, 16);
+        let fetch = handle_fetch(&header, &request, &broker);
+        let (_, response) = tokio::join!(append, fetch);
+        assert!(start.elapsed() < Duration::from_millis(10_000));
+        assert_eq!(b"a", &response.responses[0].partitions[0].records[61..]);
+    }
+
+    // a Fetch v16 frame for partition 0 of the topic, waiting up to max_wait_ms
+    // for a byte
+    fn fetch_request_frame(correlation_id: i32, topic_id: &Uuid, max_wait_ms: i32) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend(1i16.to_be_bytes());
+        msg.extend(16i16.to_be_bytes());
+        msg.extend(correlation_id.to_be_bytes());
+        msg.extend((-1i16).to_be_bytes());
+        msg.push(0);
+        msg.extend(max_wait_ms.to_be_bytes());
+        msg.extend(1i32.to_be_bytes());
+        msg.extend(1048576i32.to_be_bytes());
+        msg.push(0);
+        msg.extend(0i32.to_be_bytes());
+        msg.extend((-1i32).to_be_bytes());
+        msg.push(2);
+        msg.extend(topic_id.uuid);
+        msg.push(2);
+        msg.extend(0i32.to_be_bytes());
+        msg.extend((-1i32).to_be_bytes());
+        msg.extend(0i64.to_be_bytes());
+        msg.extend((-1i32).to_be_bytes());
+        msg.extend((-1i64).to_be_bytes());
+        msg.extend(1048576i32.to_be_bytes());
+        msg.extend([0, 0, 1, 1, 0]);
+
+        let mut frame = (msg.len() as i32).to_be_bytes().to_vec();
+        frame.extend(msg);
+        frame
+    }
+
+    #[test]
+    fn test_fetch_wait_does_not_hold_up_other_connections() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("fetch-wait-connections"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .get_mut()
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+        // the broker runs on a single thread, so the waiting fetch has to yield
+        let addr = spawn_broker(broker, Duration::from_secs(5));
+
+        let mut waiting = TcpStream::connect(addr).unwrap();
+        let start = Instant::now();
+        waiting
+            .write_all(&fetch_request_frame(1, &foo, 1000))
+            .unwrap();
+
+        let mut other = TcpStream::connect(addr).unwrap();
+        other.write_all(&api_versions_request(2)).unwrap();
+        let response = read_response(&mut other);
+        assert_eq!(2, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+        assert!(start.elapsed() < Duration::from_millis(1000));
+
+        let response = read_response(&mut waiting);
+        assert_eq!(1, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+        assert!(start.elapsed() >= Duration::from_millis(1000));
+    }
+
+    #[tokio::test]
+    async fn test_elect_leaders_not_needed() {
+        let broker = broker();
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+
+        let request = ElectLeadersRequest {
+            election_type: 0,
+            topic_partitions: Some(vec![
+                ElectLeadersTopic {
+                    topic: "foo".to_string(),
+                    partitions: vec![0, 1],
+                },
+                ElectLeadersTopic {
+                    topic: "bar".to_string(),
+                    partitions: vec![0],
+                },
+            ]),
+            timeout_ms: 1000,
+        };
+        let response = handle_elect_leaders(&request_header(43, 2), &request, &broker).await;
+
+        let results: Vec<(&str, i32, ErrorCode)> = response
+            .replica_election_results
+            .iter()
+            .flat_map(|topic| {
+                topic
+                    .partition_result
+                    .iter()
+                    .map(|p| (topic.topic.as_str(), p.partition_id, p.error_code))
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("foo", 0, ErrorCode::ElectionNotNeeded),
+                ("foo", 1, ErrorCode::UnknownTopicOrPartition),
+                ("bar", 0, ErrorCode::UnknownTopicOrPartition),
+            ],
+            results
+        );
+
+        // every partition when none are named
+        let request = ElectLeadersRequest {
+            topic_partitions: None,
+            ..request
+        };
+        let response = handle_elect_leaders(&request_header(43, 2), &request, &broker).await;
+        assert_eq!(1, response.replica_election_results.len());
+        assert_eq!(
+            1,
+            response.replica_election_results[0].partition_result.len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_caught_up_fetch_returns_empty_records() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("caught-up-fetch"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        for records in [b"a", b"b"] {
+            broker
+                .segments
+                .append(
+                    &dir,
+                    &LogConfig::default(),
+                    0,
+                    &mut segment::test::batch(0, 0, records),
+                )
+                .unwrap();
+        }
+
+        let mut request = fetch_request(0, -1, vec![(Uuid { uuid: [1; 16] }, 0)]);
+        request.topics[0].partitions[0].fetch_offset = 2;
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(ErrorCode::NoError, partition.error_code);
+        assert_eq!(2, partition.high_watermark);
+        // empty records, not null, followed by the partition's tag buffer
+        assert_eq!([1, 0], partition.encode()[35..]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_session_forgets_partition() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("forgotten-topics"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 2)]);
+
+        let header = request_header(1, 16);
+        let request = fetch_request(0, 0, vec![(foo.clone(), 0), (foo.clone(), 1)]);
+        let response = handle_fetch(&header, &request, &broker).await;
+        assert_ne!(0, response.session_id);
+        assert_eq!(2, response.responses[0].partitions.len());
+
+        let mut request = fetch_request(response.session_id, 1, vec![]);
+        request.forgotten_topics_data = vec![ForgottenTopicsData {
+            topic_id: foo.clone(),
+            partitions: vec![1],
+        }];
+        let response = handle_fetch(&header, &request, &broker).await;
+        assert_eq!(1, response.responses[0].partitions.len());
+        assert_eq!(0, response.responses[0].partitions[0].partition_index);
+
+        // new data on the forgotten partition doesn't bring it back
+        let dir = broker.log_dir.partition_dir("foo", 1);
+        broker
+            .segments
+            .append(
+                &dir,
+                &LogConfig::default(),
+                0,
+                &mut segment::test::batch(0, 0, b"a"),
+            )
+            .unwrap();
+        let request = fetch_request(response.session_id, 2, vec![]);
+        let response = handle_fetch(&header, &request, &broker).await;
+        assert_eq!(1, response.responses[0].partitions.len());
+        assert_eq!(0, response.responses[0].partitions[0].partition_index);
+    }
+
+    #[test]
+    fn test_shutdown_closes_idle_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let shutdown = Shutdown::new();
+        let handler = {
+            let broker = Arc::new(broker());
+            let shutdown = shutdown.clone();
+            spawn_runtime(async move {
+                let connection = Connection::new(CLIENT_LISTENER);
+                handle_stream(
+                    tokio_stream(stream),
+                    broker,
+                    connection,
+                    Duration::from_secs(60),
+                    shutdown,
+                )
+                .await
+            })
+        };
+
+        client.write_all(&api_versions_request(1)).unwrap();
+        read_response(&mut client);
+
+        // the handler is now blocked waiting for a request that never comes
+        let started = Instant::now();
+        shutdown.trigger();
+        handler.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut buf = [0; 1];
+        assert_eq!(0, client.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_handler_with_small_stack() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let runtime = handler_runtime(256 * 1024).unwrap();
+        let handler = {
+            let _guard = runtime.enter();
+            runtime.spawn(handle_stream(
+                tokio_stream(stream),
+                Arc::new(broker()),
+                Connection::new(CLIENT_LISTENER),
+                Duration::from_secs(5),
+                Shutdown::new(),
+            ))
+        };
+
+        client.write_all(&api_versions_request(3)).unwrap();
+        let response = read_response(&mut client);
+        assert_eq!(3, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+        assert_eq!(0, i16::from_be_bytes(response[4..6].try_into().unwrap()));
+
+        drop(client);
+        runtime.block_on(handler).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_interrupts_fetch_wait() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("shutdown-fetch-wait"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .get_mut()
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let shutdown = Shutdown::new();
+        let handler = {
+            let broker = Arc::new(broker);
+            let shutdown = shutdown.clone();
+            spawn_runtime(async move {
+                let connection = Connection::new(CLIENT_LISTENER);
+                handle_stream(
+                    tokio_stream(stream),
+                    broker,
+                    connection,
+                    Duration::from_secs(60),
+                    shutdown,
+                )
+                .await
+            })
+        };
+
+        // an empty partition, so the fetch waits out its whole max_wait_ms
+        client
+            .write_all(&fetch_request_frame(1, &foo, 60_000))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        shutdown.trigger();
+        handler.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut buf = [0; 1];
+        assert_eq!(0, client.read(&mut buf).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_log_is_storage_error() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("unreadable-log"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+        // a directory where the segment should be, which can't be read as one
+        fs::create_dir_all(broker.log_dir.segment_path("foo", 0, 0)).unwrap();
+
+        let request = fetch_request(0, -1, vec![(foo, 0)]);
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(ErrorCode::KafkaStorageError, partition.error_code);
+        assert!(partition.records.is_empty());
+
+        let request = ListOffsetsRequest {
+            replica_id: -1,
+            isolation_level: 0,
+            topics: vec![ListOffsetsRequestTopic {
+                name: "foo".to_string(),
+                partitions: vec![ListOffsetsRequestPartition {
+                    partition_index: 0,
+                    current_leader_epoch: -1,
+                    timestamp: LATEST_TIMESTAMP,
+                }],
+            }],
+        };
+        let response = handle_list_offsets(&request_header(2, 7), &request, &broker).await;
+        let partition = &response.topics[0].partitions[0];
+        assert_eq!(ErrorCode::KafkaStorageError, partition.error_code);
+        assert_eq!(-1, partition.offset);
+    }
+
+    #[tokio::test]
+    async fn test_list_offsets_leader_epoch() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("list-offsets"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        // offsets 0 and 1 written in epoch 3 at time 100, 2 and 3 in epoch 5 at time 200
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        for (leader_epoch, timestamp) in [(3i32, 100i64), (5, 200)] {
+            let mut batch = segment::test::batch(0, 1, b"ab");
+            batch[35..43].copy_from_slice(&timestamp.to_be_bytes());
+            broker
+                .segments
+                .append(&dir, &LogConfig::default(), leader_epoch, &mut batch)
+                .unwrap();
+        }
+
+        let request = ListOffsetsRequest {
+            replica_id: -1,
+            isolation_level: 0,
+            topics: vec![ListOffsetsRequestTopic {
+                name: "foo".to_string(),
+                partitions: [EARLIEST_TIMESTAMP, 150, LATEST_TIMESTAMP, 300]
+                    .into_iter()
+                    .map(|timestamp| ListOffsetsRequestPartition {
+                        partition_index: 0,
+                        current_leader_epoch: -1,
+                        timestamp,
+                    })
+                    .collect(),
+            }],
+        };
+        let response = handle_list_offsets(&request_header(2, 7), &request, &broker).await;
+
+        let found: Vec<(i64, i32)> = response.topics[0]
+            .partitions
+            .iter()
+            .map(|p| (p.offset, p.leader_epoch))
+            .collect();
+        assert_eq!(vec![(0, 3), (2, 5), (4, 5), (-1, -1)], found);
+        assert_eq!(200, response.topics[0].partitions[1].timestamp);
+    }
+
+    #[tokio::test]
+    async fn test_produce_stamps_leader_epoch() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("produce-leader-epoch"));
+        let foo = Uuid { uuid: [1; 16] };
+        let mut partition = metadata_log::test::partition(&foo, 0, 1);
+        if let RecordBody::Partition(partition) = &mut partition {
+            partition.leader_epoch = 7;
+        }
+        let topic = RecordBody::Topic(TopicRecord {
+            topic_name: "foo".to_string(),
+            topic_uuid: foo,
+        });
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(0, vec![topic, partition])]);
+
+        // whatever epoch the producer left in the batch
+        let records = segment::test::produced_batch(&[b"a"]);
+        let response = handle_produce(
+            &request_header(0, 11),
+            &produce_request("foo", &records),
+            &broker,
+        )
+        .await;
+        let partition = &response.responses[0].partition_responses[0];
+        assert_eq!(ErrorCode::NoError, partition.error_code);
+
+        let request = ListOffsetsRequest {
+            replica_id: -1,
+            isolation_level: 0,
+            topics: vec![ListOffsetsRequestTopic {
+                name: "foo".to_string(),
+                partitions: [EARLIEST_TIMESTAMP, LATEST_TIMESTAMP]
+                    .into_iter()
+                    .map(|timestamp| ListOffsetsRequestPartition {
+                        partition_index: 0,
+                        current_leader_epoch: -1,
+                        timestamp,
+                    })
+                    .collect(),
+            }],
+        };
+        let response = handle_list_offsets(&request_header(2, 7), &request, &broker).await;
+        let found: Vec<(i64, i32)> = response.topics[0]
+            .partitions
+            .iter()
+            .map(|p| (p.offset, p.leader_epoch))
+            .collect();
+        assert_eq!(vec![(0, 7), (1, 7)], found);
+    }
+
+    // topic foo with a single partition and the given config overrides
+    fn configured_topic_batch(foo: &Uuid, configs: &[(&str, &str)]) -> RecordBatch {
+        let mut records = vec![
+            RecordBody::Topic(TopicRecord {
+                topic_name: "foo".to_string(),
+                topic_uuid: foo.clone(),
+            }),
+            metadata_log::test::partition(foo, 0, 1),
+        ];
+        records.extend(configs.iter().map(|(name, value)| {
+            RecordBody::Config(ConfigRecord {
+                resource_type: TOPIC_RESOURCE,
+                resource_name: "foo".to_string(),
+                name: name.to_string(),
+                value: Some(value.to_string()),
+            })
+        }));
+        RecordBatch::new(0, records)
+    }
+
+    #[tokio::test]
+    async fn test_produce_rolls_segments() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("produce-roll"));
+        let foo = Uuid { uuid: [1; 16] };
+        // any batch fills a segment
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![configured_topic_batch(&foo, &[(SEGMENT_BYTES, "1")])]);
+
+        let records = segment::test::produced_batch(&[b"a"]);
+        for expected in 0..3 {
+            let response = handle_produce(
+                &request_header(0, 11),
+                &produce_request("foo", &records),
+                &broker,
+            )
+            .await;
+            assert_eq!(
+                expected,
+                response.responses[0].partition_responses[0].base_offset
+            );
+        }
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        assert_eq!(3, segment_paths(&dir).unwrap().len());
+
+        // fetches carry on from one segment into the next
+        let request = fetch_request(0, -1, vec![(foo, 0)]);
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let records = &response.responses[0].partitions[0].records;
+        let offsets: Vec<i64> = Segment::from_bytes(records.clone())
+            .batches()
+            .iter()
+            .map(|b| b.base_offset)
+            .collect();
+        assert_eq!(vec![0, 1, 2], offsets);
+    }
+
+    #[tokio::test]
+    async fn test_retention_deletes_expired_segments() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("retention"));
+        let foo = Uuid { uuid: [1; 16] };
+        let configs = [(SEGMENT_BYTES, "1"), (RETENTION_MS, "1000")];
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![configured_topic_batch(&foo, &configs)]);
+        let config = LogConfig::for_topic(&*broker.metadata_log.read().await, "foo");
+
+        // a segment per batch, with only the first two expired by time 10000.
+        // The third isn't, so the one after it is kept too, as is the last
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        for timestamp in [100i64, 200, 9500, 300, 400] {
+            let mut batch = segment::test::batch(0, 0, b"a");
+            batch[35..43].copy_from_slice(&timestamp.to_be_bytes());
+            broker
+                .segments
+                .append(&dir, &config, 0, &mut batch)
+                .unwrap();
+        }
+        assert_eq!(5, segment_paths(&dir).unwrap().len());
+
+        delete_expired_segments(&broker, &*broker.metadata_log.read().await, 10_000);
+        let remaining: Vec<PathBuf> = [2, 3, 4]
+            .into_iter()
+            .map(|base_offset| broker.log_dir.segment_path("foo", 0, base_offset))
+            .collect();
+        assert_eq!(remaining, segment_paths(&dir).unwrap());
+
+        // the log now starts at the first batch kept
+        let request = ListOffsetsRequest {
+            replica_id: -1,
+            isolation_level: 0,
+            topics: vec![ListOffsetsRequestTopic {
+                name: "foo".to_string(),
+                partitions: vec![ListOffsetsRequestPartition {
+                    partition_index: 0,
+                    current_leader_epoch: -1,
+                    timestamp: EARLIEST_TIMESTAMP,
+                }],
+            }],
+        };
+        let response = handle_list_offsets(&request_header(2, 7), &request, &broker).await;
+        assert_eq!(2, response.topics[0].partitions[0].offset);
+
+        // nothing else expires until the third batch does
+        delete_expired_segments(&broker, &*broker.metadata_log.read().await, 10_000);
+        assert_eq!(3, segment_paths(&dir).unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_list_offsets_unknown_partition() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("list-offsets-unknown"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+        // a log left behind for a partition the topic doesn't have
+        let path = broker.log_dir.segment_path("foo", 1, 0);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, segment::test::batch(0, 0, b"a")).unwrap();
+
+        let request = ListOffsetsRequest {
+            replica_id: -1,
+            isolation_level: 0,
+            topics: vec![ListOffsetsRequestTopic {
+                name: "foo".to_string(),
+                partitions: [0, 1]
+                    .into_iter()
+                    .map(|partition_index| ListOffsetsRequestPartition {
+                        partition_index,
+                        current_leader_epoch: -1,
+                        timestamp: LATEST_TIMESTAMP,
+                    })
+                    .collect(),
+            }],
+        };
+        let response = handle_list_offsets(&request_header(2, 7), &request, &broker).await;
+        let found: Vec<(ErrorCode, i64)> = response.topics[0]
+            .partitions
+            .iter()
+            .map(|p| (p.error_code, p.offset))
+            .collect();
+        assert_eq!(
+            vec![
+                (ErrorCode::NoError, 0),
+                (ErrorCode::UnknownTopicOrPartition, -1)
+            ],
+            found
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_offsets_earliest_and_latest() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("list-offsets-latest"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        // three records, the last in a segment of its own
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        broker
+            .segments
+            .append(
+                &dir,
+                &LogConfig::default(),
+                0,
+                &mut segment::test::batch(0, 1, b"ab"),
+            )
+            .unwrap();
+        let second = broker.log_dir.segment_path("foo", 0, 2);
+        fs::write(second, segment::test::batch(2, 0, b"c")).unwrap();
+
+        let request = ListOffsetsRequest {
+            replica_id: -1,
+            isolation_level: 0,
+            topics: vec![ListOffsetsRequestTopic {
+                name: "foo".to_string(),
+                partitions: [EARLIEST_TIMESTAMP, LATEST_TIMESTAMP]
+                    .into_iter()
+                    .map(|timestamp| ListOffsetsRequestPartition {
+                        partition_index: 0,
+                        current_leader_epoch: -1,
+                        timestamp,
+                    })
+                    .collect(),
+            }],
+        };
+        async fn offsets(broker: &Broker, request: &ListOffsetsRequest) -> Vec<i64> {
+            let response = handle_list_offsets(&request_header(2, 7), request, broker).await;
+            response.topics[0]
+                .partitions
+                .iter()
+                .map(|p| p.offset)
+                .collect()
+        }
+        assert_eq!(vec![0, 3], offsets(&broker, &request).await);
+
+        // nothing past the high watermark is listed
+        broker
+            .high_watermarks
+            .lock()
+            .unwrap()
+            .insert(("foo".to_string(), 0), 2);
+        assert_eq!(vec![0, 2], offsets(&broker, &request).await);
+    }
+
+    #[tokio::test]
+    async fn test_rebind_after_close() {
+        let listener = bind("127.0.0.1:0", SOCKET_LISTEN_BACKLOG_SIZE)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        // closing on the broker side first leaves the connection in TIME_WAIT
+        drop(stream);
+        drop(client);
+        drop(listener);
+
+        bind(&addr.to_string(), SOCKET_LISTEN_BACKLOG_SIZE)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reload_metadata_only_locks_for_new_records() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("reload-metadata-lock"));
+        let mut metadata_log = ClusterMetadataLog::new(broker.log_dir.clone());
+        metadata_log.format(MetadataVersion::default()).unwrap();
+        broker.metadata_log = RwLock::new(metadata_log);
+        let broker = Arc::new(broker);
+
+        // a request holding the metadata doesn't keep a reload with nothing
+        // to read from finishing
+        let request = broker.metadata_log.read().await;
+        tokio::time::timeout(Duration::from_secs(5), reload_metadata(&broker))
+            .await
+            .expect("reload waited on the read lock");
+        drop(request);
+
+        let foo = Uuid { uuid: [1; 16] };
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(broker.log_dir.metadata_log_path())
+            .unwrap();
+        file.write_all(&topic_batch("foo", &foo, 1).encode())
+            .unwrap();
+        drop(file);
+        reload_metadata(&broker).await;
+        assert!(broker
+            .metadata_log
+            .read()
+            .await
+            .topic_by_name("foo")
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_server_shutdown() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("server-shutdown"));
+        let mut metadata_log = ClusterMetadataLog::new(broker.log_dir.clone());
+        metadata_log.format(MetadataVersion::default()).unwrap();
+        broker.metadata_log = RwLock::new(metadata_log);
+
+        let listener = bind("127.0.0.1:0", SOCKET_LISTEN_BACKLOG_SIZE)
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(listener, Arc::new(broker));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+
+        let client = async {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(&api_versions_request(1)).await.unwrap();
+            let mut size = [0; 4];
+            client.read_exact(&mut size).await.unwrap();
+
+            // standing in for the shutdown signals
+            stop.send(()).unwrap();
+
+            // the response is there to read, then the connection is closed
+            let mut response = vec![0; i32::from_be_bytes(size) as usize];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(1, i32::from_be_bytes(response[0..4].try_into().unwrap()));
+            let mut buf = [0; 1];
+            assert_eq!(0, client.read(&mut buf).await.unwrap());
+        };
+        let stopped = async {
+            stopped.await.unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(server.serve_until(stopped), client)
+        })
+        .await
+        .unwrap();
+
+        // shutting down again, or before serving, returns right away
+        server.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), server.serve())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_reloads_metadata() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("server-reload"));
+        let mut metadata_log = ClusterMetadataLog::new(broker.log_dir.clone());
+        metadata_log.format(MetadataVersion::default()).unwrap();
+        broker.metadata_log = RwLock::new(metadata_log);
+        let broker = Arc::new(broker);
+        let path = broker.log_dir.metadata_log_path();
+
+        let listener = bind("127.0.0.1:0", SOCKET_LISTEN_BACKLOG_SIZE)
+            .await
+            .unwrap();
+        let server = Server::new(listener, Arc::clone(&broker));
+        let changes = async {
+            // a topic the controller appends shows up without any connection
+            let foo = Uuid { uuid: [1; 16] };
+            let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&topic_batch("foo", &foo, 1).encode())
+                .unwrap();
+            drop(file);
+            while broker
+                .metadata_log
+                .read()
+                .await
+                .topic_by_name("foo")
+                .is_none()
+            {
+                tokio::time::sleep(METADATA_RELOAD_INTERVAL / 10).await;
+            }
+
+            // and once the log can't be read, what was loaded is still served
+            fs::remove_file(&path).unwrap();
+            fs::create_dir(&path).unwrap();
+            tokio::time::sleep(METADATA_RELOAD_INTERVAL * 3).await;
+            assert!(broker
+                .metadata_log
+                .read()
+                .await
+                .topic_by_name("foo")
+                .is_some());
+            server.shutdown();
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::join!(server.serve(), changes)
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_panic_report_names_connection() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&reports);
+        install_panic_hook(move |report| log.lock().unwrap().push(report));
+
+        let peer = "127.0.0.1:4321".parse().unwrap();
+        let handler = spawn_runtime(handler_task(peer, async {
+            set_current_request(&request_header(1, 16));
+            panic!("boom");
+        }));
+        assert!(handler.join().is_err());
+
+        let reports = reports.lock().unwrap();
+        let report = reports
+            .iter()
+            .find(|report| report.contains("connection-127.0.0.1:4321"))
+            .unwrap();
+        assert!(report.contains("api key 1 correlation id 1"));
+        assert!(report.ends_with("boom"));
+    }
+
+    #[test]
+    fn test_client_reset_before_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let handler = spawn_runtime(async move {
+            let connection = Connection::new(CLIENT_LISTENER);
+            handle_stream(
+                tokio_stream(stream),
+                Arc::new(broker()),
+                connection,
+                Duration::from_secs(5),
+                Shutdown::new(),
+            )
+            .await
+        });
+
+        // closing with responses left unread resets the connection, so the
+        // writes for the remaining requests fail
+        let mut client = client;
+        for correlation_id in 0..100 {
+            client
+                .write_all(&api_versions_request(correlation_id))
+                .unwrap();
+        }
+        drop(client);
+
+        assert!(handler.join().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_to_closed_stream() {
+        struct Closed;
+
+        impl AsyncWrite for Closed {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                _: &[u8],
+            ) -> Poll<std::io::Result<usize>> {
+                Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let header = request_header(18, 4);
+        let response = error_response(&header, ErrorCode::NoError).unwrap();
+        let err = send(&mut Closed, &header, &response).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::BrokenPipe, err.kind());
+    }
+
+    #[tokio::test]
+    async fn test_read_committed_fetch_lists_aborted_transactions() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("aborted-transactions"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        // producer 7 writes offsets 0 and 1 in a transaction it then aborts,
+        // producer 8 commits offset 3
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        for (producer_id, last_offset_delta, commit) in [(7i64, 1, false), (8, 0, true)] {
+            let mut batch = segment::test::batch(0, last_offset_delta, b"ab");
+            batch[21..23].copy_from_slice(&0x10i16.to_be_bytes());
+            batch[43..51].copy_from_slice(&producer_id.to_be_bytes());
+            broker
+                .segments
+                .append(&dir, &LogConfig::default(), 0, &mut batch)
+                .unwrap();
+
+            let mut marker = control_batch(producer_id, 0, commit);
+            broker
+                .segments
+                .append(&dir, &LogConfig::default(), 0, &mut marker)
+                .unwrap();
+        }
+
+        let mut request = fetch_request(0, -1, vec![(Uuid { uuid: [1; 16] }, 0)]);
+        request.isolation_level = READ_COMMITTED;
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        assert_eq!(
+            vec![AbortedTransaction {
+                producer_id: 7,
+                first_offset: 0
+            }],
+            response.responses[0].partitions[0].aborted_transactions
+        );
+
+        request.isolation_level = 0;
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        assert!(response.responses[0].partitions[0]
+            .aborted_transactions
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_committed_fetch_after_abort() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("fetch-after-abort"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        // producer 7 aborts offset 0, then commits offset 2
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        for commit in [false, true] {
+            let mut batch = segment::test::batch(0, 0, b"ab");
+            batch[21..23].copy_from_slice(&0x10i16.to_be_bytes());
+            batch[43..51].copy_from_slice(&7i64.to_be_bytes());
+            broker
+                .segments
+                .append(&dir, &LogConfig::default(), 0, &mut batch)
+                .unwrap();
+
+            let mut marker = control_batch(7, 0, commit);
+            broker
+                .segments
+                .append(&dir, &LogConfig::default(), 0, &mut marker)
+                .unwrap();
+        }
+
+        // fetching from past the abort, the committed transaction isn't
+        // reported as aborted and is delivered
+        let mut request = fetch_request(0, -1, vec![(Uuid { uuid: [1; 16] }, 0)]);
+        request.isolation_level = READ_COMMITTED;
+        request.topics[0].partitions[0].fetch_offset = 2;
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let partition = &response.responses[0].partitions[0];
+        assert!(partition.aborted_transactions.is_empty());
+        let batches = Segment::from_bytes(partition.records.clone());
+        assert_eq!(
+            vec![2, 3],
+            batches
+                .batches()
+                .iter()
+                .map(|b| b.base_offset)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aborted_transactional_produce() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("transactional-produce"));
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &foo, 1)]);
+
+        let add = AddPartitionsToTxnRequest {
+            transactional_id: "txn".to_string(),
+            producer_id: 7,
+            producer_epoch: 0,
+            topics: vec![AddPartitionsToTxnTopic {
+                name: "foo".to_string(),
+                partitions: vec![0],
+            }],
+        };
+        handle_add_partitions_to_txn(&request_header(24, 3), &add, &broker.transactions);
+
+        // producer 7's batch, without the transactional bit set
+        let mut records = segment::test::produced_batch(&[b"a", b"b"]);
+        records[43..51].copy_from_slice(&7i64.to_be_bytes());
+        records[51..53].copy_from_slice(&0i16.to_be_bytes());
+        let crc = crc32c(&records[21..]);
+        records[17..21].copy_from_slice(&crc.to_be_bytes());
+        let mut request = produce_request("foo", &records);
+        request.transactional_id = Some("txn".to_string());
+        let response = handle_produce(&request_header(0, 11), &request, &broker).await;
+        assert_eq!(
+            ErrorCode::NoError,
+            response.responses[0].partition_responses[0].error_code
+        );
+
+        let end = EndTxnRequest {
+            transactional_id: "txn".to_string(),
+            producer_id: 7,
+            producer_epoch: 0,
+            committed: false,
+        };
+        handle_end_txn(&request_header(26, 3), &end, &broker).await;
+
+        let mut request = fetch_request(0, -1, vec![(foo, 0)]);
+        request.isolation_level = READ_COMMITTED;
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(
+            vec![AbortedTransaction {
+                producer_id: 7,
+                first_offset: 0
+            }],
+            partition.aborted_transactions
+        );
+        let stored = Segment::from_bytes(partition.records.clone());
+        assert_eq!(0x10, stored.batches()[0].attributes & 0x10);
+    }
+
+    #[tokio::test]
+    async fn test_last_stable_offset_behind_open_transaction() {
+        let mut broker = broker();
+        broker.log_dir = LogDir::new(temp_dir("last-stable-offset"));
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![topic_batch("foo", &Uuid { uuid: [1; 16] }, 1)]);
+
+        // producer 8 commits offset 0, producer 7 leaves offsets 2 and 3
+        // in an open transaction, and offset 4 isn't transactional
+        let path = broker.log_dir.segment_path("foo", 0, 0);
+        let dir = broker.log_dir.partition_dir("foo", 0);
+        for (producer_id, last_offset_delta, ended) in [(8i64, 0, true), (7, 1, false)] {
+            let mut batch = segment::test::batch(0, last_offset_delta, b"ab");
+            batch[21..23].copy_from_slice(&0x10i16.to_be_bytes());
+            batch[43..51].copy_from_slice(&producer_id.to_be_bytes());
+            broker
+                .segments
+                .append(&dir, &LogConfig::default(), 0, &mut batch)
+                .unwrap();
+
+            if ended {
+                let mut marker = control_batch(producer_id, 0, true);
+                broker
+                    .segments
+                    .append(&dir, &LogConfig::default(), 0, &mut marker)
+                    .unwrap();
+            }
+        }
+        let committed_size = Segment::open(&path).unwrap().read_range(0, 2).len();
+        broker
+            .segments
+            .append(
+                &dir,
+                &LogConfig::default(),
+                0,
+                &mut segment::test::batch(0, 0, b"c"),
+            )
+            .unwrap();
+
+        let mut request = fetch_request(0, -1, vec![(Uuid { uuid: [1; 16] }, 0)]);
+        request.isolation_level = READ_COMMITTED;
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(5, partition.high_watermark);
+        assert_eq!(2, partition.last_stable_offset);
+        assert_eq!(committed_size, partition.records.len());
+
+        request.isolation_level = 0;
+        let response = handle_fetch(&request_header(1, 16), &request, &broker).await;
+        let partition = &response.responses[0].partitions[0];
+        assert_eq!(2, partition.last_stable_offset);
+        assert_eq!(
+            Segment::open(&path).unwrap().read_from(0).len(),
+            partition.records.len()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_describes() {
+        let broker = Arc::new(broker());
+        let foo = Uuid { uuid: [1; 16] };
+        broker
+            .metadata_log
+            .write()
+            .await
+            .set_batches(vec![RecordBatch::new(
+                0,
+                vec![RecordBody::Topic(TopicRecord {
+                    topic_name: "foo".to_string(),
+                    topic_uuid: foo,
+                })],
+            )]);
+
+        // a reader holding on to the log doesn't keep describes out of it
+        let held = broker.metadata_log.read().await;
+        let mut describes = JoinSet::new();
+        for _ in 0..8 {
+            let broker = Arc::clone(&broker);
+            describes.spawn(async move {
+                let request = DescribeTopicPartitionsRequest {
+                    topics: vec![Some("foo".to_string())],
+                    response_partition_limit: 100,
+                    cursor: None,
+                };
+                let response = handle_describe_topic_partitions(
+                    &request_header(75, 0),
+                    &request,
+                    &broker.metadata_log,
+                    broker.partition_size_limit,
+                )
+                .await;
+                response.topics[0].error_code
+            });
+        }
+
+        for _ in 0..8 {
+            let error_code = tokio::time::timeout(Duration::from_secs(5), describes.join_next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(ErrorCode::NoError, error_code);
         }
+        drop(held);
     }
 }
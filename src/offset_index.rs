@@ -0,0 +1,104 @@
+use std::{
+    fs::File,
+    io::{Read, Result},
+    path::Path,
+};
+
+// relative offset and physical position, both 4 bytes
+const ENTRY_SIZE: usize = 8;
+
+// a segment's sparse .index, mapping some of its offsets to the position of
+// the batch holding them, so reads can start near an offset instead of at
+// the start of the segment
+#[derive(Debug)]
+pub struct OffsetIndex {
+    base_offset: i64,
+    entries: Vec<(i32, u32)>,
+}
+
+#[allow(dead_code)]
+impl OffsetIndex {
+    pub fn open(path: impl AsRef<Path>, base_offset: i64) -> Result<OffsetIndex> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Ok(OffsetIndex::from_bytes(base_offset, &data))
+    }
+
+    pub fn from_bytes(base_offset: i64, data: &[u8]) -> OffsetIndex {
+        let mut entries: Vec<(i32, u32)> = Vec::new();
+
+        for entry in data.chunks_exact(ENTRY_SIZE) {
+            let relative_offset = i32::from_be_bytes(entry[0..4].try_into().unwrap());
+            let position = u32::from_be_bytes(entry[4..8].try_into().unwrap());
+
+            // index files are preallocated, the zeroed tail isn't in use
+            if entries
+                .last()
+                .is_some_and(|&(last, _)| relative_offset <= last)
+            {
+                break;
+            }
+            entries.push((relative_offset, position));
+        }
+
+        OffsetIndex {
+            base_offset,
+            entries,
+        }
+    }
+
+    // the position of the last indexed batch at or before offset, where a
+    // scan for offset can start
+    pub fn lookup(&self, offset: i64) -> u64 {
+        let relative_offset = offset - self.base_offset;
+        let entries = self
+            .entries
+            .partition_point(|&(entry, _)| entry as i64 <= relative_offset);
+
+        match entries {
+            0 => 0,
+            n => self.entries[n - 1].1 as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{
+        offset_index::OffsetIndex,
+        segment::{find_batch, test::batch, Segment},
+    };
+
+    #[test]
+    fn test_indexed_read() {
+        let mut data = Vec::new();
+        let mut index = Vec::new();
+        for offset in 0..10 {
+            // an entry every third batch
+            if offset % 3 == 0 {
+                index.extend((offset as i32).to_be_bytes());
+                index.extend((data.len() as u32).to_be_bytes());
+            }
+            data.extend(batch(100 + offset, 0, b"a"));
+        }
+        // preallocated space
+        index.extend([0; 16]);
+
+        let index = OffsetIndex::from_bytes(100, &index);
+        let segment = Segment::from_bytes(data.clone());
+        let position = |offset: usize| segment.batches()[offset].position as u64;
+
+        let start = index.lookup(107);
+        assert_eq!(position(6), start);
+        assert_eq!(0, index.lookup(50));
+
+        let mut reader = Cursor::new(data);
+        assert_eq!(
+            Some(position(7)),
+            find_batch(&mut reader, start, 107).unwrap()
+        );
+        assert_eq!(None, find_batch(&mut reader, start, 110).unwrap());
+    }
+}
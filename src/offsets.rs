@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+// the broker's offsets.retention.minutes default, for commits that don't
+// ask for a retention of their own
+const DEFAULT_RETENTION_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommittedOffset {
+    pub offset: i64,
+    // the epoch of the leader the offset was consumed from, for consumers to
+    // detect log truncation; -1 when unknown
+    pub leader_epoch: i32,
+    pub metadata: Option<String>,
+    // None keeps the commit until it's deleted
+    pub expire_timestamp: Option<i64>,
+}
+
+// what fetching an offset returns when nothing was committed
+const NO_OFFSET: CommittedOffset = CommittedOffset {
+    offset: -1,
+    leader_epoch: -1,
+    metadata: None,
+    expire_timestamp: None,
+};
+
+// when a commit made at commit_timestamp expires, a negative retention
+// being the broker's default one
+pub fn expire_timestamp(commit_timestamp: i64, retention_time_ms: i64) -> i64 {
+    let retention_time_ms = if retention_time_ms < 0 {
+        DEFAULT_RETENTION_MS
+    } else {
+        retention_time_ms
+    };
+    commit_timestamp.saturating_add(retention_time_ms)
+}
+
+// committed consumer group offsets, keyed by group id and then topic-partition
+#[derive(Debug, Default)]
+pub struct OffsetStore {
+    groups: HashMap<String, HashMap<(String, i32), CommittedOffset>>,
+}
+
+impl OffsetStore {
+    pub fn new() -> Self {
+        OffsetStore::default()
+    }
+
+    pub fn has_group(&self, group_id: &str) -> bool {
+        self.groups.contains_key(group_id)
+    }
+
+    pub fn commit(
+        &mut self,
+        group_id: &str,
+        topic: &str,
+        partition: i32,
+        committed: CommittedOffset,
+    ) {
+        self.groups
+            .entry(group_id.to_string())
+            .or_default()
+            .insert((topic.to_string(), partition), committed);
+    }
+
+    // offset -1 when the group has no committed offset for the partition
+    pub fn fetch(&self, group_id: &str, topic: &str, partition: i32) -> CommittedOffset {
+        self.groups
+            .get(group_id)
+            .and_then(|offsets| offsets.get(&(topic.to_string(), partition)))
+            .cloned()
+            .unwrap_or(NO_OFFSET)
+    }
+
+    // every partition the group has an offset for, by topic and partition
+    pub fn fetch_all(&self, group_id: &str) -> Vec<(String, i32, CommittedOffset)> {
+        let mut offsets: Vec<_> = self
+            .groups
+            .get(group_id)
+            .into_iter()
+            .flatten()
+            .map(|((topic, partition), committed)| (topic.clone(), *partition, committed.clone()))
+            .collect();
+        offsets.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        offsets
+    }
+
+    // drops the commits whose retention ran out by now_ms
+    pub fn expire(&mut self, now_ms: i64) {
+        for offsets in self.groups.values_mut() {
+            offsets.retain(|_, committed| committed.expire_timestamp.map_or(true, |t| t > now_ms));
+        }
+    }
+
+    pub fn delete(&mut self, group_id: &str, topic: &str, partition: i32) -> bool {
+        match self.groups.get_mut(group_id) {
+            Some(offsets) => offsets.remove(&(topic.to_string(), partition)).is_some(),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{
+        api::OffsetCommitRequest,
+        offsets::{expire_timestamp, CommittedOffset, OffsetStore, DEFAULT_RETENTION_MS},
+    };
+
+    fn committed(offset: i64) -> CommittedOffset {
+        CommittedOffset {
+            offset,
+            leader_epoch: -1,
+            metadata: None,
+            expire_timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_commit_and_delete_offset() {
+        let mut store = OffsetStore::new();
+        assert_eq!(-1, store.fetch("group", "foo", 0).offset);
+
+        store.commit("group", "foo", 0, committed(42));
+        assert_eq!(42, store.fetch("group", "foo", 0).offset);
+
+        assert!(store.delete("group", "foo", 0));
+        assert_eq!(-1, store.fetch("group", "foo", 0).offset);
+        assert!(store.has_group("group"));
+        assert!(!store.delete("other", "foo", 0));
+    }
+
+    fn offset_commit_request(version: i16) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend([0, 1, b'g']);
+        buf.extend(1i32.to_be_bytes());
+        buf.extend([0, 1, b'm']);
+        if version >= 7 {
+            buf.extend((-1i16).to_be_bytes());
+        }
+        if (2..=4).contains(&version) {
+            buf.extend(1000i64.to_be_bytes());
+        }
+        buf.extend(1i32.to_be_bytes());
+        buf.extend([0, 3, b'f', b'o', b'o']);
+        buf.extend(1i32.to_be_bytes());
+        buf.extend(0i32.to_be_bytes());
+        buf.extend(42i64.to_be_bytes());
+        if version >= 6 {
+            buf.extend(7i32.to_be_bytes());
+        }
+        buf.extend((-1i16).to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_commit_leader_epoch_and_retention() {
+        let v6 = OffsetCommitRequest::parse(&mut Cursor::new(offset_commit_request(6)), 6).unwrap();
+        let v2 = OffsetCommitRequest::parse(&mut Cursor::new(offset_commit_request(2)), 2).unwrap();
+        assert_eq!(-1, v6.retention_time_ms);
+        assert_eq!(1000, v2.retention_time_ms);
+        assert_eq!(-1, v2.topics[0].partitions[0].committed_leader_epoch);
+
+        let partition = &v6.topics[0].partitions[0];
+        let mut store = OffsetStore::new();
+        store.commit(
+            &v6.group_id,
+            &v6.topics[0].name,
+            partition.partition_index,
+            CommittedOffset {
+                offset: partition.committed_offset,
+                leader_epoch: partition.committed_leader_epoch,
+                metadata: partition.committed_metadata.clone(),
+                expire_timestamp: None,
+            },
+        );
+        store.commit(
+            "g",
+            "foo",
+            1,
+            CommittedOffset {
+                expire_timestamp: Some(expire_timestamp(500, v2.retention_time_ms)),
+                ..committed(1)
+            },
+        );
+
+        assert_eq!(
+            CommittedOffset {
+                offset: 42,
+                leader_epoch: 7,
+                metadata: None,
+                expire_timestamp: None
+            },
+            store.fetch("g", "foo", 0)
+        );
+
+        // kept for the retention from the time of the commit
+        store.expire(1499);
+        assert_eq!(1, store.fetch("g", "foo", 1).offset);
+        store.expire(1500);
+        assert_eq!(42, store.fetch("g", "foo", 0).offset);
+        assert_eq!(-1, store.fetch("g", "foo", 1).offset);
+
+        assert_eq!(500 + DEFAULT_RETENTION_MS, expire_timestamp(500, -1));
+    }
+}
@@ -36,12 +36,20 @@ impl Encoder for i8 {
     fn encode(&self) -> Vec<u8> {
         vec![*self as u8]
     }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
 }
 
 impl Encoder for u8 {
     fn encode(&self) -> Vec<u8> {
         vec![*self]
     }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.push(*self);
+    }
 }
 
 pub fn parse_int16(reader: &mut impl Read) -> Result<i16> {
@@ -54,11 +62,15 @@ impl Encoder for i16 {
     fn encode(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
 }
 
 impl Parser<i32> for i32 {
     fn parse(reader: &mut impl Read) -> Result<i32> {
-        Ok(parse_int32(reader)?)
+        parse_int32(reader)
     }
 }
 
@@ -72,6 +84,10 @@ impl Encoder for i32 {
     fn encode(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
 }
 
 pub fn parse_int64(reader: &mut impl Read) -> Result<i64> {
@@ -84,6 +100,10 @@ impl Encoder for i64 {
     fn encode(&self) -> Vec<u8> {
         self.to_be_bytes().to_vec()
     }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
 }
 
 pub fn parse_varint(buf: &mut impl Read) -> Result<i32> {
@@ -91,6 +111,12 @@ pub fn parse_varint(buf: &mut impl Read) -> Result<i32> {
     Ok(num as i32)
 }
 
+// zigzag decoded, the counterpart of encode_signed_varint
+pub fn parse_signed_varint(buf: &mut impl Read) -> Result<i64> {
+    let num = parse_unsigned_varlong(buf)?;
+    Ok((num >> 1) as i64 ^ -((num & 1) as i64))
+}
+
 pub fn parse_unsigned_varint(buf: &mut impl Read) -> Result<u32> {
     let num = parse_unsigned_varlong(buf)?;
     Ok(num as u32)
@@ -100,8 +126,10 @@ pub fn parse_unsigned_varlong(buf: &mut impl Read) -> Result<u64> {
     let mut length: u8 = 0;
     let mut bytes = vec![];
 
-    for b in buf.bytes() {
-        let byte = b?;
+    loop {
+        let mut byte = [0];
+        buf.read_exact(&mut byte)?;
+        let byte = byte[0];
         bytes.push(byte);
         length += 1;
 
@@ -109,43 +137,46 @@ pub fn parse_unsigned_varlong(buf: &mut impl Read) -> Result<u64> {
             break;
         }
         if length == 10 {
-            panic!("Invalid varint");
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "varint too long",
+            ));
         }
     }
 
-    bytes.reverse();
+    Ok(decode_varint(&bytes))
+}
 
+// the value of a complete varint, least significant group first
+pub fn decode_varint(bytes: &[u8]) -> u64 {
     let mut value: u64 = 0;
-    for byte in bytes {
+    for byte in bytes.iter().rev() {
         value <<= 7;
-        value += (byte & 0x3f) as u64;
+        value += (byte & 0x7f) as u64;
     }
 
-    Ok(value)
+    value
 }
 
 pub fn encode_varint(mut varint: u64) -> Vec<u8> {
     let mut buf = Vec::new();
 
-    if varint == 0 {
-        buf.push(0);
-        return buf;
-    }
-
-    while varint != 0 {
-        let byte = (varint & 0x3f) as u8;
-        buf.push(byte | 0x80);
+    // seven bits at a time, the msb set on all but the last byte
+    while varint >= 0x80 {
+        buf.push((varint & 0x7f) as u8 | 0x80);
         varint >>= 7;
     }
-
-    // clear msb in last byte
-    let length = buf.len();
-    buf[length - 1] &= 0x3f;
+    buf.push(varint as u8);
 
     buf
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// zigzag encoded, as used for the fields of a record
+pub fn encode_signed_varint(value: i64) -> Vec<u8> {
+    encode_varint(((value << 1) ^ (value >> 63)) as u64)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[allow(dead_code)]
 pub struct Uuid {
     pub uuid: [u8; 16],
@@ -155,6 +186,35 @@ impl Uuid {
     pub fn new() -> Self {
         Uuid { uuid: [0; 16] }
     }
+
+    // Kafka writes uuids out as unpadded url safe base64, like the
+    // directory.id of a log dir's meta.properties
+    pub fn from_base64(text: &str) -> Option<Uuid> {
+        let mut bytes = Vec::with_capacity(16);
+        let (mut bits, mut bit_count) = (0u32, 0);
+
+        for c in text.bytes() {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'-' => 62,
+                b'_' => 63,
+                _ => return None,
+            };
+            bits = (bits << 6) | value as u32;
+            bit_count += 6;
+            if bit_count >= 8 {
+                bit_count -= 8;
+                bytes.push((bits >> bit_count) as u8);
+                bits &= (1 << bit_count) - 1;
+            }
+        }
+
+        Some(Uuid {
+            uuid: bytes.try_into().ok()?,
+        })
+    }
 }
 
 impl Parser<Self> for Uuid {
@@ -174,6 +234,7 @@ impl Encoder for Uuid {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct CompactString(pub String);
 
 impl Parser<Self> for CompactString {
@@ -182,34 +243,120 @@ impl Parser<Self> for CompactString {
     }
 }
 
-pub fn parse_compact_string(buf: &mut impl Read) -> Result<String> {
-    let length = parse_unsigned_varlong(buf)? as usize;
-    let mut string = vec![0u8; length - 1];
-    buf.read_exact(&mut string)?;
+#[derive(Debug)]
+pub struct CompactNullableString(pub Option<String>);
 
-    Ok(String::from_utf8(string).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
+impl Parser<Self> for CompactNullableString {
+    fn parse(reader: &mut impl Read) -> Result<Self> {
+        Ok(CompactNullableString(parse_compact_nullable_string(
+            reader,
+        )?))
+    }
 }
 
+// a null where a string was expected reads as empty, callers that need to
+// tell the two apart use parse_compact_nullable_string
+pub fn parse_compact_string(buf: &mut impl Read) -> Result<String> {
+    Ok(parse_compact_nullable_string(buf)?.unwrap_or_default())
+}
+
+// framed like any other compact bytes, its length prefix being the
+// string's byte length plus one
 pub fn encode_compact_string(string: &str) -> Vec<u8> {
-    let mut buf = Vec::new();
-    buf.extend(encode_varint(buf.len() as u64 + 1));
-    buf.extend(string.bytes());
+    encode_compact_bytes(string.as_bytes())
+}
+
+// empty bytes are still a length of 1, 0 would be null
+pub fn encode_compact_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut buf = encode_varint(bytes.len() as u64 + 1);
+    buf.extend(bytes);
     buf
 }
 
-pub fn parse_nullable_string(reader: &mut impl Read) -> Result<String> {
+// a zigzag varint length and then the bytes, as the keys and header values of
+// a record are; a length of -1 is null
+pub fn parse_varint_nullable_bytes(reader: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    match parse_signed_varint(reader)? {
+        length if length < 0 => Ok(None),
+        length => read_bytes(reader, length as u64).map(Some),
+    }
+}
+
+pub fn encode_varint_nullable_bytes(bytes: Option<&[u8]>) -> Vec<u8> {
+    match bytes {
+        Some(bytes) => {
+            let mut buf = encode_signed_varint(bytes.len() as i64);
+            buf.extend(bytes);
+            buf
+        }
+        None => encode_signed_varint(-1),
+    }
+}
+
+pub fn parse_nullable_string(reader: &mut impl Read) -> Result<Option<String>> {
     let mut buf = [0; 2];
     reader.read_exact(&mut buf)?;
     let length = i16::from_be_bytes(buf);
 
-    if length == -1 {
-        return Ok(String::new());
+    // -1 is the only negative length, the others mean the request is corrupt
+    match length {
+        -1 => return Ok(None),
+        length if length < 0 => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid string length {}", length),
+            ))
+        }
+        _ => {}
     }
 
     let mut string = vec![0u8; length as usize];
     reader.read_exact(&mut string)?;
 
-    Ok(String::from_utf8(string).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?)
+    String::from_utf8(string)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn parse_string(reader: &mut impl Read) -> Result<String> {
+    let length = parse_int16(reader)?;
+    if length < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected null string",
+        ));
+    }
+
+    let mut string = vec![0u8; length as usize];
+    reader.read_exact(&mut string)?;
+
+    String::from_utf8(string).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn encode_string(string: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend((string.len() as i16).encode());
+    buf.extend(string.bytes());
+    buf
+}
+
+pub fn encode_nullable_string(string: &Option<String>) -> Vec<u8> {
+    match string {
+        Some(s) => encode_string(s),
+        None => (-1i16).encode(),
+    }
+}
+
+pub fn parse_compact_nullable_string(reader: &mut impl Read) -> Result<Option<String>> {
+    let length = parse_unsigned_varlong(reader)?;
+    if length == 0 {
+        return Ok(None);
+    }
+
+    let string = read_bytes(reader, length - 1)?;
+    String::from_utf8(string)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
 pub fn encode_compact_nullable_string(string: &Option<String>) -> Vec<u8> {
@@ -262,15 +409,58 @@ where
     Ok(array)
 }
 
+// a length of 0 is a null array rather than an empty one
+pub fn parse_compact_nullable_array<P, R>(reader: &mut R) -> Result<Option<Vec<P>>>
+where
+    P: Parser<P>,
+    R: Read,
+{
+    let length = parse_unsigned_varlong(reader)?;
+    if length == 0 {
+        return Ok(None);
+    }
+
+    let mut array = Vec::new();
+    for _ in 0..length - 1 {
+        array.push(P::parse(reader)?);
+    }
+
+    Ok(Some(array))
+}
+
 pub fn encode_compact_array<T: Encoder>(array: &[T]) -> Vec<u8> {
-    let mut res = Vec::new();
+    // the in memory size is only an estimate, exact for the fixed size types
+    let mut res = Vec::with_capacity(5 + std::mem::size_of_val(array));
 
-    if array.is_empty() {
-        res.extend(encode_varint(0));
-    } else {
-        res.extend(encode_varint(array.len() as u64 + 1));
+    // 0 would be a null array, an empty one still has a length of 1
+    res.extend(encode_varint(array.len() as u64 + 1));
+
+    for item in array {
+        item.encode_into(&mut res);
     }
 
+    res
+}
+
+pub fn parse_array<P, R>(reader: &mut R) -> Result<Vec<P>>
+where
+    P: Parser<P>,
+    R: Read,
+{
+    let length = parse_int32(reader)?;
+    let mut array = Vec::new();
+
+    for _ in 0..length {
+        array.push(P::parse(reader)?);
+    }
+
+    Ok(array)
+}
+
+pub fn encode_array<T: Encoder>(array: &[T]) -> Vec<u8> {
+    let mut res = Vec::new();
+    res.extend((array.len() as i32).encode());
+
     for item in array {
         res.extend(item.encode());
     }
@@ -309,22 +499,68 @@ pub fn encode_nullable_field<T: Encoder>(array: &Option<T>) -> Vec<u8> {
     buf
 }
 
-// ignoring tag buffers for now
-pub fn parse_tag_buffer(reader: &mut impl Read) -> Result<Vec<u8>> {
-    let mut buf = [0];
-    reader.read_exact(&mut buf)?;
-    Ok(Vec::new())
+// reads past every field in the buffer, which callers that know of no
+// tagged fields for the struct can just drop
+pub fn parse_tag_buffer(reader: &mut impl Read) -> Result<Vec<(u32, Vec<u8>)>> {
+    parse_tagged_fields(reader)
 }
 
+// a tag buffer without fields, encode_tagged_fields writes ones with them
 pub fn encode_tag_buffer() -> Vec<u8> {
-    vec![0]
+    encode_tagged_fields(&[])
+}
+
+// tag, length and raw value of each field, left for the caller to interpret
+pub fn parse_tagged_fields(reader: &mut impl Read) -> Result<Vec<(u32, Vec<u8>)>> {
+    let count = parse_unsigned_varint(reader)?;
+    let mut fields = Vec::new();
+
+    for _ in 0..count {
+        let tag = parse_unsigned_varint(reader)?;
+        let length = parse_unsigned_varint(reader)?;
+        fields.push((tag, read_bytes(reader, length as u64)?));
+    }
+
+    Ok(fields)
+}
+
+// the next length bytes, allocated as they're read rather than up front, so
+// a length a client made up runs out of input before it runs out of memory
+fn read_bytes(reader: &mut impl Read, length: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(length).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+pub fn encode_tagged_fields(fields: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend(encode_varint(fields.len() as u64));
+
+    for (tag, data) in fields {
+        buf.extend(encode_varint(*tag as u64));
+        buf.extend(encode_varint(data.len() as u64));
+        buf.extend(data);
+    }
+
+    buf
 }
 
 #[cfg(test)]
 mod test {
     use std::io::Cursor;
 
-    use crate::primitives::{parse_compact_string, parse_unsigned_varlong};
+    use crate::{
+        api::Encoder,
+        primitives::{
+            encode_compact_array, encode_compact_string, encode_tag_buffer, encode_tagged_fields,
+            encode_varint, encode_varint_nullable_bytes, parse_compact_string,
+            parse_nullable_string, parse_tag_buffer, parse_unsigned_varlong,
+            parse_varint_nullable_bytes, Uuid,
+        },
+    };
 
     #[test]
     fn test_decode_single_byte_varint() {
@@ -344,6 +580,35 @@ mod test {
         assert_eq!(16384, value);
     }
 
+    #[test]
+    fn test_decode_varint_seven_bit_groups() {
+        // groups with bit 6 set, which a six bit mask would drop
+        let mut cursor = Cursor::new(&[0xac, 0x02]);
+        assert_eq!(300, parse_unsigned_varlong(&mut cursor).unwrap());
+
+        let mut cursor = Cursor::new(&[0xff, 0xff, 0x03]);
+        assert_eq!(65535, parse_unsigned_varlong(&mut cursor).unwrap());
+        assert_eq!(3, cursor.position());
+    }
+
+    #[test]
+    fn test_encode_varint() {
+        assert_eq!(vec![0xac, 0x02], encode_varint(300));
+        assert_eq!(vec![0], encode_varint(0));
+        assert_eq!(vec![0x7f], encode_varint(127));
+        assert_eq!(vec![0x80, 0x01], encode_varint(128));
+
+        let mut values = vec![0, 1, 63, 64, 127, 128, 300, 16383, 16384];
+        values.extend([u32::MAX as u64, u32::MAX as u64 + 1, u64::MAX]);
+        values.extend((0..64).map(|shift| 1u64 << shift));
+        for value in values {
+            let encoded = encode_varint(value);
+            let mut cursor = Cursor::new(&encoded);
+            assert_eq!(value, parse_unsigned_varlong(&mut cursor).unwrap());
+            assert_eq!(encoded.len() as u64, cursor.position());
+        }
+    }
+
     #[test]
     fn test_decode_compact_string() {
         let string = "test";
@@ -353,4 +618,186 @@ mod test {
         let mut cursor = Cursor::new(&buf);
         assert_eq!("test", &parse_compact_string(&mut cursor).unwrap());
     }
+
+    #[test]
+    fn test_encode_compact_string() {
+        let encoded = encode_compact_string("test");
+        assert_eq!(vec![5, b't', b'e', b's', b't'], encoded);
+
+        let mut cursor = Cursor::new(&encoded);
+        assert_eq!("test", &parse_compact_string(&mut cursor).unwrap());
+        assert_eq!(encoded.len() as u64, cursor.position());
+
+        // longer than a single byte length prefix
+        let long = "x".repeat(200);
+        let encoded = encode_compact_string(&long);
+        assert_eq!(vec![0xc9, 0x01], encoded[..2]);
+        assert_eq!(
+            long,
+            parse_compact_string(&mut Cursor::new(&encoded)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_null_compact_string() {
+        let mut cursor = Cursor::new([0u8]);
+        assert_eq!("", &parse_compact_string(&mut cursor).unwrap());
+        assert_eq!(1, cursor.position());
+    }
+
+    #[test]
+    fn test_decode_overlong_compact_string() {
+        // a length near u64::MAX, then the end of the frame
+        let mut buf = encode_varint(u64::MAX);
+        buf.extend(b"abc");
+        let err = parse_compact_string(&mut Cursor::new(buf)).unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_varint_nullable_bytes() {
+        for bytes in [None, Some(&b""[..]), Some(&b"key"[..])] {
+            let encoded = encode_varint_nullable_bytes(bytes);
+            let mut cursor = Cursor::new(&encoded);
+            let parsed = parse_varint_nullable_bytes(&mut cursor).unwrap();
+            assert_eq!(bytes, parsed.as_deref());
+            assert_eq!(encoded.len() as u64, cursor.position());
+        }
+        assert_eq!(vec![1], encode_varint_nullable_bytes(None));
+        assert_eq!(
+            vec![6, b'k', b'e', b'y'],
+            encode_varint_nullable_bytes(Some(b"key"))
+        );
+
+        // a length past the end of the buffer
+        let mut cursor = Cursor::new(&[6, b'k', b'e']);
+        let err = parse_varint_nullable_bytes(&mut cursor).unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_decode_nullable_string() {
+        let mut cursor = Cursor::new([0xff, 0xff]);
+        assert_eq!(None, parse_nullable_string(&mut cursor).unwrap());
+        assert_eq!(2, cursor.position());
+
+        let mut cursor = Cursor::new([0, 0]);
+        assert_eq!(
+            Some(String::new()),
+            parse_nullable_string(&mut cursor).unwrap()
+        );
+        assert_eq!(2, cursor.position());
+
+        let mut cursor = Cursor::new([0, 3, b'c', b'l', b'i']);
+        assert_eq!(
+            Some("cli".to_string()),
+            parse_nullable_string(&mut cursor).unwrap()
+        );
+
+        // lengths below -1 are corrupt, not null
+        for length in [-2i16, i16::MIN] {
+            let mut cursor = Cursor::new(length.to_be_bytes());
+            let err = parse_nullable_string(&mut cursor).unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_encode_borrowed_compact_string() {
+        let name = String::from("topic-name");
+        let encoded = encode_compact_string(&name[..5]);
+
+        assert!(encoded.ends_with(b"topic"));
+        assert_eq!("topic-name", name);
+    }
+
+    // what encode_compact_array did before values were encoded in place
+    fn encode_each<T: Encoder>(array: &[T]) -> Vec<u8> {
+        let mut buf = encode_varint(array.len() as u64 + 1);
+        for item in array {
+            buf.extend(item.encode());
+        }
+        buf
+    }
+
+    #[test]
+    fn test_encode_compact_array_in_place() {
+        let ints: Vec<i32> = (0..40).collect();
+        let bytes = vec![0xab; 100];
+        let longs = [-1i64, 0, 1];
+        let uuids = [Uuid { uuid: [7; 16] }];
+
+        assert_eq!(encode_each(&ints), encode_compact_array(&ints));
+        assert_eq!(
+            encode_each(&ints[5..10]),
+            encode_compact_array(&ints[5..10])
+        );
+        assert_eq!(encode_each(&bytes), encode_compact_array(&bytes));
+        assert_eq!(encode_each(&longs), encode_compact_array(&longs));
+        assert_eq!(encode_each(&uuids), encode_compact_array(&uuids));
+    }
+
+    #[test]
+    fn test_encode_empty_compact_array() {
+        let empty: Vec<i32> = Vec::new();
+        assert_eq!(vec![1], encode_compact_array(&empty));
+        assert_eq!(vec![2, 0, 0, 0, 7], encode_compact_array(&[7i32]));
+    }
+
+    #[test]
+    fn test_uuid_from_base64() {
+        let uuid = Uuid::from_base64("AAECAwQFBgcICQoLDA0ODw").unwrap();
+        assert_eq!(
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+            uuid.uuid
+        );
+        assert_eq!(
+            Some(Uuid { uuid: [0xff; 16] }),
+            Uuid::from_base64("_____________________w")
+        );
+        assert_eq!(None, Uuid::from_base64("AAECAwQFBgcICQoLDA0O"));
+        assert_eq!(None, Uuid::from_base64("AAECAwQFBgcICQoLDA0OD+"));
+    }
+
+    #[test]
+    fn test_decode_overlong_varint() {
+        let mut cursor = Cursor::new(&[0xff; 11]);
+        let err = parse_unsigned_varlong(&mut cursor).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_empty_tag_buffer() {
+        assert_eq!(vec![0], encode_tag_buffer());
+
+        let mut cursor = Cursor::new(encode_tag_buffer());
+        assert!(parse_tag_buffer(&mut cursor).unwrap().is_empty());
+        assert_eq!(1, cursor.position());
+    }
+
+    #[test]
+    fn test_tag_buffer_with_field() {
+        let fields = vec![(3, vec![0xaa; 200])];
+        let mut buf = encode_tagged_fields(&fields);
+        // the count, the tag, the length in two bytes and the value
+        assert_eq!([1, 3, 0xc8, 0x01], buf[..4]);
+        assert_eq!(204, buf.len());
+
+        // whatever follows the buffer is left to read
+        buf.push(7);
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(fields, parse_tag_buffer(&mut cursor).unwrap());
+        assert_eq!(204, cursor.position());
+    }
+
+    #[test]
+    fn test_tag_buffer_with_overlong_field() {
+        // a field claiming u32::MAX bytes, with only two of them sent
+        let mut buf = vec![1, 0];
+        buf.extend(encode_varint(u32::MAX as u64));
+        buf.extend([0xaa, 0xbb]);
+
+        let err = parse_tag_buffer(&mut Cursor::new(buf)).unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+    }
 }
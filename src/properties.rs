@@ -0,0 +1,271 @@
+use std::{collections::HashMap, fs, io::Result, path::Path};
+
+use crate::log_dir::LogDir;
+
+pub const LOG_DIRS: &str = "log.dirs";
+// the older single directory form, only used without log.dirs
+pub const LOG_DIR: &str = "log.dir";
+pub const METADATA_LOG_DIR: &str = "metadata.log.dir";
+pub const BROKER_RACK: &str = "broker.rack";
+pub const SOCKET_REQUEST_MAX_BYTES: &str = "socket.request.max.bytes";
+// how many connections the kernel queues for the listener before they're accepted
+pub const SOCKET_LISTEN_BACKLOG_SIZE: &str = "socket.listen.backlog.size";
+pub const LISTENERS: &str = "listeners";
+// where clients are told to connect, the listeners when unset
+pub const ADVERTISED_LISTENERS: &str = "advertised.listeners";
+// the bytes per second each client may send before it is throttled
+pub const CLIENT_BYTE_RATE: &str = "client.quota.bytes.per.second";
+// reject or close, for requests to APIs the broker doesn't implement
+pub const UNIMPLEMENTED_APIS: &str = "unimplemented.apis";
+// the stack of each thread connections are handled on
+pub const HANDLER_THREAD_STACK_SIZE: &str = "handler.thread.stack.size";
+
+// the key=value lines of a server.properties file, without comments
+pub fn parse_properties(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('!'))
+        .filter_map(|line| {
+            let (key, value) = line.split_once(['=', ':'])?;
+            Some((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+pub fn read_properties(path: impl AsRef<Path>) -> Result<HashMap<String, String>> {
+    Ok(parse_properties(&fs::read_to_string(path)?))
+}
+
+// the log dirs a properties file configures, default when it sets none
+pub fn log_dir(properties: &HashMap<String, String>, default: &str) -> LogDir {
+    let dirs: Vec<&str> = properties
+        .get(LOG_DIRS)
+        .or_else(|| properties.get(LOG_DIR))
+        .map(|dirs| {
+            dirs.split(',')
+                .map(str::trim)
+                .filter(|dir| !dir.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let log_dir = match dirs.is_empty() {
+        true => LogDir::new(default),
+        false => LogDir::with_dirs(dirs),
+    };
+
+    match properties.get(METADATA_LOG_DIR) {
+        Some(dir) if !dir.is_empty() => log_dir.with_metadata_dir(dir),
+        _ => log_dir,
+    }
+}
+
+// the rack the broker is in, if the properties file sets one
+pub fn broker_rack(properties: &HashMap<String, String>) -> Option<String> {
+    properties
+        .get(BROKER_RACK)
+        .filter(|rack| !rack.is_empty())
+        .cloned()
+}
+
+// the largest request, and Metadata response, the broker handles; default
+// when the properties file doesn't set a valid one
+pub fn socket_request_max_bytes(properties: &HashMap<String, String>, default: usize) -> usize {
+    properties
+        .get(SOCKET_REQUEST_MAX_BYTES)
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(default)
+}
+
+// the listener's backlog, default when the properties file doesn't set a
+// valid one
+pub fn socket_listen_backlog_size(properties: &HashMap<String, String>, default: u32) -> u32 {
+    properties
+        .get(SOCKET_LISTEN_BACKLOG_SIZE)
+        .and_then(|size| size.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(default)
+}
+
+// the stack size of the handler threads, default when the properties file
+// doesn't set a valid one
+pub fn handler_thread_stack_size(properties: &HashMap<String, String>, default: usize) -> usize {
+    properties
+        .get(HANDLER_THREAD_STACK_SIZE)
+        .and_then(|size| size.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(default)
+}
+
+// the bytes per second a client may send, unlimited when the properties file
+// doesn't set a valid rate
+pub fn client_byte_rate(properties: &HashMap<String, String>) -> Option<u64> {
+    properties
+        .get(CLIENT_BYTE_RATE)
+        .and_then(|rate| rate.parse().ok())
+        .filter(|rate| *rate > 0)
+}
+
+// the host and port of the named listener in a value like
+// PLAINTEXT://0.0.0.0:9093,CONTROLLER://:9094, where a listener without a
+// host has an empty one
+pub fn parse_listener(listeners: &str, name: &str) -> Option<(String, i32)> {
+    listeners.split(',').map(str::trim).find_map(|listener| {
+        let (listener_name, address) = listener.split_once("://")?;
+        if listener_name != name {
+            return None;
+        }
+        let (host, port) = address.rsplit_once(':')?;
+        Some((host.to_string(), port.parse().ok()?))
+    })
+}
+
+// the address the named listener binds to
+pub fn listener_address(properties: &HashMap<String, String>, name: &str) -> Option<(String, i32)> {
+    parse_listener(properties.get(LISTENERS)?, name)
+}
+
+// the address clients are given for the named listener
+pub fn advertised_address(
+    properties: &HashMap<String, String>,
+    name: &str,
+) -> Option<(String, i32)> {
+    properties
+        .get(ADVERTISED_LISTENERS)
+        .and_then(|listeners| parse_listener(listeners, name))
+        .or_else(|| listener_address(properties, name))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, path::PathBuf, process};
+
+    use crate::properties::{
+        advertised_address, broker_rack, client_byte_rate, handler_thread_stack_size, log_dir,
+        parse_listener, parse_properties, read_properties, socket_listen_backlog_size,
+        socket_request_max_bytes,
+    };
+
+    #[test]
+    fn test_log_dir_from_properties() {
+        let dir = env::temp_dir().join(format!("kafka-test-{}-properties", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("server.properties");
+        fs::write(
+            &path,
+            "# Licensed to the Apache Software Foundation\n\
+             process.roles=broker,controller\n\
+             node.id=1\n\
+             \n\
+             broker.rack=rack-a\n\
+             socket.request.max.bytes=1048576\n\
+             socket.listen.backlog.size=128\n\
+             client.quota.bytes.per.second=1000\n\
+             log.dirs = /var/kafka/a, /var/kafka/b\n",
+        )
+        .unwrap();
+        let properties = read_properties(&path).unwrap();
+        assert_eq!("broker,controller", properties["process.roles"]);
+        assert_eq!(Some("rack-a".to_string()), broker_rack(&properties));
+        assert_eq!(1048576, socket_request_max_bytes(&properties, 100));
+        assert_eq!(128, socket_listen_backlog_size(&properties, 50));
+        assert_eq!(Some(1000), client_byte_rate(&properties));
+
+        let logs = log_dir(&properties, "/tmp/kraft-combined-logs");
+        assert_eq!(
+            PathBuf::from("/var/kafka/a/__cluster_metadata-0/00000000000000000000.log"),
+            logs.metadata_log_path()
+        );
+
+        fs::write(
+            &path,
+            "log.dirs=/var/kafka/a\nmetadata.log.dir=/var/kafka/metadata\n",
+        )
+        .unwrap();
+        let logs = log_dir(&read_properties(&path).unwrap(), "/tmp/kraft-combined-logs");
+        assert_eq!(
+            PathBuf::from("/var/kafka/metadata/__cluster_metadata-0/00000000000000000000.log"),
+            logs.metadata_log_path()
+        );
+        assert_eq!(
+            PathBuf::from("/var/kafka/a/foo-0"),
+            logs.partition_dir("foo", 0)
+        );
+
+        // without log.dirs the default is used, and there's no rack
+        fs::write(&path, "node.id=1\n").unwrap();
+        assert_eq!(None, broker_rack(&read_properties(&path).unwrap()));
+        assert_eq!(None, client_byte_rate(&read_properties(&path).unwrap()));
+        assert_eq!(
+            100,
+            socket_request_max_bytes(&read_properties(&path).unwrap(), 100)
+        );
+        assert_eq!(
+            50,
+            socket_listen_backlog_size(&read_properties(&path).unwrap(), 50)
+        );
+        let logs = log_dir(&read_properties(&path).unwrap(), "/tmp/kraft-combined-logs");
+        assert_eq!(
+            PathBuf::from("/tmp/kraft-combined-logs/__cluster_metadata-0/00000000000000000000.log"),
+            logs.metadata_log_path()
+        );
+    }
+
+    #[test]
+    fn test_handler_thread_stack_size() {
+        let properties =
+            |value: &str| parse_properties(&format!("handler.thread.stack.size={}\n", value));
+        assert_eq!(
+            262144,
+            handler_thread_stack_size(&properties("262144"), 1 << 21)
+        );
+        // unset, invalid or zero keep the default
+        assert_eq!(
+            1 << 21,
+            handler_thread_stack_size(&parse_properties(""), 1 << 21)
+        );
+        assert_eq!(
+            1 << 21,
+            handler_thread_stack_size(&properties("256k"), 1 << 21)
+        );
+        assert_eq!(
+            1 << 21,
+            handler_thread_stack_size(&properties("0"), 1 << 21)
+        );
+    }
+
+    #[test]
+    fn test_listeners() {
+        assert_eq!(
+            Some(("0.0.0.0".to_string(), 9093)),
+            parse_listener("PLAINTEXT://0.0.0.0:9093", "PLAINTEXT")
+        );
+        assert_eq!(
+            Some(("".to_string(), 9094)),
+            parse_listener(
+                "PLAINTEXT://localhost:9092, CONTROLLER://:9094",
+                "CONTROLLER"
+            )
+        );
+        assert_eq!(None, parse_listener("CONTROLLER://:9094", "PLAINTEXT"));
+        assert_eq!(None, parse_listener("PLAINTEXT://localhost", "PLAINTEXT"));
+
+        // advertised.listeners wins over listeners, which are the fallback
+        let mut properties =
+            parse_properties("listeners=PLAINTEXT://0.0.0.0:9093,CONTROLLER://:9094\n");
+        assert_eq!(
+            Some(("0.0.0.0".to_string(), 9093)),
+            advertised_address(&properties, "PLAINTEXT")
+        );
+        properties.insert(
+            "advertised.listeners".to_string(),
+            "PLAINTEXT://broker-1:9093".to_string(),
+        );
+        assert_eq!(
+            Some(("broker-1".to_string(), 9093)),
+            advertised_address(&properties, "PLAINTEXT")
+        );
+    }
+}
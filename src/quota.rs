@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+// clients without an id share the bucket of the default client entity
+pub const DEFAULT_CLIENT: &str = "<default>";
+// usage is counted over windows this long, after which a client that sent
+// nothing more is forgotten
+const WINDOW: Duration = Duration::from_secs(1);
+
+pub fn quota_key(client_id: &str) -> &str {
+    if client_id.is_empty() {
+        DEFAULT_CLIENT
+    } else {
+        client_id
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ClientUsage {
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    usage: ClientUsage,
+}
+
+// what each client sent in its current window, keyed by client id, checked
+// against the bytes per second each client may send
+#[derive(Debug)]
+pub struct ClientQuotas {
+    windows: HashMap<String, Window>,
+    // unlimited when None
+    byte_rate: Option<u64>,
+    last_expired: Instant,
+}
+
+impl Default for ClientQuotas {
+    fn default() -> Self {
+        ClientQuotas::with_byte_rate(None)
+    }
+}
+
+impl ClientQuotas {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        ClientQuotas::default()
+    }
+
+    pub fn with_byte_rate(byte_rate: Option<u64>) -> Self {
+        ClientQuotas {
+            windows: HashMap::new(),
+            byte_rate: byte_rate.filter(|rate| *rate > 0),
+            last_expired: Instant::now(),
+        }
+    }
+
+    // counts a request against the client's quota, returning how long the
+    // client is throttled for going over it
+    pub fn record(&mut self, client_id: &str, bytes: usize) -> Duration {
+        let now = Instant::now();
+        self.expire(now);
+
+        let window = self
+            .windows
+            .entry(quota_key(client_id).to_string())
+            .or_insert_with(|| Window {
+                start: now,
+                usage: ClientUsage::default(),
+            });
+        if now.duration_since(window.start) >= WINDOW {
+            window.start = now;
+            window.usage = ClientUsage::default();
+        }
+        window.usage.requests += 1;
+        window.usage.bytes += bytes as u64;
+
+        // as long as the bytes over a window's worth take at the rate
+        match self.byte_rate {
+            Some(rate) => Duration::from_secs_f64(window.usage.bytes as f64 / rate as f64)
+                .saturating_sub(WINDOW),
+            None => Duration::ZERO,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn usage(&self, client_id: &str) -> ClientUsage {
+        self.windows
+            .get(quota_key(client_id))
+            .map(|window| window.usage)
+            .unwrap_or_default()
+    }
+
+    // drops the clients whose window has ended, at most once a window
+    fn expire(&mut self, now: Instant) {
+        if now.duration_since(self.last_expired) < WINDOW {
+            return;
+        }
+        self.windows
+            .retain(|_, window| now.duration_since(window.start) < WINDOW);
+        self.last_expired = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use crate::quota::{ClientQuotas, ClientUsage, DEFAULT_CLIENT, WINDOW};
+
+    #[test]
+    fn test_client_buckets() {
+        let mut quotas = ClientQuotas::new();
+        quotas.record("consumer-1", 100);
+        quotas.record("consumer-2", 10);
+        quotas.record("consumer-2", 10);
+        quotas.record("", 5);
+
+        assert_eq!(
+            ClientUsage {
+                requests: 1,
+                bytes: 100
+            },
+            quotas.usage("consumer-1")
+        );
+        assert_eq!(
+            ClientUsage {
+                requests: 2,
+                bytes: 20
+            },
+            quotas.usage("consumer-2")
+        );
+        assert_eq!(quotas.usage(""), quotas.usage(DEFAULT_CLIENT));
+        assert_eq!(1, quotas.usage("").requests);
+    }
+
+    #[test]
+    fn test_byte_rate_throttle() {
+        let mut quotas = ClientQuotas::with_byte_rate(Some(1000));
+        assert_eq!(Duration::ZERO, quotas.record("consumer-1", 1000));
+        // a second's worth over the rate
+        assert_eq!(Duration::from_secs(1), quotas.record("consumer-1", 1000));
+        // other clients have buckets of their own
+        assert_eq!(Duration::ZERO, quotas.record("consumer-2", 10));
+
+        let mut unlimited = ClientQuotas::new();
+        assert_eq!(Duration::ZERO, unlimited.record("consumer-1", usize::MAX));
+    }
+
+    #[test]
+    fn test_idle_clients_expire() {
+        let mut quotas = ClientQuotas::new();
+        quotas.record("consumer-1", 100);
+        thread::sleep(WINDOW);
+        quotas.record("consumer-2", 10);
+
+        assert_eq!(ClientUsage::default(), quotas.usage("consumer-1"));
+        assert_eq!(1, quotas.windows.len());
+    }
+}
@@ -0,0 +1,1276 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Result, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use tokio::sync::watch;
+
+use crate::{
+    api::{BatchIndexAndErrorMessage, ErrorCode},
+    compression::Compression,
+    crc::crc32c,
+    log_config::LogConfig,
+    log_dir::{segment_file, segment_paths},
+    offset_index::OffsetIndex,
+    primitives::parse_unsigned_varlong,
+};
+
+// base offset and batch length precede the part counted by the batch length
+const LOG_OVERHEAD: usize = 12;
+const PARTITION_LEADER_EPOCH_POSITION: usize = 12;
+const MAGIC_POSITION: usize = 16;
+const CRC_POSITION: usize = 17;
+const ATTRIBUTES_POSITION: usize = 21;
+const LAST_OFFSET_DELTA_POSITION: usize = 23;
+const MAX_TIMESTAMP_POSITION: usize = 35;
+const PRODUCER_ID_POSITION: usize = 43;
+const RECORDS_COUNT_POSITION: usize = 57;
+
+pub const TRANSACTIONAL_FLAG: i16 = 0x10;
+pub const CONTROL_FLAG: i16 = 0x20;
+// the control record key type of an abort marker, commit being 1
+const ABORT_MARKER: i16 = 0;
+const BATCH_HEADER_SIZE: usize = 61;
+// how far the records of a compressed batch are let to decompress, well past
+// anything a producer's batch.size would fill
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+// a batch located by its header alone, without decoding its records
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchPosition {
+    pub base_offset: i64,
+    pub last_offset: i64,
+    pub leader_epoch: i32,
+    pub attributes: i16,
+    pub max_timestamp: i64,
+    pub producer_id: i64,
+    pub position: usize,
+    pub size: usize,
+}
+
+impl BatchPosition {
+    // the batch whose header starts header, found at position in its segment
+    fn parse(header: &[u8], position: usize) -> BatchPosition {
+        let base_offset = i64::from_be_bytes(header[0..8].try_into().unwrap());
+        let batch_length = i32::from_be_bytes(header[8..12].try_into().unwrap());
+        let leader_epoch = i32::from_be_bytes(
+            header[PARTITION_LEADER_EPOCH_POSITION..PARTITION_LEADER_EPOCH_POSITION + 4]
+                .try_into()
+                .unwrap(),
+        );
+        let attributes = i16::from_be_bytes(
+            header[ATTRIBUTES_POSITION..ATTRIBUTES_POSITION + 2]
+                .try_into()
+                .unwrap(),
+        );
+        let last_offset_delta = i32::from_be_bytes(
+            header[LAST_OFFSET_DELTA_POSITION..LAST_OFFSET_DELTA_POSITION + 4]
+                .try_into()
+                .unwrap(),
+        );
+        let max_timestamp = i64::from_be_bytes(
+            header[MAX_TIMESTAMP_POSITION..MAX_TIMESTAMP_POSITION + 8]
+                .try_into()
+                .unwrap(),
+        );
+        let producer_id = i64::from_be_bytes(
+            header[PRODUCER_ID_POSITION..PRODUCER_ID_POSITION + 8]
+                .try_into()
+                .unwrap(),
+        );
+
+        BatchPosition {
+            base_offset,
+            last_offset: base_offset + last_offset_delta as i64,
+            leader_epoch,
+            attributes,
+            max_timestamp,
+            producer_id,
+            position,
+            size: LOG_OVERHEAD + batch_length.max(0) as usize,
+        }
+    }
+}
+
+// the batches of a partition log segment, indexed by their own offsets so
+// that gaps left by compaction don't throw off lookups
+#[derive(Debug)]
+pub struct Segment {
+    data: Vec<u8>,
+    batches: Vec<BatchPosition>,
+}
+
+#[allow(dead_code)]
+impl Segment {
+    pub fn open(path: impl AsRef<Path>) -> Result<Segment> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Ok(Segment::from_bytes(data))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Segment {
+        let mut batches = Vec::new();
+        let mut position = 0;
+
+        // a trailing partial batch is left out, as a writer may still be appending it
+        while data.len() - position >= BATCH_HEADER_SIZE {
+            let batch = BatchPosition::parse(&data[position..], position);
+            if batch.size < BATCH_HEADER_SIZE || position + batch.size > data.len() {
+                break;
+            }
+            position += batch.size;
+            batches.push(batch);
+        }
+
+        Segment { data, batches }
+    }
+
+    pub fn batches(&self) -> &[BatchPosition] {
+        &self.batches
+    }
+
+    pub fn log_start_offset(&self) -> i64 {
+        self.batches.first().map(|b| b.base_offset).unwrap_or(0)
+    }
+
+    pub fn log_end_offset(&self) -> i64 {
+        self.batches.last().map(|b| b.last_offset + 1).unwrap_or(0)
+    }
+
+    // the epoch of the leader that wrote offset, offsets past the end belong
+    // to the current leader; -1 when there is no such leader
+    pub fn leader_epoch(&self, offset: i64) -> i32 {
+        match self.batches.iter().find(|b| b.last_offset >= offset) {
+            Some(batch) => batch.leader_epoch,
+            None => self.batches.last().map(|b| b.leader_epoch).unwrap_or(-1),
+        }
+    }
+
+    // the producer id and first offset of each aborted transaction with
+    // records between fetch_offset and max_offset, which read_committed
+    // consumers skip
+    pub fn aborted_transactions(&self, fetch_offset: i64, max_offset: i64) -> Vec<(i64, i64)> {
+        let mut ongoing: HashMap<i64, i64> = HashMap::new();
+        let mut aborted = Vec::new();
+
+        for batch in &self.batches {
+            if batch.attributes & TRANSACTIONAL_FLAG == 0 {
+                continue;
+            }
+
+            if batch.attributes & CONTROL_FLAG == 0 {
+                ongoing
+                    .entry(batch.producer_id)
+                    .or_insert(batch.base_offset);
+            } else if let Some(first_offset) = ongoing.remove(&batch.producer_id) {
+                let data = &self.data[batch.position..batch.position + batch.size];
+                if batch.base_offset >= fetch_offset
+                    && first_offset < max_offset
+                    && control_type(data) == Some(ABORT_MARKER)
+                {
+                    aborted.push((batch.producer_id, first_offset));
+                }
+            }
+        }
+
+        aborted
+    }
+
+    // the first batch with a timestamp at or after timestamp
+    pub fn batch_for_timestamp(&self, timestamp: i64) -> Option<&BatchPosition> {
+        self.batches.iter().find(|b| b.max_timestamp >= timestamp)
+    }
+
+    // the batches from the one holding offset onwards; when offset was
+    // compacted away this starts at the next batch after the gap
+    pub fn read_from(&self, offset: i64) -> &[u8] {
+        self.read_range(offset, i64::MAX)
+    }
+
+    // like read_from, but leaving out the batches that reach max_offset
+    pub fn read_range(&self, offset: i64, max_offset: i64) -> &[u8] {
+        let batches: Vec<_> = self
+            .batches
+            .iter()
+            .filter(|b| b.last_offset >= offset && b.last_offset < max_offset)
+            .collect();
+
+        match (batches.first(), batches.last()) {
+            (Some(first), Some(last)) => &self.data[first.position..last.position + last.size],
+            _ => &[],
+        }
+    }
+}
+
+// a segment of a SegmentSet, known by its file alone until something needs
+// its batches; their headers are then read once, their records only per read
+#[derive(Debug)]
+struct SegmentFile {
+    path: PathBuf,
+    base_offset: i64,
+    batches: OnceLock<Vec<BatchPosition>>,
+}
+
+impl SegmentFile {
+    fn batches(&self) -> Result<&[BatchPosition]> {
+        if let Some(batches) = self.batches.get() {
+            return Ok(batches);
+        }
+        let batches = read_batch_positions(&mut File::open(&self.path)?)?;
+        Ok(self.batches.get_or_init(|| batches))
+    }
+
+    // where a scan for offset can start, the segment's start without an index
+    fn indexed_position(&self, offset: i64) -> Result<u64> {
+        match OffsetIndex::open(self.path.with_extension("index"), self.base_offset) {
+            Ok(index) => Ok(index.lookup(offset)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// the segments of a partition, ordered by base offset, so that reads can
+// carry on from the end of one segment into the next. Opening one only lists
+// its files, each read touching no more of them than it needs
+#[derive(Debug, Default)]
+pub struct SegmentSet {
+    segments: Vec<SegmentFile>,
+}
+
+impl SegmentSet {
+    // every .log file in the partition dir, none when it doesn't exist yet
+    pub fn open(dir: impl AsRef<Path>) -> Result<SegmentSet> {
+        let paths = match segment_paths(dir.as_ref()) {
+            Ok(paths) => paths,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(SegmentSet::default()),
+            Err(err) => return Err(err),
+        };
+
+        let segments = paths
+            .into_iter()
+            .map(|path| SegmentFile {
+                // segment_paths only lists the ones named after their base offset
+                base_offset: path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse().ok())
+                    .unwrap_or_default(),
+                path,
+                batches: OnceLock::new(),
+            })
+            .collect();
+        Ok(SegmentSet { segments })
+    }
+
+    // every batch up to the first one for which f returns Some, across segments
+    fn find_batch<T>(&self, mut f: impl FnMut(&BatchPosition) -> Option<T>) -> Result<Option<T>> {
+        for segment in &self.segments {
+            if let Some(found) = segment.batches()?.iter().find_map(&mut f) {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn log_start_offset(&self) -> Result<i64> {
+        Ok(self.find_batch(|b| Some(b.base_offset))?.unwrap_or(0))
+    }
+
+    // only the last segment with a batch is read, from its last indexed one
+    pub fn log_end_offset(&self) -> Result<i64> {
+        for segment in self.segments.iter().rev() {
+            let start = segment.indexed_position(i64::MAX)?;
+            let end_offset = read_log_end_offset(&mut File::open(&segment.path)?, start)?;
+            if end_offset > 0 {
+                return Ok(end_offset);
+            }
+        }
+        // a log whose batches were all deleted carries on from its last segment
+        Ok(self.segments.last().map_or(0, |s| s.base_offset))
+    }
+
+    // the segment appends go to, the last one
+    fn active_segment(&self) -> Option<&Path> {
+        self.segments.last().map(|segment| segment.path.as_path())
+    }
+
+    pub fn batch_for_timestamp(&self, timestamp: i64) -> Result<Option<BatchPosition>> {
+        self.find_batch(|b| (b.max_timestamp >= timestamp).then(|| b.clone()))
+    }
+
+    // the epoch of the leader that wrote offset, offsets past the end belong
+    // to the current leader; -1 when there is no such leader
+    pub fn leader_epoch(&self, offset: i64) -> Result<i32> {
+        let mut last = -1;
+        let found = self.find_batch(|b| {
+            last = b.leader_epoch;
+            (b.last_offset >= offset).then_some(b.leader_epoch)
+        })?;
+        Ok(found.unwrap_or(last))
+    }
+
+    // the producer id and first offset of each aborted transaction with
+    // records between fetch_offset and max_offset: its marker is at or after
+    // fetch_offset and it starts before max_offset. Aborts that ended before
+    // fetch_offset are left out, or the consumer would drop the producer's
+    // later committed records too. A transaction can start in one segment
+    // and end in a later one, so the ones still open are carried from
+    // segment to segment
+    pub fn aborted_transactions(
+        &self,
+        fetch_offset: i64,
+        max_offset: i64,
+    ) -> Result<Vec<(i64, i64)>> {
+        let mut ongoing: HashMap<i64, i64> = HashMap::new();
+        let mut aborted = Vec::new();
+
+        for segment in &self.segments {
+            let batches = segment.batches()?;
+            let mut file = None;
+
+            for batch in batches {
+                if batch.attributes & TRANSACTIONAL_FLAG == 0 {
+                    continue;
+                }
+
+                if batch.attributes & CONTROL_FLAG == 0 {
+                    ongoing
+                        .entry(batch.producer_id)
+                        .or_insert(batch.base_offset);
+                } else if let Some(first_offset) = ongoing.remove(&batch.producer_id) {
+                    if batch.base_offset < fetch_offset || first_offset >= max_offset {
+                        continue;
+                    }
+                    let file = match &mut file {
+                        Some(file) => file,
+                        None => file.insert(File::open(&segment.path)?),
+                    };
+                    if control_type(&read_batch(file, batch)?) == Some(ABORT_MARKER) {
+                        aborted.push((batch.producer_id, first_offset));
+                    }
+                }
+            }
+        }
+
+        Ok(aborted)
+    }
+
+    // the first offset of the earliest transaction that no marker has ended yet
+    pub fn first_unstable_offset(&self) -> Result<Option<i64>> {
+        let mut ongoing: HashMap<i64, i64> = HashMap::new();
+
+        for segment in &self.segments {
+            for batch in segment.batches()? {
+                if batch.attributes & TRANSACTIONAL_FLAG == 0 {
+                    continue;
+                }
+
+                if batch.attributes & CONTROL_FLAG == 0 {
+                    ongoing
+                        .entry(batch.producer_id)
+                        .or_insert(batch.base_offset);
+                } else {
+                    ongoing.remove(&batch.producer_id);
+                }
+            }
+        }
+
+        Ok(ongoing.into_values().min())
+    }
+
+    // the batches from the one holding offset up to max_offset, across
+    // segments, stopping before the batch that would exceed max_bytes. the
+    // first batch is returned whatever its size, so consumers can make
+    // progress. Segments before offset aren't read, and the first one read
+    // is scanned from the batch its offset index points at
+    pub fn read_range(&self, offset: i64, max_offset: i64, max_bytes: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+
+        let first = self
+            .segments
+            .partition_point(|s| s.base_offset <= offset)
+            .saturating_sub(1);
+        for (i, segment) in self.segments.iter().enumerate().skip(first) {
+            let mut file = File::open(&segment.path)?;
+            let length = file.seek(SeekFrom::End(0))?;
+            let mut position = match i == first {
+                true => segment.indexed_position(offset)?,
+                false => 0,
+            };
+
+            let mut header = [0u8; BATCH_HEADER_SIZE];
+            while position + BATCH_HEADER_SIZE as u64 <= length {
+                file.seek(SeekFrom::Start(position))?;
+                file.read_exact(&mut header)?;
+                let batch = BatchPosition::parse(&header, position as usize);
+                // like from_bytes, a partial trailing batch is left out
+                if batch.size < BATCH_HEADER_SIZE || position + batch.size as u64 > length {
+                    break;
+                }
+                position += batch.size as u64;
+
+                if batch.last_offset < offset {
+                    continue;
+                }
+                if batch.last_offset >= max_offset
+                    || (!buf.is_empty() && buf.len() + batch.size > max_bytes)
+                {
+                    return Ok(buf);
+                }
+                buf.extend(read_batch(&mut file, &batch)?);
+            }
+        }
+
+        Ok(buf)
+    }
+}
+
+// the whole of batch, header and records
+fn read_batch(file: &mut File, batch: &BatchPosition) -> Result<Vec<u8>> {
+    let mut data = vec![0; batch.size];
+    file.seek(SeekFrom::Start(batch.position as u64))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// the position of every complete batch of a segment, reading only their headers
+fn read_batch_positions(reader: &mut (impl Read + Seek)) -> Result<Vec<BatchPosition>> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    let mut batches = Vec::new();
+    let mut position = 0;
+    let mut header = [0u8; BATCH_HEADER_SIZE];
+
+    while position + BATCH_HEADER_SIZE as u64 <= length {
+        reader.seek(SeekFrom::Start(position))?;
+        reader.read_exact(&mut header)?;
+
+        let batch = BatchPosition::parse(&header, position as usize);
+        if batch.size < BATCH_HEADER_SIZE || position + batch.size as u64 > length {
+            break;
+        }
+        position += batch.size as u64;
+        batches.push(batch);
+    }
+
+    Ok(batches)
+}
+
+// the key type of a control batch's record, which precedes everything but
+// the record's varint length, attributes and deltas
+fn control_type(batch: &[u8]) -> Option<i16> {
+    let record = batch.get(BATCH_HEADER_SIZE..)?;
+
+    let mut position = 0;
+    for field in 0..5 {
+        if field == 1 {
+            // the attributes byte
+            position += 1;
+            continue;
+        }
+        while *record.get(position)? & 0x80 != 0 {
+            position += 1;
+        }
+        position += 1;
+    }
+
+    // the key's version comes before its type
+    let key = record.get(position + 2..position + 4)?;
+    Some(i16::from_be_bytes(key.try_into().unwrap()))
+}
+
+// the segment a partition's batches are appended to, how much it holds, and
+// the offset the next batch is given
+#[derive(Debug)]
+struct ActiveSegment {
+    path: PathBuf,
+    size: u64,
+    end_offset: i64,
+}
+
+impl ActiveSegment {
+    // the last segment of the partition, carrying on from the end of the
+    // whole log rather than of that one file
+    fn open(dir: &Path) -> Result<ActiveSegment> {
+        let segments = SegmentSet::open(dir)?;
+        let path = segments
+            .active_segment()
+            .map_or_else(|| segment_file(dir, 0), Path::to_path_buf);
+        let size = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        Ok(ActiveSegment {
+            path,
+            size,
+            end_offset: segments.log_end_offset()?,
+        })
+    }
+}
+
+// serializes appends to each partition, so concurrent writers can't be handed
+// overlapping offsets
+#[derive(Debug, Default)]
+pub struct SegmentWriter {
+    // by partition dir, opened on the first append to it
+    active_segments: Mutex<HashMap<PathBuf, Arc<Mutex<Option<ActiveSegment>>>>>,
+    // how many batches were appended, for fetches waiting on new records
+    appended: watch::Sender<u64>,
+}
+
+impl SegmentWriter {
+    pub fn new() -> Self {
+        SegmentWriter::default()
+    }
+
+    // changes after every append, to any segment
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.appended.subscribe()
+    }
+
+    // appends batch at the end of the partition in dir, assigning its base offset
+    // and stamping it with the leader epoch it was written in. A segment grown
+    // to the topic's segment.bytes is rolled, the batch starting the next one
+    pub fn append(
+        &self,
+        dir: &Path,
+        config: &LogConfig,
+        leader_epoch: i32,
+        batch: &mut [u8],
+    ) -> Result<i64> {
+        let active = self
+            .active_segments
+            .lock()
+            .unwrap()
+            .entry(dir.to_path_buf())
+            .or_default()
+            .clone();
+        // held until the batch is written, the map lock only while looking it up
+        let mut active = active.lock().unwrap();
+        let active = match &mut *active {
+            Some(active) => active,
+            None => active.insert(ActiveSegment::open(dir)?),
+        };
+
+        // neither the base offset nor the leader epoch is covered by the crc, so
+        // they can be patched in place
+        let base_offset = active.end_offset;
+        batch[0..8].copy_from_slice(&base_offset.to_be_bytes());
+        batch[PARTITION_LEADER_EPOCH_POSITION..PARTITION_LEADER_EPOCH_POSITION + 4]
+            .copy_from_slice(&leader_epoch.to_be_bytes());
+
+        if active.size > 0 && config.should_roll(active.size as usize) {
+            active.path = segment_file(dir, base_offset);
+            active.size = 0;
+        }
+
+        fs::create_dir_all(dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&active.path)?;
+        file.write_all(batch)?;
+        active.size += batch.len() as u64;
+
+        let last_offset_delta = i32::from_be_bytes(
+            batch[LAST_OFFSET_DELTA_POSITION..LAST_OFFSET_DELTA_POSITION + 4]
+                .try_into()
+                .unwrap(),
+        );
+        active.end_offset = base_offset + last_offset_delta as i64 + 1;
+        self.appended.send_modify(|appended| *appended += 1);
+
+        Ok(base_offset)
+    }
+}
+
+// scans forward from a batch boundary, such as one found in the offset index,
+// to the position of the batch holding offset, reading only batch headers
+#[allow(dead_code)]
+pub fn find_batch(reader: &mut (impl Read + Seek), start: u64, offset: i64) -> Result<Option<u64>> {
+    let mut position = start;
+    let mut header = [0u8; BATCH_HEADER_SIZE];
+
+    loop {
+        reader.seek(SeekFrom::Start(position))?;
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let base_offset = i64::from_be_bytes(header[0..8].try_into().unwrap());
+        let batch_length = i32::from_be_bytes(header[8..12].try_into().unwrap());
+        let last_offset_delta = i32::from_be_bytes(
+            header[LAST_OFFSET_DELTA_POSITION..LAST_OFFSET_DELTA_POSITION + 4]
+                .try_into()
+                .unwrap(),
+        );
+
+        if base_offset + last_offset_delta as i64 >= offset {
+            return Ok(Some(position));
+        }
+        position += (LOG_OVERHEAD + batch_length.max(0) as usize) as u64;
+    }
+}
+
+// why a produced record set can't be appended
+#[derive(Debug, PartialEq)]
+pub struct InvalidRecords {
+    pub error_code: ErrorCode,
+    pub record_errors: Vec<BatchIndexAndErrorMessage>,
+    pub message: String,
+}
+
+impl InvalidRecords {
+    fn new(error_code: ErrorCode, message: String) -> Self {
+        InvalidRecords {
+            error_code,
+            record_errors: Vec::new(),
+            message,
+        }
+    }
+}
+
+// checks each batch of a produced record set before it's appended: its
+// size, framing and crc, and that its records are the ones it counts
+pub fn validate_records(
+    records: &[u8],
+    max_message_bytes: usize,
+) -> std::result::Result<(), InvalidRecords> {
+    let mut position = 0;
+
+    while position < records.len() {
+        let rest = &records[position..];
+        if rest.len() < BATCH_HEADER_SIZE {
+            return Err(InvalidRecords::new(
+                ErrorCode::CorruptMessage,
+                format!("batch at {} is shorter than its header", position),
+            ));
+        }
+
+        let batch_length = i32::from_be_bytes(rest[8..12].try_into().unwrap());
+        let size = LOG_OVERHEAD + batch_length.max(0) as usize;
+        if size > max_message_bytes {
+            return Err(InvalidRecords::new(
+                ErrorCode::MessageTooLarge,
+                format!(
+                    "batch of {} bytes is larger than max.message.bytes {}",
+                    size, max_message_bytes
+                ),
+            ));
+        }
+        if size < BATCH_HEADER_SIZE || size > rest.len() {
+            return Err(InvalidRecords::new(
+                ErrorCode::CorruptMessage,
+                format!(
+                    "batch at {} has an invalid length {}",
+                    position, batch_length
+                ),
+            ));
+        }
+
+        let batch = &rest[..size];
+        if batch[MAGIC_POSITION] != 2 {
+            return Err(InvalidRecords::new(
+                ErrorCode::CorruptMessage,
+                format!("unsupported batch magic {}", batch[MAGIC_POSITION]),
+            ));
+        }
+        let crc = u32::from_be_bytes(batch[CRC_POSITION..CRC_POSITION + 4].try_into().unwrap());
+        if crc32c(&batch[ATTRIBUTES_POSITION..]) != crc {
+            return Err(InvalidRecords::new(
+                ErrorCode::CorruptMessage,
+                format!("batch at {} fails its crc check", position),
+            ));
+        }
+
+        let attributes = i16::from_be_bytes(
+            batch[ATTRIBUTES_POSITION..ATTRIBUTES_POSITION + 2]
+                .try_into()
+                .unwrap(),
+        );
+        let decompressed;
+        let records = match Compression::from_attributes(attributes) {
+            Ok(Compression::None) => &batch[BATCH_HEADER_SIZE..],
+            compression => {
+                decompressed = compression
+                    .and_then(|c| c.decompress(&batch[BATCH_HEADER_SIZE..], MAX_DECOMPRESSED_SIZE))
+                    .map_err(|err| {
+                        InvalidRecords::new(
+                            ErrorCode::CorruptMessage,
+                            format!("batch at {} can't be decompressed: {}", position, err),
+                        )
+                    })?;
+                &decompressed[..]
+            }
+        };
+
+        validate_batch_records(batch, records)?;
+        position += size;
+    }
+
+    Ok(())
+}
+
+// walks the records by their length prefixes, each has to carry the next
+// offset delta and together they have to fill the batch. records are the
+// batch's own, decompressed when its attributes say they're compressed
+fn validate_batch_records(batch: &[u8], records: &[u8]) -> std::result::Result<(), InvalidRecords> {
+    let invalid_record = |index: i32, message: String| {
+        let mut invalid = InvalidRecords::new(ErrorCode::InvalidRecord, message.clone());
+        invalid.record_errors.push(BatchIndexAndErrorMessage {
+            batch_index: index,
+            batch_index_error_message: Some(message),
+        });
+        invalid
+    };
+    let zigzag = |value: u64| (value >> 1) as i64 ^ -((value & 1) as i64);
+
+    let count = i32::from_be_bytes(
+        batch[RECORDS_COUNT_POSITION..RECORDS_COUNT_POSITION + 4]
+            .try_into()
+            .unwrap(),
+    );
+    let last_offset_delta = i32::from_be_bytes(
+        batch[LAST_OFFSET_DELTA_POSITION..LAST_OFFSET_DELTA_POSITION + 4]
+            .try_into()
+            .unwrap(),
+    );
+    if count <= 0 || last_offset_delta != count - 1 {
+        return Err(InvalidRecords::new(
+            ErrorCode::InvalidRecord,
+            format!(
+                "batch counts {} records up to offset delta {}",
+                count, last_offset_delta
+            ),
+        ));
+    }
+
+    let mut rest = records;
+    for index in 0..count {
+        let length = match parse_unsigned_varlong(&mut rest) {
+            Ok(length) => zigzag(length),
+            Err(_) => return Err(invalid_record(index, "record is truncated".to_string())),
+        };
+        if length < 0 || length as usize > rest.len() {
+            return Err(invalid_record(
+                index,
+                format!("record length {} overruns the batch", length),
+            ));
+        }
+        let (record, next) = rest.split_at(length as usize);
+        rest = next;
+
+        if record_offset_delta(record).map(zigzag) != Some(index as i64) {
+            return Err(invalid_record(
+                index,
+                format!("record doesn't have offset delta {}", index),
+            ));
+        }
+    }
+
+    if !rest.is_empty() {
+        return Err(InvalidRecords::new(
+            ErrorCode::InvalidRecord,
+            format!("{} bytes follow the last record", rest.len()),
+        ));
+    }
+
+    Ok(())
+}
+
+// the attributes byte comes first, then the timestamp and offset deltas
+fn record_offset_delta(record: &[u8]) -> Option<u64> {
+    let mut fields = record.get(1..)?;
+    parse_unsigned_varlong(&mut fields).ok()?;
+    parse_unsigned_varlong(&mut fields).ok()
+}
+
+// the log end offset from the last complete batch, reading only the headers
+// of the batches from start on, such as the last position in the offset index
+pub fn read_log_end_offset(reader: &mut (impl Read + Seek), start: u64) -> Result<i64> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    let mut position = start;
+    let mut header = [0u8; BATCH_HEADER_SIZE];
+    let mut end_offset = 0;
+
+    while position + BATCH_HEADER_SIZE as u64 <= length {
+        reader.seek(SeekFrom::Start(position))?;
+        reader.read_exact(&mut header)?;
+
+        let base_offset = i64::from_be_bytes(header[0..8].try_into().unwrap());
+        let batch_length = i32::from_be_bytes(header[8..12].try_into().unwrap());
+        let last_offset_delta = i32::from_be_bytes(
+            header[LAST_OFFSET_DELTA_POSITION..LAST_OFFSET_DELTA_POSITION + 4]
+                .try_into()
+                .unwrap(),
+        );
+
+        // like from_bytes, a partial trailing batch doesn't count
+        let size = (LOG_OVERHEAD + batch_length.max(0) as usize) as u64;
+        if size < BATCH_HEADER_SIZE as u64 || position + size > length {
+            break;
+        }
+
+        end_offset = base_offset + last_offset_delta as i64 + 1;
+        position += size;
+    }
+
+    Ok(end_offset)
+}
+
+// the base offset of the first batch in a partition dir, 0 when it has none,
+// reading only that batch's header rather than opening the whole log
+pub fn read_log_start_offset(dir: &Path) -> Result<i64> {
+    let paths = match segment_paths(dir) {
+        Ok(paths) => paths,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut header = [0u8; BATCH_HEADER_SIZE];
+    for path in paths {
+        match File::open(path)?.read_exact(&mut header) {
+            Ok(()) => return Ok(i64::from_be_bytes(header[0..8].try_into().unwrap())),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::{
+        env, fs,
+        io::{Cursor, Write},
+        process,
+        sync::Arc,
+        thread,
+    };
+
+    use flate2::{write::GzEncoder, Compression as GzLevel};
+
+    use crate::{
+        api::ErrorCode,
+        crc::crc32c,
+        log_config::LogConfig,
+        log_dir::{segment_file, segment_paths},
+        offset_index::OffsetIndex,
+        primitives::encode_signed_varint,
+        segment::{
+            read_log_end_offset, read_log_start_offset, validate_records, Segment, SegmentSet,
+            SegmentWriter,
+        },
+        txn::{control_batch, mark_transactional},
+    };
+
+    pub fn batch(base_offset: i64, last_offset_delta: i32, records: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(base_offset.to_be_bytes());
+        buf.extend((49 + records.len() as i32).to_be_bytes());
+        buf.extend(0i32.to_be_bytes());
+        buf.push(2);
+        buf.extend(0u32.to_be_bytes());
+        buf.extend(0i16.to_be_bytes());
+        buf.extend(last_offset_delta.to_be_bytes());
+        buf.extend(0i64.to_be_bytes());
+        buf.extend(0i64.to_be_bytes());
+        buf.extend((-1i64).to_be_bytes());
+        buf.extend((-1i16).to_be_bytes());
+        buf.extend((-1i32).to_be_bytes());
+        buf.extend((last_offset_delta + 1).to_be_bytes());
+        buf.extend(records);
+        buf
+    }
+
+    // a record without key or headers
+    pub fn record(offset_delta: i64, value: &[u8]) -> Vec<u8> {
+        let mut body = vec![0];
+        body.extend(encode_signed_varint(0));
+        body.extend(encode_signed_varint(offset_delta));
+        body.extend(encode_signed_varint(-1));
+        body.extend(encode_signed_varint(value.len() as i64));
+        body.extend(value);
+        body.extend(encode_signed_varint(0));
+
+        let mut buf = encode_signed_varint(body.len() as i64);
+        buf.extend(body);
+        buf
+    }
+
+    // a batch of real records with a valid crc, as a producer sends it
+    pub fn produced_batch(values: &[&[u8]]) -> Vec<u8> {
+        let records: Vec<u8> = values
+            .iter()
+            .enumerate()
+            .flat_map(|(offset_delta, value)| record(offset_delta as i64, value))
+            .collect();
+
+        let mut buf = batch(0, values.len() as i32 - 1, &records);
+        let crc = crc32c(&buf[21..]);
+        buf[17..21].copy_from_slice(&crc.to_be_bytes());
+        buf
+    }
+
+    // the same batch with its records gzipped, as producers with
+    // compression.type=gzip send it
+    pub fn compressed_batch(values: &[&[u8]]) -> Vec<u8> {
+        let uncompressed = produced_batch(values);
+        let mut gzip = GzEncoder::new(Vec::new(), GzLevel::default());
+        gzip.write_all(&uncompressed[61..]).unwrap();
+
+        let mut buf = uncompressed[..61].to_vec();
+        buf.extend(gzip.finish().unwrap());
+        let length = buf.len() as i32 - 12;
+        buf[8..12].copy_from_slice(&length.to_be_bytes());
+        buf[21..23].copy_from_slice(&1i16.to_be_bytes());
+        let crc = crc32c(&buf[21..]);
+        buf[17..21].copy_from_slice(&crc.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_validate_records() {
+        let mut records = produced_batch(&[b"a", b"bc"]);
+        records.extend(produced_batch(&[b"d"]));
+        assert_eq!(Ok(()), validate_records(&records, 1024));
+
+        // a bit flipped in the second batch's value
+        let mut corrupt = records.clone();
+        let value = corrupt.len() - 2;
+        corrupt[value] ^= 1;
+        let err = validate_records(&corrupt, 1024).unwrap_err();
+        assert_eq!(ErrorCode::CorruptMessage, err.error_code);
+        assert!(err.record_errors.is_empty());
+
+        let oversized = produced_batch(&[&[0xab; 2000]]);
+        let err = validate_records(&oversized, 1024).unwrap_err();
+        assert_eq!(ErrorCode::MessageTooLarge, err.error_code);
+        assert_eq!(Ok(()), validate_records(&oversized, 4096));
+    }
+
+    #[test]
+    fn test_validate_compressed_records() {
+        let records = compressed_batch(&[b"a", b"bc", b"d"]);
+        assert_eq!(Ok(()), validate_records(&records, 1024));
+
+        // records that aren't gzip, under a correct crc
+        let mut corrupt = records.clone();
+        corrupt[61..].fill(0xff);
+        let crc = crc32c(&corrupt[21..]);
+        corrupt[17..21].copy_from_slice(&crc.to_be_bytes());
+        let err = validate_records(&corrupt, 1024).unwrap_err();
+        assert_eq!(ErrorCode::CorruptMessage, err.error_code);
+
+        // and a codec kafka doesn't define
+        let mut unknown = records.clone();
+        unknown[22] = 6;
+        let crc = crc32c(&unknown[21..]);
+        unknown[17..21].copy_from_slice(&crc.to_be_bytes());
+        let err = validate_records(&unknown, 1024).unwrap_err();
+        assert_eq!(ErrorCode::CorruptMessage, err.error_code);
+    }
+
+    #[test]
+    fn test_validate_record_offset_deltas() {
+        // the second record claims offset delta 5, though the crc is right
+        let mut records = record(0, b"a");
+        records.extend(record(5, b"b"));
+        let mut invalid = batch(0, 1, &records);
+        let crc = crc32c(&invalid[21..]);
+        invalid[17..21].copy_from_slice(&crc.to_be_bytes());
+
+        let err = validate_records(&invalid, 1024).unwrap_err();
+        assert_eq!(ErrorCode::InvalidRecord, err.error_code);
+        assert_eq!(1, err.record_errors.len());
+        assert_eq!(1, err.record_errors[0].batch_index);
+    }
+
+    #[test]
+    fn test_read_compacted_segment() {
+        // offsets 2 to 4 were removed by the cleaner
+        let first = batch(0, 1, b"ab");
+        let second = batch(5, 2, b"cde");
+        let mut data = first.clone();
+        data.extend(&second);
+
+        let segment = Segment::from_bytes(data.clone());
+        assert_eq!(2, segment.batches().len());
+        assert_eq!(1, segment.batches()[0].last_offset);
+        assert_eq!(5, segment.batches()[1].base_offset);
+        assert_eq!(7, segment.batches()[1].last_offset);
+
+        assert_eq!(&data[..], segment.read_from(1));
+        assert_eq!(&second[..], segment.read_from(3));
+        assert_eq!(&second[..], segment.read_from(6));
+        assert!(segment.read_from(8).is_empty());
+    }
+
+    #[test]
+    fn test_partial_trailing_batch() {
+        let mut data = batch(0, 0, b"a");
+        data.extend(&batch(1, 0, b"b")[..20]);
+
+        let segment = Segment::from_bytes(data);
+        assert_eq!(1, segment.batches().len());
+    }
+
+    #[test]
+    fn test_append_to_last_segment() {
+        let dir = env::temp_dir().join(format!("kafka-test-{}-append-segments", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("00000000000000000000.log"), batch(0, 1, b"ab")).unwrap();
+        fs::write(dir.join("00000000000000000002.log"), batch(2, 0, b"c")).unwrap();
+
+        // offsets carry on from the end of the whole log, in its last segment
+        let writer = SegmentWriter::new();
+        assert_eq!(
+            3,
+            writer
+                .append(&dir, &LogConfig::default(), 0, &mut batch(0, 0, b"d"))
+                .unwrap()
+        );
+        assert_eq!(
+            4,
+            writer
+                .append(&dir, &LogConfig::default(), 0, &mut batch(0, 0, b"e"))
+                .unwrap()
+        );
+        let first = Segment::open(dir.join("00000000000000000000.log")).unwrap();
+        let last = Segment::open(dir.join("00000000000000000002.log")).unwrap();
+        assert_eq!(2, first.log_end_offset());
+        assert_eq!(5, last.log_end_offset());
+    }
+
+    #[test]
+    fn test_append_rolls_full_segment() {
+        let dir = env::temp_dir().join(format!("kafka-test-{}-roll-segments", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // room for two of the batches, rolled once the second fills it
+        let size = batch(0, 1, b"ab").len() as i64;
+        let config = LogConfig {
+            segment_bytes: 2 * size,
+            ..LogConfig::default()
+        };
+        let writer = SegmentWriter::new();
+        for expected in [0, 2, 4, 6, 8] {
+            let offset = writer
+                .append(&dir, &config, 0, &mut batch(0, 1, b"ab"))
+                .unwrap();
+            assert_eq!(expected, offset);
+        }
+
+        let segments: Vec<(i64, usize)> = [0, 4, 8]
+            .into_iter()
+            .map(|base_offset| {
+                let segment = Segment::open(segment_file(&dir, base_offset)).unwrap();
+                (segment.log_start_offset(), segment.batches().len())
+            })
+            .collect();
+        assert_eq!(vec![(0, 2), (4, 2), (8, 1)], segments);
+        assert_eq!(3, segment_paths(&dir).unwrap().len());
+
+        // a writer opening the partition afresh carries on in the last segment
+        let writer = SegmentWriter::new();
+        let offset = writer
+            .append(&dir, &config, 0, &mut batch(0, 1, b"ab"))
+            .unwrap();
+        assert_eq!(10, offset);
+        assert_eq!(3, segment_paths(&dir).unwrap().len());
+    }
+
+    #[test]
+    fn test_concurrent_appends() {
+        let dir = env::temp_dir().join(format!("kafka-test-{}-appends", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("00000000000000000000.log");
+        let writer = Arc::new(SegmentWriter::new());
+        let appended = writer.subscribe();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let writer = writer.clone();
+                let dir = dir.clone();
+                thread::spawn(move || {
+                    (0..10)
+                        .map(|_| {
+                            writer
+                                .append(&dir, &LogConfig::default(), 0, &mut batch(0, 1, b"ab"))
+                                .unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut offsets: Vec<i64> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+        offsets.sort();
+        assert_eq!((0..80).map(|i| i * 2).collect::<Vec<_>>(), offsets);
+        assert!(appended.has_changed().unwrap());
+        assert_eq!(80, *appended.borrow());
+
+        let segment = Segment::open(&path).unwrap();
+        assert_eq!(80, segment.batches().len());
+        assert_eq!(160, segment.log_end_offset());
+    }
+
+    #[test]
+    fn test_read_across_segments() {
+        let first = [batch(0, 1, b"ab"), batch(2, 1, b"cd")];
+        let second = [batch(4, 0, b"e"), batch(5, 2, b"fgh")];
+
+        let dir = env::temp_dir().join(format!("kafka-test-{}-segment-set", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("00000000000000000000.log"), first.concat()).unwrap();
+        fs::write(dir.join("00000000000000000004.log"), second.concat()).unwrap();
+        fs::write(dir.join("00000000000000000004.index"), []).unwrap();
+        assert_eq!(0, read_log_start_offset(&dir).unwrap());
+
+        let segments = SegmentSet::open(&dir).unwrap();
+        assert_eq!(0, segments.log_start_offset().unwrap());
+        assert_eq!(8, segments.log_end_offset().unwrap());
+
+        // starting in the last batch of the first segment
+        let records = segments.read_range(3, i64::MAX, usize::MAX).unwrap();
+        assert_eq!([&first[1][..], &second[0], &second[1]].concat(), records);
+
+        // the budget cuts off at a batch boundary, in the next segment
+        let budget = first[1].len() + second[0].len();
+        assert_eq!(
+            [&first[1][..], &second[0]].concat(),
+            segments.read_range(3, i64::MAX, budget + 1).unwrap()
+        );
+
+        // the first batch is returned even when it's over budget
+        assert_eq!(first[1], segments.read_range(3, i64::MAX, 1).unwrap());
+        assert_eq!(
+            [&first[1][..], &second[0]].concat(),
+            segments.read_range(3, 5, usize::MAX).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_from_offset_index() {
+        let dir = env::temp_dir().join(format!("kafka-test-{}-segment-index", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut data = Vec::new();
+        let mut index = Vec::new();
+        for offset in 10..20 {
+            if offset % 4 == 0 {
+                index.extend((offset as i32 - 10).to_be_bytes());
+                index.extend((data.len() as u32).to_be_bytes());
+            }
+            data.extend(batch(offset, 0, b"a"));
+        }
+        let batch_size = data.len() / 10;
+        fs::write(dir.join("00000000000000000010.log"), &data).unwrap();
+        fs::write(dir.join("00000000000000000010.index"), index).unwrap();
+        // a segment before the one read from, which can't be read at all
+        fs::create_dir_all(dir.join("00000000000000000000.log")).unwrap();
+
+        let segments = SegmentSet::open(&dir).unwrap();
+        assert_eq!(
+            data[7 * batch_size..],
+            segments.read_range(17, i64::MAX, usize::MAX).unwrap()
+        );
+        assert_eq!(20, segments.log_end_offset().unwrap());
+        assert!(segments.log_start_offset().is_err());
+    }
+
+    // a batch of producer_id's transaction
+    fn transactional(mut batch: Vec<u8>, producer_id: i64) -> Vec<u8> {
+        batch[43..51].copy_from_slice(&producer_id.to_be_bytes());
+        mark_transactional(&mut batch);
+        batch
+    }
+
+    #[test]
+    fn test_transaction_across_segments() {
+        let dir = env::temp_dir().join(format!("kafka-test-{}-segment-txn", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut abort = control_batch(7, 0, false);
+        abort[0..8].copy_from_slice(&3i64.to_be_bytes());
+        let first = [
+            transactional(batch(0, 1, b"ab"), 7),
+            transactional(batch(2, 0, b"c"), 8),
+        ];
+        let second = [abort, batch(4, 0, b"d")];
+        fs::write(dir.join("00000000000000000000.log"), first.concat()).unwrap();
+        fs::write(dir.join("00000000000000000003.log"), second.concat()).unwrap();
+
+        // 7's transaction started in the first segment and was aborted in
+        // the second, 8's is still open
+        let segments = SegmentSet::open(&dir).unwrap();
+        assert_eq!(
+            vec![(7, 0)],
+            segments.aborted_transactions(0, i64::MAX).unwrap()
+        );
+        assert_eq!(vec![(7, 0)], segments.aborted_transactions(3, 1).unwrap());
+        // fetching past the abort marker, or up to the transaction's start
+        assert!(segments
+            .aborted_transactions(4, i64::MAX)
+            .unwrap()
+            .is_empty());
+        assert!(segments.aborted_transactions(0, 0).unwrap().is_empty());
+        assert_eq!(Some(2), segments.first_unstable_offset().unwrap());
+    }
+
+    #[test]
+    fn test_read_log_end_offset() {
+        let mut data = Vec::new();
+        let mut index = Vec::new();
+        let mut base_offset = 0;
+        for last_offset_delta in 0..20 {
+            if base_offset % 4 == 0 {
+                index.extend((base_offset as i32).to_be_bytes());
+                index.extend((data.len() as u32).to_be_bytes());
+            }
+            data.extend(batch(base_offset, last_offset_delta, b"ab"));
+            base_offset += last_offset_delta as i64 + 1;
+        }
+        // a writer is in the middle of appending another batch
+        data.extend(&batch(base_offset, 0, b"c")[..30]);
+
+        let expected = Segment::from_bytes(data.clone()).log_end_offset();
+        assert_eq!(210, expected);
+
+        let mut reader = Cursor::new(&data);
+        assert_eq!(expected, read_log_end_offset(&mut reader, 0).unwrap());
+
+        let start = OffsetIndex::from_bytes(0, &index).lookup(i64::MAX);
+        assert!(start > 0);
+        assert_eq!(expected, read_log_end_offset(&mut reader, start).unwrap());
+
+        let mut empty = Cursor::new(Vec::new());
+        assert_eq!(0, read_log_end_offset(&mut empty, 0).unwrap());
+    }
+
+    #[test]
+    fn test_read_log_start_offset() {
+        let dir = env::temp_dir().join(format!("kafka-test-{}-log-start", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(0, read_log_start_offset(&dir).unwrap());
+
+        // the segments before 5 were deleted, leaving an empty active one
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("00000000000000000005.log"), batch(5, 1, b"ab")).unwrap();
+        fs::write(dir.join("00000000000000000003.log"), []).unwrap();
+        assert_eq!(5, read_log_start_offset(&dir).unwrap());
+    }
+}
@@ -0,0 +1,458 @@
+use std::io::{self, Result};
+
+use crate::{
+    api::{
+        ApiVersionsRequest, DescribeTopicPartitionsRequest, KCursor, ProducePartitionData,
+        ProduceRequest, ProduceTopicData,
+    },
+    primitives::decode_varint,
+};
+
+pub trait SliceParser<'a>: Sized {
+    fn parse_slice(reader: &mut SliceReader<'a>) -> Result<Self>;
+}
+
+// reads fields straight out of an in-memory request frame, avoiding the
+// per-field read_exact calls of the Read based parsers
+pub struct SliceReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        SliceReader { buf, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn int8(&mut self) -> Result<i8> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    pub fn int16(&mut self) -> Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn int32(&mut self) -> Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn unsigned_varlong(&mut self) -> Result<u64> {
+        let rest = self.remaining();
+        let length = match rest.iter().take(10).position(|b| b & 0x80 == 0) {
+            Some(end) => end + 1,
+            None if rest.len() < 10 => {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "varint too long",
+                ));
+            }
+        };
+
+        Ok(decode_varint(self.take(length)?))
+    }
+
+    fn utf8(&mut self, length: usize) -> Result<String> {
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // the length of a non-flexible array, null ones being empty
+    pub fn array_length(&mut self) -> Result<usize> {
+        Ok(self.int32()?.max(0) as usize)
+    }
+
+    pub fn string(&mut self) -> Result<String> {
+        match self.nullable_string()? {
+            Some(string) => Ok(string),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected null string",
+            )),
+        }
+    }
+
+    pub fn nullable_string(&mut self) -> Result<Option<String>> {
+        match self.int16()? {
+            length if length < 0 => Ok(None),
+            length => Ok(Some(self.utf8(length as usize)?)),
+        }
+    }
+
+    pub fn nullable_bytes(&mut self) -> Result<Option<&'a [u8]>> {
+        match self.int32()? {
+            length if length < 0 => Ok(None),
+            length => Ok(Some(self.take(length as usize)?)),
+        }
+    }
+
+    pub fn compact_string(&mut self) -> Result<String> {
+        let length = self.unsigned_varlong()? as usize;
+        match length.checked_sub(1) {
+            Some(length) => self.utf8(length),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected null string",
+            )),
+        }
+    }
+
+    pub fn compact_nullable_string(&mut self) -> Result<Option<String>> {
+        match self.unsigned_varlong()? as usize {
+            0 => Ok(None),
+            length => Ok(Some(self.utf8(length - 1)?)),
+        }
+    }
+
+    // borrowed from the frame rather than copied out of it
+    pub fn compact_nullable_bytes(&mut self) -> Result<Option<&'a [u8]>> {
+        match self.unsigned_varlong()? as usize {
+            0 => Ok(None),
+            length => Ok(Some(self.take(length - 1)?)),
+        }
+    }
+
+    // for strings that are only ever logged or used as keys, like the client
+    // id, where bytes that aren't UTF-8 shouldn't cost a client its connection
+    pub fn nullable_string_lossy(&mut self) -> Result<Option<String>> {
+        match self.int16()? {
+            -1 => Ok(None),
+            length if length < 0 => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid string length {}", length),
+            )),
+            length => {
+                let bytes = self.take(length as usize)?;
+                Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+            }
+        }
+    }
+
+    // like parse_tag_buffer, the values borrowed from the frame
+    pub fn tag_buffer(&mut self) -> Result<Vec<(u32, &'a [u8])>> {
+        let count = self.unsigned_varlong()?;
+        let mut fields = Vec::new();
+        for _ in 0..count {
+            let tag = self.unsigned_varlong()? as u32;
+            let length = self.unsigned_varlong()? as usize;
+            fields.push((tag, self.take(length)?));
+        }
+        Ok(fields)
+    }
+}
+
+impl SliceParser<'_> for ApiVersionsRequest {
+    fn parse_slice(reader: &mut SliceReader) -> Result<Self> {
+        Ok(ApiVersionsRequest {
+            client_software_name: reader.compact_string()?,
+            client_software_version: reader.compact_string()?,
+        })
+    }
+}
+
+impl SliceParser<'_> for DescribeTopicPartitionsRequest {
+    fn parse_slice(reader: &mut SliceReader) -> Result<Self> {
+        let length = reader.unsigned_varlong()?;
+        let mut topics = Vec::new();
+        for _ in 1..length {
+            topics.push(reader.compact_nullable_string()?);
+            reader.tag_buffer()?;
+        }
+
+        let request = DescribeTopicPartitionsRequest {
+            topics,
+            response_partition_limit: reader.int32()?,
+            cursor: match reader.int8()? {
+                -1 => None,
+                _ => Some(KCursor::parse_slice(reader)?),
+            },
+        };
+
+        reader.tag_buffer()?;
+        Ok(request)
+    }
+}
+
+impl SliceParser<'_> for KCursor {
+    fn parse_slice(reader: &mut SliceReader) -> Result<Self> {
+        let cursor = KCursor {
+            topic_name: reader.compact_string()?,
+            partition_index: reader.int32()?,
+        };
+
+        reader.tag_buffer()?;
+        Ok(cursor)
+    }
+}
+
+impl<'a> ProduceRequest<'a> {
+    pub fn parse_slice(reader: &mut SliceReader<'a>, version: i16) -> Result<Self> {
+        // v9 is the first flexible version
+        let flexible = version >= 9;
+        let array_length = |reader: &mut SliceReader| {
+            if flexible {
+                Ok(reader.unsigned_varlong()?.saturating_sub(1) as usize)
+            } else {
+                reader.array_length()
+            }
+        };
+
+        let transactional_id = match (version >= 3, flexible) {
+            (false, _) => None,
+            (true, false) => reader.nullable_string()?,
+            (true, true) => reader.compact_nullable_string()?,
+        };
+        let acks = reader.int16()?;
+        let timeout_ms = reader.int32()?;
+
+        let mut topic_data = Vec::new();
+        for _ in 0..array_length(reader)? {
+            let name = if flexible {
+                reader.compact_string()?
+            } else {
+                reader.string()?
+            };
+
+            let mut partition_data = Vec::new();
+            for _ in 0..array_length(reader)? {
+                let index = reader.int32()?;
+                let records = if flexible {
+                    reader.compact_nullable_bytes()?
+                } else {
+                    reader.nullable_bytes()?
+                };
+                partition_data.push(ProducePartitionData { index, records });
+                if flexible {
+                    reader.tag_buffer()?;
+                }
+            }
+
+            topic_data.push(ProduceTopicData {
+                name,
+                partition_data,
+            });
+            if flexible {
+                reader.tag_buffer()?;
+            }
+        }
+
+        if flexible {
+            reader.tag_buffer()?;
+        }
+
+        Ok(ProduceRequest {
+            transactional_id,
+            acks,
+            timeout_ms,
+            topic_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, time::Instant};
+
+    use crate::{
+        api::{ApiVersionsRequest, DescribeTopicPartitionsRequest, Parser, ProduceRequest},
+        primitives::encode_varint,
+        slice::{SliceParser, SliceReader},
+    };
+
+    fn api_versions_body() -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.push(10);
+        buf.extend(b"kafka-cli");
+        buf.push(4);
+        buf.extend(b"0.1");
+        buf.push(0);
+        buf
+    }
+
+    #[test]
+    fn test_api_versions_parsers_agree() {
+        let body = api_versions_body();
+        let from_cursor = ApiVersionsRequest::parse(&mut Cursor::new(&body)).unwrap();
+        let from_slice = ApiVersionsRequest::parse_slice(&mut SliceReader::new(&body)).unwrap();
+
+        assert_eq!(from_cursor, from_slice);
+        assert_eq!("kafka-cli", from_slice.client_software_name);
+    }
+
+    #[test]
+    fn test_describe_topic_partitions_parsers_agree() {
+        let mut body = Vec::new();
+        body.push(3);
+        body.extend([4, b'f', b'o', b'o', 0]);
+        body.extend([0, 0]);
+        body.extend(100i32.to_be_bytes());
+        body.push(1);
+        body.extend([4, b'f', b'o', b'o']);
+        body.extend(2i32.to_be_bytes());
+        body.extend([0, 0]);
+
+        let from_cursor = DescribeTopicPartitionsRequest::parse(&mut Cursor::new(&body)).unwrap();
+        let from_slice =
+            DescribeTopicPartitionsRequest::parse_slice(&mut SliceReader::new(&body)).unwrap();
+
+        assert_eq!(from_cursor, from_slice);
+        assert_eq!(2, from_slice.cursor.unwrap().partition_index);
+    }
+
+    #[test]
+    fn test_describe_topic_partitions_consumes_frame() {
+        // a v0 request for "foo", without a cursor
+        let body = [2, 4, b'f', b'o', b'o', 0, 0, 0, 0, 100, 0xff, 0];
+
+        let mut reader = SliceReader::new(&body);
+        DescribeTopicPartitionsRequest::parse_slice(&mut reader).unwrap();
+        assert!(reader.remaining().is_empty());
+
+        let mut cursor = Cursor::new(&body);
+        DescribeTopicPartitionsRequest::parse(&mut cursor).unwrap();
+        assert_eq!(body.len() as u64, cursor.position());
+    }
+
+    #[test]
+    fn test_tag_buffer_with_fields() {
+        // a v0 DescribeTopicPartitions request whose final tag buffer holds
+        // a two byte field tagged 0
+        let body = [
+            2, 4, b'f', b'o', b'o', 0, 0, 0, 0, 100, 0xff, 1, 0, 2, 0xab, 0xcd,
+        ];
+
+        let mut reader = SliceReader::new(&body);
+        DescribeTopicPartitionsRequest::parse_slice(&mut reader).unwrap();
+        assert!(reader.remaining().is_empty());
+
+        let mut cursor = Cursor::new(&body);
+        DescribeTopicPartitionsRequest::parse(&mut cursor).unwrap();
+        assert_eq!(body.len() as u64, cursor.position());
+
+        let mut reader = SliceReader::new(&body[11..]);
+        assert_eq!(vec![(0, &[0xab, 0xcd][..])], reader.tag_buffer().unwrap());
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        let body = api_versions_body();
+        let result = ApiVersionsRequest::parse_slice(&mut SliceReader::new(&body[..5]));
+        assert_eq!(
+            std::io::ErrorKind::UnexpectedEof,
+            result.unwrap_err().kind()
+        );
+    }
+
+    // cargo test bench_api_versions_parsers -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_api_versions_parsers() {
+        let body = api_versions_body();
+        let iterations = 1_000_000;
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            ApiVersionsRequest::parse(&mut Cursor::new(&body)).unwrap();
+        }
+        let cursor = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            ApiVersionsRequest::parse_slice(&mut SliceReader::new(&body)).unwrap();
+        }
+        let slice = start.elapsed();
+
+        println!("cursor: {:?}, slice: {:?}", cursor, slice);
+    }
+
+    #[test]
+    fn test_overlong_varint() {
+        let mut reader = SliceReader::new(&[0xff; 11]);
+        let err = reader.unsigned_varlong().unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_produce_v3_and_v9() {
+        let records = [1, 2, 3];
+
+        let mut v3 = Vec::new();
+        v3.extend(2i16.to_be_bytes());
+        v3.extend(b"tx");
+        v3.extend((-1i16).to_be_bytes());
+        v3.extend(1000i32.to_be_bytes());
+        v3.extend(1i32.to_be_bytes());
+        v3.extend(3i16.to_be_bytes());
+        v3.extend(b"foo");
+        v3.extend(1i32.to_be_bytes());
+        v3.extend(0i32.to_be_bytes());
+        v3.extend((records.len() as i32).to_be_bytes());
+        v3.extend(records);
+
+        let mut v9 = vec![3];
+        v9.extend(b"tx");
+        v9.extend((-1i16).to_be_bytes());
+        v9.extend(1000i32.to_be_bytes());
+        v9.extend([2, 4, b'f', b'o', b'o', 2]);
+        v9.extend(0i32.to_be_bytes());
+        v9.push(records.len() as u8 + 1);
+        v9.extend(records);
+        v9.extend([0, 0, 0]);
+
+        let mut reader = SliceReader::new(&v3);
+        let from_v3 = ProduceRequest::parse_slice(&mut reader, 3).unwrap();
+        assert!(reader.remaining().is_empty());
+        let mut reader = SliceReader::new(&v9);
+        let from_v9 = ProduceRequest::parse_slice(&mut reader, 9).unwrap();
+        assert!(reader.remaining().is_empty());
+
+        assert_eq!(from_v3, from_v9);
+        assert_eq!(Some("tx"), from_v3.transactional_id.as_deref());
+        assert_eq!(-1, from_v3.acks);
+        assert_eq!(1000, from_v3.timeout_ms);
+        assert_eq!("foo", from_v3.topic_data[0].name);
+        let partition = &from_v3.topic_data[0].partition_data[0];
+        assert_eq!(0, partition.index);
+        assert_eq!(Some(&records[..]), partition.records);
+    }
+
+    #[test]
+    fn test_produce_records_borrowed_from_frame() {
+        let records = vec![0xab; 4 << 20];
+
+        let mut body = vec![0];
+        body.extend((-1i16).to_be_bytes());
+        body.extend(1000i32.to_be_bytes());
+        body.extend([2, 4, b'f', b'o', b'o', 2]);
+        body.extend(0i32.to_be_bytes());
+        body.extend(encode_varint(records.len() as u64 + 1));
+        body.extend(&records);
+        body.extend([0, 0, 0]);
+
+        let request = ProduceRequest::parse_slice(&mut SliceReader::new(&body), 9).unwrap();
+        assert!(request.transactional_id.is_none());
+        assert_eq!("foo", request.topic_data[0].name);
+
+        // the records point into the frame itself, nothing was copied
+        let parsed = request.topic_data[0].partition_data[0].records.unwrap();
+        assert_eq!(records.len(), parsed.len());
+        assert!(body.as_ptr_range().contains(&parsed.as_ptr()));
+        assert!(parsed.iter().all(|b| *b == 0xab));
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    api::ErrorCode,
+    crc::crc32c,
+    primitives::encode_signed_varint,
+    segment::{CONTROL_FLAG, TRANSACTIONAL_FLAG},
+};
+
+#[derive(Debug)]
+pub struct Transaction {
+    pub producer_id: i64,
+    pub producer_epoch: i16,
+    pub partitions: BTreeSet<(String, i32)>,
+}
+
+// ongoing transactions, keyed by transactional id
+#[derive(Debug, Default)]
+pub struct TransactionCoordinator {
+    transactions: HashMap<String, Transaction>,
+}
+
+#[allow(dead_code)]
+impl TransactionCoordinator {
+    pub fn new() -> Self {
+        TransactionCoordinator::default()
+    }
+
+    pub fn add_partitions(
+        &mut self,
+        transactional_id: &str,
+        producer_id: i64,
+        producer_epoch: i16,
+        partitions: impl IntoIterator<Item = (String, i32)>,
+    ) -> Result<(), ErrorCode> {
+        let txn = self
+            .transactions
+            .entry(transactional_id.to_string())
+            .or_insert_with(|| Transaction {
+                producer_id,
+                producer_epoch,
+                partitions: BTreeSet::new(),
+            });
+
+        // a newer epoch means the producer was restarted and fences the old one
+        if txn.producer_id != producer_id || producer_epoch < txn.producer_epoch {
+            return Err(ErrorCode::InvalidProducerEpoch);
+        }
+        if producer_epoch > txn.producer_epoch {
+            txn.producer_epoch = producer_epoch;
+            txn.partitions.clear();
+        }
+
+        txn.partitions.extend(partitions);
+        Ok(())
+    }
+
+    // completes the transaction, returning the partitions that need a marker
+    pub fn end(
+        &mut self,
+        transactional_id: &str,
+        producer_id: i64,
+        producer_epoch: i16,
+    ) -> Result<BTreeSet<(String, i32)>, ErrorCode> {
+        let txn = self
+            .transactions
+            .get_mut(transactional_id)
+            .ok_or(ErrorCode::InvalidTxnState)?;
+
+        if txn.producer_id != producer_id || txn.producer_epoch != producer_epoch {
+            return Err(ErrorCode::InvalidProducerEpoch);
+        }
+
+        Ok(std::mem::take(&mut txn.partitions))
+    }
+
+    // a transactional produce may only write to partitions added to its
+    // ongoing transaction, under the producer's current epoch
+    pub fn validate_produce(
+        &self,
+        transactional_id: &str,
+        producer_id: i64,
+        producer_epoch: i16,
+        topic: &str,
+        partition: i32,
+    ) -> Result<(), ErrorCode> {
+        let txn = self
+            .transactions
+            .get(transactional_id)
+            .ok_or(ErrorCode::InvalidTxnState)?;
+
+        if txn.producer_id != producer_id || txn.producer_epoch != producer_epoch {
+            return Err(ErrorCode::InvalidProducerEpoch);
+        }
+        if !txn.partitions.contains(&(topic.to_string(), partition)) {
+            return Err(ErrorCode::InvalidTxnState);
+        }
+
+        Ok(())
+    }
+}
+
+// sets the transactional bit on every batch of a produced record set,
+// updating each crc to cover the new attributes
+pub fn mark_transactional(records: &mut [u8]) {
+    let mut position = 0;
+    while position + 61 <= records.len() {
+        let length = i32::from_be_bytes(records[position + 8..position + 12].try_into().unwrap());
+        let end = (position + 12 + length.max(0) as usize).min(records.len());
+
+        let batch = &mut records[position..end];
+        let attributes = i16::from_be_bytes([batch[21], batch[22]]) | TRANSACTIONAL_FLAG;
+        batch[21..23].copy_from_slice(&attributes.to_be_bytes());
+        let crc = crc32c(&batch[21..]);
+        batch[17..21].copy_from_slice(&crc.to_be_bytes());
+
+        position = end;
+    }
+}
+
+// a single control record marking the end of a producer's transaction
+pub fn control_batch(producer_id: i64, producer_epoch: i16, commit: bool) -> Vec<u8> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64;
+
+    let mut key = Vec::new();
+    key.extend(0i16.to_be_bytes());
+    key.extend((commit as i16).to_be_bytes());
+
+    // version and coordinator epoch
+    let mut value = Vec::new();
+    value.extend(0i16.to_be_bytes());
+    value.extend(0i32.to_be_bytes());
+
+    let mut record = Vec::new();
+    record.push(0);
+    record.extend(encode_signed_varint(0));
+    record.extend(encode_signed_varint(0));
+    record.extend(encode_signed_varint(key.len() as i64));
+    record.extend(key);
+    record.extend(encode_signed_varint(value.len() as i64));
+    record.extend(value);
+    record.extend(encode_signed_varint(0));
+
+    let mut body = Vec::new();
+    body.extend((TRANSACTIONAL_FLAG | CONTROL_FLAG).to_be_bytes());
+    body.extend(0i32.to_be_bytes());
+    body.extend(timestamp.to_be_bytes());
+    body.extend(timestamp.to_be_bytes());
+    body.extend(producer_id.to_be_bytes());
+    body.extend(producer_epoch.to_be_bytes());
+    body.extend((-1i32).to_be_bytes());
+    body.extend(1i32.to_be_bytes());
+    body.extend(encode_signed_varint(record.len() as i64));
+    body.extend(record);
+
+    let mut buf = Vec::new();
+    buf.extend(0i64.to_be_bytes());
+    buf.extend((body.len() as i32 + 9).to_be_bytes());
+    buf.extend(0i32.to_be_bytes());
+    buf.push(2);
+    buf.extend(crc32c(&body).to_be_bytes());
+    buf.extend(body);
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        api::ErrorCode,
+        crc::crc32c,
+        segment::{test::batch, TRANSACTIONAL_FLAG},
+        txn::{mark_transactional, TransactionCoordinator},
+    };
+
+    #[test]
+    fn test_fenced_producer() {
+        let mut coordinator = TransactionCoordinator::new();
+        let partitions = vec![("foo".to_string(), 0)];
+
+        coordinator
+            .add_partitions("txn", 1, 1, partitions.clone())
+            .unwrap();
+        coordinator
+            .add_partitions("txn", 1, 2, partitions.clone())
+            .unwrap();
+
+        let result = coordinator.add_partitions("txn", 1, 1, partitions);
+        assert!(result == Err(ErrorCode::InvalidProducerEpoch));
+        assert!(coordinator.end("txn", 1, 1) == Err(ErrorCode::InvalidProducerEpoch));
+        assert!(coordinator.end("other", 1, 1) == Err(ErrorCode::InvalidTxnState));
+    }
+
+    #[test]
+    fn test_transactional_produce() {
+        let mut coordinator = TransactionCoordinator::new();
+        coordinator
+            .add_partitions("txn", 1, 1, vec![("foo".to_string(), 0)])
+            .unwrap();
+
+        assert_eq!(Ok(()), coordinator.validate_produce("txn", 1, 1, "foo", 0));
+        assert_eq!(
+            Err(ErrorCode::InvalidTxnState),
+            coordinator.validate_produce("txn", 1, 1, "foo", 1)
+        );
+        assert_eq!(
+            Err(ErrorCode::InvalidProducerEpoch),
+            coordinator.validate_produce("txn", 1, 0, "foo", 0)
+        );
+        assert_eq!(
+            Err(ErrorCode::InvalidTxnState),
+            coordinator.validate_produce("other", 1, 1, "foo", 0)
+        );
+
+        let mut records = batch(0, 0, b"a");
+        records.extend(batch(1, 0, b"b"));
+        mark_transactional(&mut records);
+
+        let second = records.len() / 2;
+        for batch in [&records[..second], &records[second..]] {
+            let attributes = i16::from_be_bytes([batch[21], batch[22]]);
+            assert_eq!(TRANSACTIONAL_FLAG, attributes & TRANSACTIONAL_FLAG);
+            assert_eq!(crc32c(&batch[21..]).to_be_bytes(), batch[17..21]);
+        }
+    }
+}